@@ -1,4 +1,36 @@
+use std::{env, fs, path::Path};
+
 fn main() {
     // Pass in our custom linker script
     println!("cargo:rustc-link-arg=-Tbootloaders/raspi/linker.ld");
+    println!("cargo:rerun-if-changed=linker.ld");
+
+    // Embed every overlay in overlays/, which the bootloader merges into the firmware's device tree in the
+    // order of their file names. The directory is kept in the repository, as Cargo reruns the script on every
+    // build when told to watch a path that doesn't exist.
+    println!("cargo:rerun-if-changed=overlays");
+    let mut overlays: Vec<_> = fs::read_dir("overlays")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "dtbo")
+        })
+        .collect();
+    overlays.sort();
+    let entries: String = overlays
+        .iter()
+        .map(|path| {
+            let path = fs::canonicalize(path).unwrap();
+            format!("    include_bytes!({:?}),\n", path)
+        })
+        .collect();
+    // Leave the file alone when nothing changed, so the bootloader isn't rebuilt for nothing
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("overlays.rs");
+    let contents = format!("&[\n{}]\n", entries);
+    if fs::read_to_string(&out_path).ok().as_deref() != Some(contents.as_str()) {
+        fs::write(out_path, contents).unwrap();
+    }
 }
@@ -0,0 +1,32 @@
+//! Data cache maintenance, for memory shared with devices that don't snoop the ARM's caches
+
+use aarch64_cpu::asm::barrier;
+use core::arch::asm;
+
+/// Returns the size of the smallest data cache line of any cache the core can see, in bytes
+fn dcache_line_size() -> usize {
+    let ctr: u64;
+    // Safety: CTR_EL0 is always readable from EL1
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack)) };
+    // DminLine is the log2 of the number of words in a line
+    4 << ((ctr >> 16) & 0xF)
+}
+
+/// Cleans and invalidates every data cache line covering [start, start + size) to the point of coherency, so
+/// that our writes are visible to other observers of memory, and that we see theirs from then on
+///
+/// Nothing but the data in the range is affected, since cleaning a line never loses writes in it.
+pub fn clean_invalidate_dcache(start: usize, size: usize) {
+    let line_size = dcache_line_size();
+    let end = start + size;
+    let mut line = start - start % line_size;
+    // Order earlier accesses to the range before the maintenance
+    barrier::dsb(barrier::SY);
+    while line < end {
+        // Safety: Cleaning and invalidating a line has no effect beyond the line's data reaching memory
+        unsafe { asm!("dc civac, {}", in(reg) line, options(nostack)) };
+        line += line_size;
+    }
+    // And the maintenance before anything after it
+    barrier::dsb(barrier::SY);
+}
@@ -0,0 +1,87 @@
+//! Taking IRQs at EL1
+//!
+//! The vectors themselves live in exceptions.S. IRQs are only unmasked while the bootloader waits on
+//! devices, and are masked again before the MMU is enabled and the kernel takes over the vectors.
+
+use aarch64_cpu::{
+    asm::{barrier, wfe},
+    registers::{
+        ReadWriteable, Readable, Writeable, CNTKCTL_EL1, DAIF, ELR_EL1, ESR_EL1, FAR_EL1, VBAR_EL1,
+    },
+};
+use core::ptr::addr_of;
+
+use crate::device_drivers::interrupt_controller::INTC;
+
+/// The event stream wakes wait_for_event whenever bit EVENT_STREAM_BIT of the counter flips, which is every
+/// 2^15 ticks (under 2ms at the 19.2MHz and 54MHz counters of the Pi 3 and 4)
+const EVENT_STREAM_BIT: u64 = 14;
+
+extern "C" {
+    static exception_vectors: u8;
+}
+
+/// Points VBAR_EL1 at our exception vectors, and starts the event stream so that wait_for_event never
+/// sleeps for long, even if the interrupt it waits on never comes
+///
+/// # Safety
+/// Must only be called while the bootloader's image is mapped where it was linked to run
+pub unsafe fn install_vectors() {
+    VBAR_EL1.set(addr_of!(exception_vectors) as u64);
+    CNTKCTL_EL1.modify(CNTKCTL_EL1::EVNTEN::Enable + CNTKCTL_EL1::EVNTI.val(EVENT_STREAM_BIT));
+    barrier::isb(barrier::SY);
+}
+
+/// Lets IRQs be taken
+///
+/// # Safety
+/// The exception vectors must be installed, and every interrupt the controller can raise must have a handler
+pub unsafe fn unmask_interrupts() {
+    DAIF.modify(DAIF::I::Unmasked);
+}
+
+pub fn mask_interrupts() {
+    DAIF.modify(DAIF::I::Masked);
+}
+
+/// Runs closure with IRQs masked, for state that is shared with an interrupt handler
+pub fn without_interrupts<T>(closure: impl FnOnce() -> T) -> T {
+    let masked = DAIF.matches_all(DAIF::I::Masked);
+    mask_interrupts();
+    let result = closure();
+    if !masked {
+        DAIF.modify(DAIF::I::Unmasked);
+    }
+
+    result
+}
+
+pub fn interrupts_enabled() -> bool {
+    DAIF.matches_all(DAIF::I::Unmasked)
+}
+
+/// Sleeps until an interrupt is taken or the event stream ticks
+///
+/// Returning from an exception sets the event register, so an interrupt taken just before this is called
+/// still wakes it straight away.
+pub fn wait_for_event() {
+    wfe();
+}
+
+#[no_mangle]
+extern "C" fn handle_irq() {
+    if let Some(intc) = INTC.get() {
+        intc.dispatch();
+    }
+}
+
+#[no_mangle]
+extern "C" fn handle_unexpected_exception(vector: u64) -> ! {
+    panic!(
+        "Unexpected exception through vector {}: ESR {:#X}, ELR {:#X}, FAR {:#X}",
+        vector,
+        ESR_EL1.get(),
+        ELR_EL1.get(),
+        FAR_EL1.get()
+    );
+}
@@ -1,9 +1,12 @@
 use common::{
-    allocators::page_frame_allocator::FrameAllocator, arch::Arch,
-    memory::address_space::AddressSpace, util::error::AddressSpaceError,
+    allocators::page_frame_allocator::FrameAllocator,
+    arch::{aarch64::page_table::PageTable, Arch},
+    memory::address_space::AddressSpace,
+    util::error::AddressSpaceError,
 };
 
-use crate::paging::page_table::PageTable;
+pub mod cache;
+pub mod interrupts;
 
 pub struct ArchImpl {}
 
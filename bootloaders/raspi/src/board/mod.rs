@@ -0,0 +1,112 @@
+use aarch64_cpu::registers::{Readable, MIDR_EL1};
+use common::device_tree::interrupts::{GicInterruptType, InterruptLine, Trigger};
+use core::fmt::Display;
+
+use crate::device_tree::RaspiDeviceTree;
+
+pub mod revision;
+
+/// MIDR_EL1 part numbers of the cores used by each supported board
+const PART_CORTEX_A53: u64 = 0xD03;
+const PART_CORTEX_A72: u64 = 0xD08;
+
+/// The Raspberry Pi models the bootloader knows how to drive
+#[derive(Clone, Copy, PartialEq)]
+pub enum Board {
+    Raspi3,
+    Raspi4,
+}
+
+/// The interrupt controller that routes peripheral interrupts to the cores
+#[derive(Clone, Copy, PartialEq)]
+pub enum InterruptController {
+    /// The BCM2835 "ARM control" block, chained behind the BCM2836 per-core local controller
+    Bcm2836,
+    /// The ARM GIC-400
+    Gic400,
+}
+
+impl Board {
+    /// Works out which board we are running on
+    ///
+    /// The root compatible of the device tree is preferred, since the firmware fills it in for the exact
+    /// model. Without a usable device tree, we fall back to identifying the CPU cores, which differ between
+    /// every supported board.
+    pub fn detect(dtb: Option<&RaspiDeviceTree>) -> Board {
+        dtb.and_then(Self::from_device_tree)
+            .unwrap_or_else(Self::from_cpu)
+    }
+
+    /// Identifies the board from the SoC named in the root compatible of the device tree
+    pub fn from_device_tree(dtb: &RaspiDeviceTree) -> Option<Board> {
+        let root = dtb.device_tree().root()?;
+        if root.is_compatible("brcm,bcm2711") {
+            Some(Board::Raspi4)
+        } else if root.is_compatible("brcm,bcm2837") || root.is_compatible("brcm,bcm2710") {
+            Some(Board::Raspi3)
+        } else {
+            None
+        }
+    }
+
+    /// Identifies the board from the part number of the core we are running on
+    pub fn from_cpu() -> Board {
+        match MIDR_EL1.read(MIDR_EL1::PartNum) {
+            PART_CORTEX_A72 => Board::Raspi4,
+            PART_CORTEX_A53 => Board::Raspi3,
+            // Anything else is at least as likely to be a Pi 3 class board, which also covers QEMU's raspi3b
+            _ => Board::Raspi3,
+        }
+    }
+
+    /// Physical address at which the ARM sees the start of the main peripheral window
+    pub fn mmio_base(&self) -> usize {
+        match self {
+            Board::Raspi3 => 0x3F000000,
+            // "a peripheral described in this document ... [is] visible to the ARM at 0x0_FEnn_nnnn if Low
+            // Peripheral mode is enabled."
+            // - BCM2711 ARM PERIPHERALS
+            Board::Raspi4 => 0xFE000000,
+        }
+    }
+
+    pub fn interrupt_controller(&self) -> InterruptController {
+        match self {
+            Board::Raspi3 => InterruptController::Bcm2836,
+            Board::Raspi4 => InterruptController::Gic400,
+        }
+    }
+
+    /// The usual interrupt line of the ARM mailbox, as the board's device tree describes it
+    pub fn mailbox_interrupt(&self) -> InterruptLine {
+        match self {
+            // ARM specific interrupt 1 of the basic pending register
+            Board::Raspi3 => InterruptLine::Bcm2835 { bank: 0, irq: 1 },
+            Board::Raspi4 => InterruptLine::Gic {
+                kind: GicInterruptType::Spi,
+                number: 0x21,
+                intid: 0x41,
+                trigger: Trigger::LevelHigh,
+                cpu_mask: 0,
+            },
+        }
+    }
+}
+
+impl Display for Board {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Board::Raspi3 => write!(f, "Raspberry Pi 3"),
+            Board::Raspi4 => write!(f, "Raspberry Pi 4"),
+        }
+    }
+}
+
+impl Display for InterruptController {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InterruptController::Bcm2836 => write!(f, "BCM2836 local + BCM2835 ARM control"),
+            InterruptController::Gic400 => write!(f, "GIC-400"),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Decoding of the board revision codes reported by the firmware
+//!
+//! Every board made since the Pi 2 uses the "new style" revision code, laid out as NOQu uuWu FMMM CCCC PPPP
+//! TTTT TTTT RRRR (from the most significant bit down). F is set for new style codes, M encodes the amount of
+//! RAM, C the manufacturer, P the SoC, T the model and R the board revision. The older codes used by the
+//! first Pi 1 boards are only a lookup table of their own, and aren't decoded.
+
+use core::fmt::Display;
+
+const NEW_STYLE: u32 = 1 << 23;
+
+/// The model of Raspberry Pi, as found in bits 4-11 of the revision code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    A,
+    B,
+    APlus,
+    BPlus,
+    Pi2B,
+    Alpha,
+    Cm1,
+    Pi3B,
+    Zero,
+    Cm3,
+    ZeroW,
+    Pi3BPlus,
+    Pi3APlus,
+    Cm3Plus,
+    Pi4B,
+    Zero2W,
+    Pi400,
+    Cm4,
+    Cm4S,
+    Pi5,
+    Unknown(u32),
+}
+
+/// The manufacturer of the board, as found in bits 16-19 of the revision code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Manufacturer {
+    SonyUk,
+    Egoman,
+    Embest,
+    SonyJapan,
+    Stadium,
+    Unknown(u32),
+}
+
+/// A decoded new style revision code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoardRevision {
+    pub model: Model,
+    /// Amount of RAM on the board, in bytes
+    pub memory_size: u64,
+    pub manufacturer: Manufacturer,
+    /// Revision of the board itself, eg 2 for a v1.2 board
+    pub revision: u32,
+}
+
+impl BoardRevision {
+    /// Decodes a revision code, or returns None for old style codes
+    pub fn decode(code: u32) -> Option<BoardRevision> {
+        if code & NEW_STYLE == 0 {
+            return None;
+        }
+
+        let model = match (code >> 4) & 0xFF {
+            0x0 => Model::A,
+            0x1 => Model::B,
+            0x2 => Model::APlus,
+            0x3 => Model::BPlus,
+            0x4 => Model::Pi2B,
+            0x5 => Model::Alpha,
+            0x6 => Model::Cm1,
+            0x8 => Model::Pi3B,
+            0x9 => Model::Zero,
+            0xA => Model::Cm3,
+            0xC => Model::ZeroW,
+            0xD => Model::Pi3BPlus,
+            0xE => Model::Pi3APlus,
+            0x10 => Model::Cm3Plus,
+            0x11 => Model::Pi4B,
+            0x12 => Model::Zero2W,
+            0x13 => Model::Pi400,
+            0x14 => Model::Cm4,
+            0x15 => Model::Cm4S,
+            0x17 => Model::Pi5,
+            other => Model::Unknown(other),
+        };
+        let manufacturer = match (code >> 16) & 0xF {
+            0 => Manufacturer::SonyUk,
+            1 => Manufacturer::Egoman,
+            2 | 4 => Manufacturer::Embest,
+            3 => Manufacturer::SonyJapan,
+            5 => Manufacturer::Stadium,
+            other => Manufacturer::Unknown(other),
+        };
+        // 256MiB, doubling with every step
+        let memory_size = 0x10000000 << ((code >> 20) & 0x7);
+
+        Some(BoardRevision {
+            model,
+            memory_size,
+            manufacturer,
+            revision: code & 0xF,
+        })
+    }
+}
+
+impl Display for Model {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Model::A => write!(f, "A"),
+            Model::B => write!(f, "B"),
+            Model::APlus => write!(f, "A+"),
+            Model::BPlus => write!(f, "B+"),
+            Model::Pi2B => write!(f, "2B"),
+            Model::Alpha => write!(f, "Alpha"),
+            Model::Cm1 => write!(f, "CM1"),
+            Model::Pi3B => write!(f, "3B"),
+            Model::Zero => write!(f, "Zero"),
+            Model::Cm3 => write!(f, "CM3"),
+            Model::ZeroW => write!(f, "Zero W"),
+            Model::Pi3BPlus => write!(f, "3B+"),
+            Model::Pi3APlus => write!(f, "3A+"),
+            Model::Cm3Plus => write!(f, "CM3+"),
+            Model::Pi4B => write!(f, "4B"),
+            Model::Zero2W => write!(f, "Zero 2 W"),
+            Model::Pi400 => write!(f, "400"),
+            Model::Cm4 => write!(f, "CM4"),
+            Model::Cm4S => write!(f, "CM4S"),
+            Model::Pi5 => write!(f, "5"),
+            Model::Unknown(model) => write!(f, "unknown model {:#X}", model),
+        }
+    }
+}
+
+impl Display for Manufacturer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Manufacturer::SonyUk => write!(f, "Sony UK"),
+            Manufacturer::Egoman => write!(f, "Egoman"),
+            Manufacturer::Embest => write!(f, "Embest"),
+            Manufacturer::SonyJapan => write!(f, "Sony Japan"),
+            Manufacturer::Stadium => write!(f, "Stadium"),
+            Manufacturer::Unknown(id) => write!(f, "unknown manufacturer {}", id),
+        }
+    }
+}
+
+impl Display for BoardRevision {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Raspberry Pi {} rev 1.{}, {}MiB RAM, made by {}",
+            self.model,
+            self.revision,
+            self.memory_size / 0x100000,
+            self.manufacturer
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BoardRevision, Manufacturer, Model};
+    use kernel::{kprint, kprintln};
+
+    #[test_case]
+    fn board_revision_tests() {
+        kprint!("Testing board revision decoding...");
+
+        // The code QEMU's raspi3b reports
+        assert_eq!(
+            BoardRevision::decode(0xA02082),
+            Some(BoardRevision {
+                model: Model::Pi3B,
+                memory_size: 0x40000000,
+                manufacturer: Manufacturer::SonyUk,
+                revision: 2,
+            })
+        );
+        // A 4GiB Pi 4B v1.1
+        assert_eq!(
+            BoardRevision::decode(0xC03111),
+            Some(BoardRevision {
+                model: Model::Pi4B,
+                memory_size: 0x100000000,
+                manufacturer: Manufacturer::SonyUk,
+                revision: 1,
+            })
+        );
+        // An 8GiB Pi 4B made by Embest
+        let revision = BoardRevision::decode(0xD04114).unwrap();
+        assert_eq!(revision.memory_size, 0x200000000);
+        assert_eq!(revision.manufacturer, Manufacturer::Embest);
+        // Old style codes aren't decoded
+        assert_eq!(BoardRevision::decode(0x000E), None);
+
+        kprintln!("Success!");
+    }
+}
@@ -1,25 +1,48 @@
-#![allow(unused_imports)] // Because some imports are used conditionally and mistakenly seen as unused
-
 use core::arch::asm;
 
-use common::util::register_ref::RegisterRef;
+use common::{
+    device_drivers::registry::{DeviceResources, Driver},
+    util::{error::DeviceError, register_ref::RegisterRef},
+};
 use tock_registers::{
     interfaces::ReadWriteable, register_bitfields, register_structs, registers::ReadWrite,
 };
 
 use self::{
+    GPFSEL1::{FSEL16, FSEL17},
     GPIO_PUP_PDN_CNTRL_REG0::{GPIO_PUP_PDN_CNTRL14, GPIO_PUP_PDN_CNTRL15},
     GPPUD::PUD,
     GPPUDCLK0::{PUDCLK14, PUDCLK15},
 };
 
-use super::MMIO_BASE;
+use super::EarlyDevices;
+use crate::board::Board;
+
+/// Offset of the GPIO registers from the start of the peripheral window
+pub const GPIO_MMIO_OFFSET: usize = 0x200000;
+pub const GPIO_COMPATIBLE: &[&str] = &["brcm,bcm2835-gpio", "brcm,bcm2711-gpio"];
+
+pub const DRIVER: Driver<EarlyDevices> = Driver {
+    name: "gpio",
+    compatible: GPIO_COMPATIBLE,
+    probe,
+};
 
-pub const GPIO_PHYS_BASE: usize = MMIO_BASE + 0x200000;
+fn probe(resources: &DeviceResources, devices: &mut EarlyDevices) -> Result<(), DeviceError> {
+    if devices.gpio.is_some() {
+        return Err(DeviceError::Busy);
+    }
+    let (base, _) = resources.regs().next().ok_or(DeviceError::BadOperand)?;
+    // Safety: The address comes from a device tree node that is compatible with this driver
+    devices.gpio = Some(unsafe { Gpio::new(base, devices.board) });
+    Ok(())
+}
 
 register_structs! {
    pub GpioRegisters {
       (0x00 => reserved0),
+      (0x04 => gpfsel1: ReadWrite<u32, GPFSEL1::Register>),
+      (0x08 => reserved3),
       (0x94 => gppud: ReadWrite<u32, GPPUD::Register>),  // RPI3 only
       (0x98 => gppudclk0: ReadWrite<u32, GPPUDCLK0::Register>), // RPI3 only,
       (0x9C => reserved1),
@@ -32,6 +55,17 @@ register_structs! {
 register_bitfields!(
    u32,
 
+   GPFSEL1 [
+      FSEL16 OFFSET(18) NUMBITS(3) [
+         INPUT = 0,
+         ALT3 = 7,
+      ],
+      FSEL17 OFFSET(21) NUMBITS(3) [
+         INPUT = 0,
+         ALT3 = 7,
+      ],
+   ],
+
    GPPUD [
       PUD OFFSET(0) NUMBITS(2) [
          NONE = 0,
@@ -64,25 +98,45 @@ register_bitfields!(
 
 pub struct Gpio {
     registers: RegisterRef<GpioRegisters>,
+    board: Board,
 }
 
 impl Gpio {
     /// Creates a new representation of the General Purpose I/O pins
     ///
     /// This acts essentially as a simple wrapper around a chunk of MMIO specified by start_addr,
-    /// so that certain useful register manipulations can be abstracted away into method calls.
+    /// so that certain useful register manipulations can be abstracted away into method calls. The board
+    /// decides which of the (incompatible) pull control registers are used.
     ///
     /// # Safety
     /// start_addr must be dereferencable to GpioRegisters (ie, it must point to the correct start address in MMIO).
-    pub unsafe fn new(start_addr: usize) -> Self {
+    pub unsafe fn new(start_addr: usize, board: Board) -> Self {
         Self {
             registers: RegisterRef::new(start_addr),
+            board,
         }
     }
 
-    #[cfg(feature = "raspi3")]
     /// Configures the GPIO pins 14 and 15 to be neither UP nor DOWN, as expected by UART0
     pub fn configure_uart0_pull(&mut self) {
+        match self.board {
+            Board::Raspi3 => self.configure_uart0_pull_gppud(),
+            Board::Raspi4 => self.configure_uart0_pull_pup_pdn(),
+        }
+    }
+
+    /// Routes UART0's CTS and RTS lines to GPIO pins 16 and 17 (or gives the pins back as inputs), for
+    /// hardware flow control
+    pub fn configure_uart0_flow_control(&mut self, enabled: bool) {
+        if enabled {
+            self.registers.gpfsel1.modify(FSEL16::ALT3 + FSEL17::ALT3);
+        } else {
+            self.registers.gpfsel1.modify(FSEL16::INPUT + FSEL17::INPUT);
+        }
+    }
+
+    /// Pull configuration through GPPUD and GPPUDCLK0, as found on the BCM2837
+    fn configure_uart0_pull_gppud(&mut self) {
         // We have to set pins 14 and 15 to neither pull up nor pull down
         // Yet I still don't quite understand why this specific sequence of register writes
         // It seems to be something to do with using GPPUD to specify the mode you want, then using
@@ -112,9 +166,8 @@ impl Gpio {
         self.registers.gppudclk0.modify(PUDCLK15::CLEAR);
     }
 
-    #[cfg(feature = "raspi4")]
-    /// Configures the GPIO pins 14 and 15 to be neither UP nor DOWN, as expected by UART0
-    pub fn configure_uart0_pull(&mut self) {
+    /// Pull configuration through GPIO_PUP_PDN_CNTRL_REG0, as found on the BCM2711
+    fn configure_uart0_pull_pup_pdn(&mut self) {
         self.registers
             .pup_pdn_ctrl_reg0
             .modify(GPIO_PUP_PDN_CNTRL14::NONE);
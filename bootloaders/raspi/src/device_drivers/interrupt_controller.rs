@@ -0,0 +1,260 @@
+//! Driver for the interrupt controller that delivers peripheral interrupts to the boot core
+//!
+//! On the Raspberry Pi 3 that is the BCM2835 ARM control block, whose interrupts reach the cores through the
+//! BCM2836 local controller. That one sends them all to core 0 out of reset, so it needs no setup of its own.
+//! On the Raspberry Pi 4 it is the GIC-400, of which we only set up what core 0 needs.
+//!
+//! The bootloader only ever handles a few interrupts, so their handlers are kept in a small fixed table.
+
+use common::{
+    device_drivers::registry::{DeviceResources, Driver},
+    device_tree::interrupts::InterruptLine,
+    util::{
+        error::DeviceError, register_ref::RegisterRef, single_threaded_cell::SingleThreadedCell,
+    },
+};
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
+
+use super::EarlyDevices;
+
+/// Offset of the ARM control interrupt registers from the start of the peripheral window
+pub const ARMCTRL_MMIO_OFFSET: usize = 0xB200;
+/// The usual addresses of the GIC-400's distributor and CPU interface, which are outside the main
+/// peripheral window
+pub const GIC_DISTRIBUTOR_ADDR: usize = 0xFF841000;
+pub const GIC_CPU_INTERFACE_ADDR: usize = 0xFF842000;
+pub const ARMCTRL_COMPATIBLE: &[&str] = &["brcm,bcm2835-armctrl-ic", "brcm,bcm2836-armctrl-ic"];
+pub const GIC_COMPATIBLE: &[&str] = &["arm,gic-400"];
+/// Upper bound on the number of interrupts with a handler
+const MAX_HANDLERS: usize = 4;
+/// Interrupt IDs from here on are special, and 1023 means nothing is pending
+const GIC_SPECIAL_INTID: u32 = 1020;
+/// Only interrupts of a higher priority (a lower value) than the mask are signalled to the core
+const GIC_PRIORITY_MASK: u32 = 0xF0;
+const GIC_PRIORITY: u8 = 0xA0;
+
+/// The interrupt controller handle_irq dispatches to, set once the handlers are registered
+pub static INTC: SingleThreadedCell<Intc> = SingleThreadedCell::new();
+
+pub const ARMCTRL_DRIVER: Driver<EarlyDevices> = Driver {
+    name: "armctrl-ic",
+    compatible: ARMCTRL_COMPATIBLE,
+    probe: probe_armctrl,
+};
+
+pub const GIC_DRIVER: Driver<EarlyDevices> = Driver {
+    name: "gic-400",
+    compatible: GIC_COMPATIBLE,
+    probe: probe_gic,
+};
+
+fn probe_armctrl(
+    resources: &DeviceResources,
+    devices: &mut EarlyDevices,
+) -> Result<(), DeviceError> {
+    if devices.intc.is_some() {
+        return Err(DeviceError::Busy);
+    }
+    let (base, _) = resources.regs().next().ok_or(DeviceError::BadOperand)?;
+    // Safety: The address comes from a device tree node that is compatible with this driver
+    devices.intc = Some(unsafe { Intc::new_armctrl(base) });
+    Ok(())
+}
+
+fn probe_gic(resources: &DeviceResources, devices: &mut EarlyDevices) -> Result<(), DeviceError> {
+    if devices.intc.is_some() {
+        return Err(DeviceError::Busy);
+    }
+    // The distributor comes first, then the CPU interface
+    let mut regs = resources.regs();
+    let (Some((distributor, _)), Some((cpu_interface, _))) = (regs.next(), regs.next()) else {
+        return Err(DeviceError::BadOperand);
+    };
+    // Safety: As above
+    devices.intc = Some(unsafe { Intc::new_gic(distributor, cpu_interface) });
+    Ok(())
+}
+
+register_structs! {
+   pub ArmControlRegisters {
+      (0x00 => pending_basic: ReadOnly<u32>),
+      (0x04 => pending: [ReadOnly<u32>; 2]),
+      (0x0C => reserved0),
+      (0x10 => enable: [ReadWrite<u32>; 2]),
+      (0x18 => enable_basic: ReadWrite<u32>),
+      (0x1C => disable: [ReadWrite<u32>; 2]),
+      (0x24 => disable_basic: ReadWrite<u32>),
+      (0x28 => @END),
+   }
+}
+
+register_structs! {
+   pub GicDistributorRegisters {
+      (0x000 => ctlr: ReadWrite<u32>),
+      (0x004 => reserved0),
+      (0x100 => isenabler: [ReadWrite<u32>; 32]),
+      (0x180 => icenabler: [ReadWrite<u32>; 32]),
+      (0x200 => reserved1),
+      (0x400 => ipriorityr: [ReadWrite<u8>; 1024]),
+      (0x800 => itargetsr: [ReadWrite<u8>; 1024]),
+      (0xC00 => reserved2),
+      (0x1000 => @END),
+   }
+}
+
+register_structs! {
+   pub GicCpuInterfaceRegisters {
+      (0x00 => ctlr: ReadWrite<u32>),
+      (0x04 => pmr: ReadWrite<u32>),
+      (0x08 => reserved0),
+      (0x0C => iar: ReadOnly<u32>),
+      (0x10 => eoir: WriteOnly<u32>),
+      (0x14 => @END),
+   }
+}
+
+enum Chip {
+    ArmControl(RegisterRef<ArmControlRegisters>),
+    Gic400 {
+        distributor: RegisterRef<GicDistributorRegisters>,
+        cpu_interface: RegisterRef<GicCpuInterfaceRegisters>,
+    },
+}
+
+pub struct Intc {
+    chip: Chip,
+    /// Handlers by interrupt ID: bank * 32 + irq for the ARM control block, and the INTID for the GIC
+    handlers: [Option<(u32, fn())>; MAX_HANDLERS],
+}
+
+impl Intc {
+    /// Creates a driver for the BCM2835 ARM control block at base
+    pub unsafe fn new_armctrl(base: usize) -> Self {
+        Self {
+            chip: Chip::ArmControl(RegisterRef::new(base)),
+            handlers: [None; MAX_HANDLERS],
+        }
+    }
+
+    /// Creates a driver for the GIC-400 with the given distributor and CPU interface
+    pub unsafe fn new_gic(distributor: usize, cpu_interface: usize) -> Self {
+        Self {
+            chip: Chip::Gic400 {
+                distributor: RegisterRef::new(distributor),
+                cpu_interface: RegisterRef::new(cpu_interface),
+            },
+            handlers: [None; MAX_HANDLERS],
+        }
+    }
+
+    /// Unmasks line at the controller, calling handler whenever it is raised
+    ///
+    /// Fails with BadOperand if the line belongs to a different controller, and with Busy once MAX_HANDLERS
+    /// handlers are registered.
+    pub fn enable(&mut self, line: InterruptLine, handler: fn()) -> Result<(), DeviceError> {
+        let id = self.id(line).ok_or(DeviceError::BadOperand)?;
+        let entry = self
+            .handlers
+            .iter_mut()
+            .find(|entry| entry.is_none())
+            .ok_or(DeviceError::Busy)?;
+        *entry = Some((id, handler));
+        let bit = 1 << (id % 32);
+        match &self.chip {
+            Chip::ArmControl(regs) => match id / 32 {
+                0 => regs.enable_basic.set(bit),
+                bank => regs.enable[bank as usize - 1].set(bit),
+            },
+            Chip::Gic400 {
+                distributor,
+                cpu_interface,
+            } => {
+                // Only core 0 ever takes interrupts in the bootloader
+                distributor.ipriorityr[id as usize].set(GIC_PRIORITY);
+                distributor.itargetsr[id as usize].set(1);
+                distributor.isenabler[id as usize / 32].set(bit);
+                distributor.ctlr.set(1);
+                cpu_interface.pmr.set(GIC_PRIORITY_MASK);
+                cpu_interface.ctlr.set(1);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Masks every interrupt with a handler at the controller, leaving it as we found it for the kernel
+    pub fn disable_all(&self) {
+        for &(id, _) in self.handlers.iter().flatten() {
+            self.disable(id);
+        }
+    }
+
+    /// Calls the handler of every pending interrupt, from handle_irq
+    pub fn dispatch(&self) {
+        match &self.chip {
+            // Nothing but the interrupts with a handler is ever enabled, so those are the only ones to check
+            Chip::ArmControl(regs) => {
+                for &(id, handler) in self.handlers.iter().flatten() {
+                    let pending = match id / 32 {
+                        0 => regs.pending_basic.get(),
+                        bank => regs.pending[bank as usize - 1].get(),
+                    };
+                    if pending & (1 << (id % 32)) != 0 {
+                        handler();
+                    }
+                }
+            }
+            Chip::Gic400 { cpu_interface, .. } => loop {
+                let iar = cpu_interface.iar.get();
+                let id = iar & 0x3FF;
+                if id >= GIC_SPECIAL_INTID {
+                    break;
+                }
+                match self.handler(id) {
+                    Some(handler) => handler(),
+                    // Someone else enabled it, so make sure it doesn't keep firing
+                    None => self.disable(id),
+                }
+                cpu_interface.eoir.set(iar);
+            },
+        }
+    }
+
+    /// The interrupt ID line has on this controller, if it belongs to it
+    fn id(&self, line: InterruptLine) -> Option<u32> {
+        match (&self.chip, line) {
+            (Chip::ArmControl(_), InterruptLine::Bcm2835 { bank, irq }) if bank < 3 && irq < 32 => {
+                Some(bank * 32 + irq)
+            }
+            (Chip::Gic400 { .. }, InterruptLine::Gic { intid, .. })
+                if intid < GIC_SPECIAL_INTID =>
+            {
+                Some(intid)
+            }
+            _ => None,
+        }
+    }
+
+    fn handler(&self, id: u32) -> Option<fn()> {
+        self.handlers
+            .iter()
+            .flatten()
+            .find(|&&(handler_id, _)| handler_id == id)
+            .map(|&(_, handler)| handler)
+    }
+
+    fn disable(&self, id: u32) {
+        let bit = 1 << (id % 32);
+        match &self.chip {
+            Chip::ArmControl(regs) => match id / 32 {
+                0 => regs.disable_basic.set(bit),
+                bank => regs.disable[bank as usize - 1].set(bit),
+            },
+            Chip::Gic400 { distributor, .. } => distributor.icenabler[id as usize / 32].set(bit),
+        }
+    }
+}
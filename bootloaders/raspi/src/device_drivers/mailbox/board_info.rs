@@ -0,0 +1,61 @@
+//! Hardware and firmware identification, as reported by the firmware
+//!
+//! Not every firmware answers every tag (QEMU only emulates some of them), so each piece of information is
+//! optional on its own.
+
+use common::util::error::DeviceError;
+
+use super::{
+    message::{
+        GetBoardMacAddress, GetBoardModel, GetBoardRevision, GetBoardSerial, GetDmaChannels,
+        GetFirmwareRevision, PropertyBuffer,
+    },
+    Mailbox,
+};
+use crate::board::revision::BoardRevision;
+
+/// Everything the firmware tells us about the board it's running on
+#[derive(Clone, Copy, Debug)]
+pub struct BoardInfo {
+    pub firmware_revision: Option<u32>,
+    pub model: Option<u32>,
+    /// The raw revision code, see BoardInfo::decoded_revision
+    pub revision: Option<u32>,
+    pub mac_address: Option<[u8; 6]>,
+    pub serial: Option<u64>,
+    /// A mask of the DMA channels the ARM is free to use
+    pub dma_channels: Option<u32>,
+}
+
+impl BoardInfo {
+    /// Decodes the revision code into the Pi model, RAM size and manufacturer
+    pub fn decoded_revision(&self) -> Option<BoardRevision> {
+        self.revision.and_then(BoardRevision::decode)
+    }
+}
+
+impl Mailbox {
+    /// Queries the firmware for everything in BoardInfo, in a single request
+    ///
+    /// Fails only if the request as a whole fails. Tags the firmware doesn't answer are left as None.
+    pub fn board_info(&mut self) -> Result<BoardInfo, DeviceError> {
+        let mut message = PropertyBuffer::<32>::new();
+        // The buffer is sized to fit every tag below
+        let firmware_revision = message.add::<GetFirmwareRevision>(()).unwrap();
+        let model = message.add::<GetBoardModel>(()).unwrap();
+        let revision = message.add::<GetBoardRevision>(()).unwrap();
+        let mac_address = message.add::<GetBoardMacAddress>(()).unwrap();
+        let serial = message.add::<GetBoardSerial>(()).unwrap();
+        let dma_channels = message.add::<GetDmaChannels>(()).unwrap();
+        self.send_property_mail(&mut message)?;
+
+        Ok(BoardInfo {
+            firmware_revision: message.response(&firmware_revision).ok(),
+            model: message.response(&model).ok(),
+            revision: message.response(&revision).ok(),
+            mac_address: message.response(&mac_address).ok(),
+            serial: message.response(&serial).ok(),
+            dma_channels: message.response(&dma_channels).ok(),
+        })
+    }
+}
@@ -1,103 +1,313 @@
-use core::mem::size_of;
+//! Property channel messages
+//!
+//! A message is a buffer of tags, each of which is a request to the firmware along with room for its
+//! response. Tags are packed one after another into a PropertyBuffer, which is sent through the mailbox in a
+//! single round trip. Afterwards, the response of every tag is read back (and checked) on its own.
+
+use core::{
+    marker::PhantomData,
+    mem::size_of,
+    ptr::{read_unaligned, write_unaligned},
+};
+
+use common::util::error::{CapacityError, DeviceError};
 
 pub const STATUS_SUCCESS: u32 = 0x80000000;
 pub const STATUS_FAILURE: u32 = 0x80000001;
 
-pub const CLOCK_UART: u32 = 2;
+/// Set in the request/response code of a tag once the firmware has responded to it, with the length of the
+/// response in the remaining bits
+const TAG_RESPONSE: u32 = 1 << 31;
+/// The buffer size and request/response code that start every buffer
+const HEADER_WORDS: usize = 2;
+/// The id, value buffer size and request/response code that start every tag
+const TAG_HEADER_WORDS: usize = 3;
 
-#[repr(C, align(16))]
-pub struct Message<T> {
-    msgsize: u32,
-    pub status: u32,
-    pub data: T,
-    null: u32,
+/// A property tag the firmware understands, along with the layouts of its request and response values
+///
+/// # Safety
+/// Request and Response must be plain data that is valid for any bit pattern (eg #[repr(C)] structs of
+/// integers, byte arrays or ()), as they are copied in and out of the buffer as raw memory
+pub unsafe trait PropertyTag {
+    const ID: u32;
+    type Request: Copy;
+    type Response: Copy;
 }
 
-pub trait MailboxMessageData {}
+/// Identifies a tag added to a PropertyBuffer, to read its response once the buffer has been sent
+pub struct TagHandle<T: PropertyTag> {
+    offset: usize,
+    tag: PhantomData<T>,
+}
+
+/// A property message of up to N words, holding any number of tags
+///
+/// The firmware only needs the buffer to be 16 byte aligned, but it is given cache lines of its own so that
+/// the cache maintenance around sending it never touches anything else.
+#[repr(C, align(64))]
+pub struct PropertyBuffer<const N: usize> {
+    words: [u32; N],
+    /// Number of words used so far, excluding the end tag
+    len: usize,
+}
+
+impl<const N: usize> PropertyBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            words: [0; N],
+            len: HEADER_WORDS,
+        }
+    }
+
+    /// Appends a tag to the buffer. The tag's value buffer is sized to fit both its request and response,
+    /// and padded to a whole number of words.
+    pub fn add<T: PropertyTag>(
+        &mut self,
+        request: T::Request,
+    ) -> Result<TagHandle<T>, CapacityError> {
+        let value_words = size_of::<T::Request>()
+            .max(size_of::<T::Response>())
+            .div_ceil(4);
+        let offset = self.len;
+        let end = offset + TAG_HEADER_WORDS + value_words;
+        // Always leave room for the end tag
+        if end + 1 > N {
+            return Err(CapacityError);
+        }
+
+        self.words[offset] = T::ID;
+        self.words[offset + 1] = (value_words * 4) as u32;
+        self.words[offset + 2] = 0;
+        let value = &mut self.words[offset + TAG_HEADER_WORDS..end];
+        value.fill(0);
+        // Safety: The value has room for the request, which PropertyTag guarantees is plain data
+        unsafe { write_unaligned(value.as_mut_ptr() as *mut T::Request, request) };
+        self.len = end;
+
+        Ok(TagHandle {
+            offset,
+            tag: PhantomData,
+        })
+    }
+
+    /// Returns the response the firmware gave to a tag, once the buffer has been sent
+    ///
+    /// Fails if the firmware rejected the whole buffer, didn't respond to this tag, or responded with less
+    /// than a full response.
+    pub fn response<T: PropertyTag>(&self, tag: &TagHandle<T>) -> Result<T::Response, DeviceError> {
+        if self.words[1] != STATUS_SUCCESS {
+            return Err(DeviceError::Other);
+        }
+        let code = self.words[tag.offset + 2];
+        if code & TAG_RESPONSE == 0 {
+            return Err(DeviceError::Other);
+        }
+        if ((code & !TAG_RESPONSE) as usize) < size_of::<T::Response>() {
+            return Err(DeviceError::BadOperand);
+        }
+
+        let value = &self.words[tag.offset + TAG_HEADER_WORDS..];
+        // Safety: add() made the value large enough for the response, which PropertyTag guarantees is plain
+        // data
+        Ok(unsafe { read_unaligned(value.as_ptr() as *const T::Response) })
+    }
+
+    /// Fills in the header and end tag, and returns the message as it is to be handed to the firmware
+    pub(super) fn finish(&mut self) -> &mut [u32] {
+        self.words[self.len] = 0;
+        let words = self.len + 1;
+        self.words[0] = (words * 4) as u32;
+        self.words[1] = 0;
+        &mut self.words[..words]
+    }
 
-#[derive(Default)]
+    /// The request/response code of the whole buffer
+    pub fn status(&self) -> u32 {
+        self.words[1]
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
 #[repr(C)]
-pub struct GetArmMemory {
-    tag: u32,
-    bufsize: u32,
-    status: u32,
+pub struct MemoryRegion {
     pub base: u32,
     pub size: u32,
 }
 
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, Default)]
 #[repr(C)]
-pub struct GetClockRate {
-    tag: u32,
-    bufsize: u32,
-    status: u32,
+pub struct ClockRate {
     pub id: u32,
     pub rate: u32,
 }
 
-#[derive(Default)]
+/// The state of a clock or power domain. Bit 0 is set if it's on, and bit 1 in a response if it doesn't
+/// exist. In a request to turn a power domain on or off, bit 1 asks the firmware to wait for it to settle.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct DeviceState {
+    pub id: u32,
+    pub state: u32,
+}
+
+/// A temperature in thousandths of a degree Celsius, for the sensor with the given id (only 0 exists)
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Temperature {
+    pub id: u32,
+    pub value: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
 #[repr(C)]
-pub struct SetClockRate {
-    tag: u32,
-    bufsize: u32,
-    status: u32,
+pub struct SetClockRateRequest {
     pub id: u32,
     pub rate: u32,
     pub skip_turbo: u32,
 }
 
-impl MailboxMessageData for GetArmMemory {}
-impl MailboxMessageData for GetClockRate {}
-impl MailboxMessageData for SetClockRate {}
-
-impl GetArmMemory {
-    pub fn new() -> Message<Self> {
-        Message {
-            msgsize: size_of::<Message<Self>>().try_into().unwrap(),
-            status: 0,
-            data: GetArmMemory {
-                tag: 0x00010005,
-                bufsize: 8,
-                status: 0,
-                base: 0,
-                size: 0,
-            },
-            null: 0,
-        }
-    }
+pub struct GetFirmwareRevision;
+pub struct GetBoardModel;
+pub struct GetBoardRevision;
+pub struct GetBoardMacAddress;
+pub struct GetBoardSerial;
+pub struct GetArmMemory;
+pub struct GetVcMemory;
+pub struct GetPowerState;
+pub struct SetPowerState;
+pub struct GetClockState;
+pub struct SetClockState;
+pub struct GetClockRate;
+pub struct GetClockRateMeasured;
+pub struct GetMaxClockRate;
+pub struct GetMinClockRate;
+pub struct SetClockRate;
+pub struct GetTemperature;
+pub struct GetMaxTemperature;
+pub struct GetDmaChannels;
+
+// Safety: Every request and response below is made up of nothing but integers
+unsafe impl PropertyTag for GetFirmwareRevision {
+    const ID: u32 = 0x00000001;
+    type Request = ();
+    type Response = u32;
 }
 
-impl GetClockRate {
-    pub fn new(id: u32) -> Message<Self> {
-        Message {
-            msgsize: size_of::<Message<Self>>().try_into().unwrap(),
-            status: 0,
-            data: Self {
-                tag: 0x00030002,
-                bufsize: 8,
-                status: 0,
-                id,
-                rate: 0,
-            },
-            null: 0,
-        }
-    }
+unsafe impl PropertyTag for GetBoardModel {
+    const ID: u32 = 0x00010001;
+    type Request = ();
+    type Response = u32;
 }
 
-impl SetClockRate {
-    pub fn new(id: u32, rate: u32) -> Message<Self> {
-        Message {
-            msgsize: size_of::<Message<Self>>().try_into().unwrap(),
-            status: 0,
-            data: Self {
-                tag: 0x00038002,
-                bufsize: 12,
-                status: 0,
-                id,
-                rate,
-                skip_turbo: 0,
-            },
-            null: 0,
-        }
-    }
+unsafe impl PropertyTag for GetBoardRevision {
+    const ID: u32 = 0x00010002;
+    type Request = ();
+    type Response = u32;
+}
+
+/// The MAC address, in network byte order
+unsafe impl PropertyTag for GetBoardMacAddress {
+    const ID: u32 = 0x00010003;
+    type Request = ();
+    type Response = [u8; 6];
+}
+
+unsafe impl PropertyTag for GetBoardSerial {
+    const ID: u32 = 0x00010004;
+    type Request = ();
+    type Response = u64;
+}
+
+unsafe impl PropertyTag for GetArmMemory {
+    const ID: u32 = 0x00010005;
+    type Request = ();
+    type Response = MemoryRegion;
+}
+
+unsafe impl PropertyTag for GetVcMemory {
+    const ID: u32 = 0x00010006;
+    type Request = ();
+    type Response = MemoryRegion;
+}
+
+/// Takes the id of the power domain to query
+unsafe impl PropertyTag for GetPowerState {
+    const ID: u32 = 0x00020001;
+    type Request = u32;
+    type Response = DeviceState;
+}
+
+unsafe impl PropertyTag for SetPowerState {
+    const ID: u32 = 0x00028001;
+    type Request = DeviceState;
+    type Response = DeviceState;
+}
+
+/// Takes the id of the clock to query
+unsafe impl PropertyTag for GetClockState {
+    const ID: u32 = 0x00030001;
+    type Request = u32;
+    type Response = DeviceState;
+}
+
+unsafe impl PropertyTag for SetClockState {
+    const ID: u32 = 0x00038001;
+    type Request = DeviceState;
+    type Response = DeviceState;
+}
+
+/// Takes the id of the clock to query. This is the rate the clock was set to, see GetClockRateMeasured for
+/// the rate it actually runs at.
+unsafe impl PropertyTag for GetClockRate {
+    const ID: u32 = 0x00030002;
+    type Request = u32;
+    type Response = ClockRate;
+}
+
+/// Takes the id of the clock to query
+unsafe impl PropertyTag for GetClockRateMeasured {
+    const ID: u32 = 0x00030047;
+    type Request = u32;
+    type Response = ClockRate;
+}
+
+/// Takes the id of the clock to query
+unsafe impl PropertyTag for GetMaxClockRate {
+    const ID: u32 = 0x00030004;
+    type Request = u32;
+    type Response = ClockRate;
+}
+
+/// Takes the id of the clock to query
+unsafe impl PropertyTag for GetMinClockRate {
+    const ID: u32 = 0x00030007;
+    type Request = u32;
+    type Response = ClockRate;
+}
+
+unsafe impl PropertyTag for SetClockRate {
+    const ID: u32 = 0x00038002;
+    type Request = SetClockRateRequest;
+    type Response = ClockRate;
+}
+
+/// Takes the id of the sensor to read
+unsafe impl PropertyTag for GetTemperature {
+    const ID: u32 = 0x00030006;
+    type Request = u32;
+    type Response = Temperature;
+}
+
+/// The temperature at which the firmware starts throttling the clocks. Takes the id of the sensor.
+unsafe impl PropertyTag for GetMaxTemperature {
+    const ID: u32 = 0x0003000A;
+    type Request = u32;
+    type Response = Temperature;
+}
+
+/// A mask of the DMA channels the ARM is free to use
+unsafe impl PropertyTag for GetDmaChannels {
+    const ID: u32 = 0x00060001;
+    type Request = ();
+    type Response = u32;
 }
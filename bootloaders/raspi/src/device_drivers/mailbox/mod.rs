@@ -1,26 +1,56 @@
-use common::util::{error::DeviceError, register_ref::RegisterRef};
-use tock_registers::{
-    interfaces::{ReadWriteable, Readable, Writeable},
-    register_bitfields, register_structs,
-    registers::{InMemoryRegister, ReadWrite},
+use common::{
+    device_drivers::registry::{DeviceResources, Driver},
+    device_tree::interrupts::InterruptLine,
+    util::{error::DeviceError, register_ref::RegisterRef},
 };
+use tock_registers::{register_bitfields, register_structs, registers::ReadWrite};
 
-use self::message::{MailboxMessageData, Message, STATUS_FAILURE};
+use self::message::{PropertyBuffer, PropertyTag};
 
-use super::MMIO_BASE;
+use super::EarlyDevices;
 
+pub mod board_info;
 pub mod message;
+pub mod power;
+pub mod request;
 
-pub const MAILBOX_PHYS_BASE: usize = MMIO_BASE + 0xB880;
-pub const MAILBOX_0_PHYS_BASE: usize = MAILBOX_PHYS_BASE + 0x0;
-pub const MAILBOX_1_PHYS_BASE: usize = MAILBOX_PHYS_BASE + 0x20;
+/// Offset of the mailbox registers from the start of the peripheral window
+pub const MAILBOX_MMIO_OFFSET: usize = 0xB880;
+pub const MAILBOX_0_OFFSET: usize = 0x0;
+pub const MAILBOX_1_OFFSET: usize = 0x20;
+pub const MAILBOX_COMPATIBLE: &[&str] = &["brcm,bcm2835-mbox"];
+/// How long to wait for the firmware to answer a message, in milliseconds. Even the slowest tags (eg
+/// changing the ARM clock) are answered well within this.
+const MAILBOX_TIMEOUT_MS: u64 = 1000;
+
+pub const DRIVER: Driver<EarlyDevices> = Driver {
+    name: "mailbox",
+    compatible: MAILBOX_COMPATIBLE,
+    probe,
+};
+
+fn probe(resources: &DeviceResources, devices: &mut EarlyDevices) -> Result<(), DeviceError> {
+    if devices.mailbox.is_some() {
+        return Err(DeviceError::Busy);
+    }
+    let (base, _) = resources.regs().next().ok_or(DeviceError::BadOperand)?;
+    // Safety: The address comes from a device tree node that is compatible with this driver
+    let mut mailbox = unsafe { Mailbox::new(base) };
+    mailbox.interrupt = resources
+        .interrupts()
+        .next()
+        .map(|interrupt| interrupt.line);
+    devices.mailbox = Some(mailbox);
+    Ok(())
+}
 
 register_structs!(
    pub MailboxRegisters {
       (0x00 => data: ReadWrite<u32, DATA::Register>),
       (0x04 => reserved0),
       (0x18 => status: ReadWrite<u32, STATUS::Register>),
-      (0x1C => reserved1),
+      (0x1C => config: ReadWrite<u32, CONFIG::Register>),
+      (0x20 => reserved1),
       (0x24 => @END),
    }
 );
@@ -37,87 +67,205 @@ register_bitfields!(
       LEVEL OFFSET(0) NUMBITS(8),
       EMPTY OFFSET(30) NUMBITS(1),
       FULL OFFSET(31) NUMBITS(1),
+   ],
+
+   CONFIG [
+      /// Raise the ARM mailbox interrupt while the mailbox isn't empty
+      DATA_IRQ OFFSET(0) NUMBITS(1),
    ]
 );
 
 pub struct Mailbox {
     mbox_0: RegisterRef<MailboxRegisters>,
     mbox_1: RegisterRef<MailboxRegisters>,
+    /// Translates the virtual address of a message buffer to the physical address the VideoCore sees it at
+    virt_to_phys: fn(usize) -> usize,
+    /// The interrupt raised when a reply is waiting, if we know where it is delivered
+    interrupt: Option<InterruptLine>,
 }
 
 impl Mailbox {
+    /// Creates a mailbox driver for use while memory is identity mapped
     pub unsafe fn new(start_addr: usize) -> Self {
         Self {
             mbox_0: RegisterRef::new(start_addr),
             mbox_1: RegisterRef::new(start_addr + 0x20),
+            virt_to_phys: |virt| virt,
+            interrupt: None,
         }
     }
 
-    /// Sends a single message to the VideoCore mailbox, blocking until a reply is received
-    ///
-    /// Note that the mailbox can only fit 32 bit addresses into its register, so caller must verify
-    /// that the address of their message is below 0x100000000. The mailbox also requires physical addresses,
-    /// so if virtual memory mapping is enabled a lookup from virt -> phys will be necessary first
-    pub fn send_property_mail<T: MailboxMessageData>(
-        &mut self,
-        message: &mut Message<T>,
-    ) -> Result<(), DeviceError> {
-        let message_ptr: *mut Message<T> = message;
-        // If the CPU receiving queue is full, we can't send a message or we risk losing the reply
-        // if the queue hasn't freed up space by the time the GPU services the request.
-        if self.mbox_0.status.is_set(STATUS::FULL) {
-            return Err(DeviceError::Busy);
-        }
-        // message_addr MUST be a physical address below 4GiB! It cannot be greater than 32 bits.
-        if message_ptr as usize >= 0x100000000 {
-            return Err(DeviceError::BadOperand);
-        }
+    pub fn interrupt(&self) -> Option<InterruptLine> {
+        self.interrupt
+    }
 
-        // Write mailbox address upper 28 bits into mbox_1 read register
-        // Write in channel 8, the property channel, the only channel supported
-        let data: InMemoryRegister<u32, DATA::Register> = InMemoryRegister::new(0);
-        data.modify(DATA::ADDR.val(message_ptr as u32 >> 4));
-        data.modify(DATA::CHANNEL.val(8));
+    pub fn set_interrupt(&mut self, interrupt: InterruptLine) {
+        self.interrupt = Some(interrupt);
+    }
 
-        // Send data to the VideoCore
-        self.mbox_1.data.set(data.get());
+    // Unsafe because bad things will happen if the address translation function is not correct for the
+    // mailbox's message buffers, which are statics of the bootloader
+    pub unsafe fn set_translator(&mut self, virt_to_phys: fn(usize) -> usize) {
+        self.virt_to_phys = virt_to_phys;
+    }
 
-        // Block until a message is received from the VideoCore on channel 8
-        while self.mbox_0.status.is_set(STATUS::EMPTY) || self.mbox_0.data.read(DATA::CHANNEL) != 8
-        {
-        }
+    /// Sends a buffer of property tags to the VideoCore mailbox, blocking until a reply is received. The
+    /// response of each tag can then be read from the buffer.
+    ///
+    /// The message is copied into a buffer of the mailbox's own to be sent, and the reply copied back into
+    /// it, so it can live anywhere (including the stack). See Mailbox::submit to send a message without
+    /// waiting for the reply.
+    ///
+    /// Gives up after MAILBOX_TIMEOUT_MS, leaving the message as it was sent. A late reply only ever reaches
+    /// the mailbox's buffer, which isn't reused until the reply has been read.
+    pub fn send_property_mail<const N: usize>(
+        &mut self,
+        message: &mut PropertyBuffer<N>,
+    ) -> Result<(), DeviceError> {
+        self.submit(message)?.wait()
+    }
 
-        if message.status == STATUS_FAILURE {
-            Err(DeviceError::Other)
-        } else {
-            Ok(())
-        }
+    /// Sends a message holding a single tag, returning its response
+    pub fn send_tag<T: PropertyTag>(
+        &mut self,
+        request: T::Request,
+    ) -> Result<T::Response, DeviceError> {
+        let mut message = PropertyBuffer::<16>::new();
+        let tag = message
+            .add::<T>(request)
+            .map_err(|_| DeviceError::BadOperand)?;
+        self.send_property_mail(&mut message)?;
+        message.response(&tag)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
-        message::{GetClockRate, SetClockRate, STATUS_FAILURE, STATUS_SUCCESS},
-        Mailbox, MAILBOX_PHYS_BASE,
+        message::{
+            GetArmMemory, GetClockRate, GetVcMemory, PropertyBuffer, SetClockRate,
+            SetClockRateRequest,
+        },
+        power::{Clock, PowerDomain},
+        request::MAX_IN_FLIGHT,
+        Mailbox, MAILBOX_MMIO_OFFSET,
     };
+    use crate::board::Board;
+    use common::util::error::DeviceError;
     use kernel::{kprint, kprintln};
 
     #[test_case]
     fn mailbox_tests() {
         kprint!("Testing VideoCore Mailbox device driver...");
 
-        let mut mailbox = unsafe { Mailbox::new(MAILBOX_PHYS_BASE) };
+        let mut mailbox =
+            unsafe { Mailbox::new(Board::from_cpu().mmio_base() + MAILBOX_MMIO_OFFSET) };
 
         // Try setting the clock rate for the UART...
         let new_clock_rate = 3000000;
-        let mut message = SetClockRate::new(2, new_clock_rate);
+        let mut message = PropertyBuffer::<16>::new();
+        let set = message
+            .add::<SetClockRate>(SetClockRateRequest {
+                id: Clock::Uart as u32,
+                rate: new_clock_rate,
+                skip_turbo: 0,
+            })
+            .unwrap();
         mailbox.send_property_mail(&mut message).unwrap();
+        assert_eq!(message.response(&set).unwrap().rate, new_clock_rate);
 
-        // Read the new clock rate to confirm it is correct
-        let mut message = GetClockRate::new(2);
+        // Then read it back, along with a few other tags in the same message
+        let mut message = PropertyBuffer::<32>::new();
+        let arm = message.add::<GetArmMemory>(()).unwrap();
+        let rate = message.add::<GetClockRate>(Clock::Uart as u32).unwrap();
+        let vc = message.add::<GetVcMemory>(()).unwrap();
         mailbox.send_property_mail(&mut message).unwrap();
-        assert_eq!(message.data.rate, new_clock_rate);
+        assert_eq!(message.response(&rate).unwrap().rate, new_clock_rate);
+        assert!(message.response(&arm).unwrap().size != 0);
+        assert!(message.response(&vc).unwrap().size != 0);
+
+        // Both QEMU and the real firmware answer these, so there should always be a revision to decode
+        let info = mailbox.board_info().unwrap();
+        assert!(info.firmware_revision.is_some());
+        assert!(info.decoded_revision().is_some());
+        assert!(info.dma_channels.is_some_and(|mask| mask != 0));
+
+        // The typed clock and power API goes through the same tags, and puts the UART back at its usual rate
+        assert_eq!(mailbox.clock_rate(Clock::Uart).unwrap(), new_clock_rate);
+        assert_eq!(
+            mailbox
+                .set_clock_rate(Clock::Uart, 30000000, false)
+                .unwrap(),
+            30000000
+        );
+        assert_eq!(mailbox.clock_rate(Clock::Uart).unwrap(), 30000000);
+        assert!(mailbox.clock_enabled(Clock::Uart).unwrap());
+        assert!(mailbox.set_power_domain(PowerDomain::Uart0, true).unwrap());
+        assert!(mailbox.power_domain_on(PowerDomain::Uart0).unwrap());
+        assert!(mailbox.temperature().unwrap() < mailbox.max_temperature().unwrap());
+
+        // The same tags batched into as few messages as possible, as the boot summary reads them
+        let info = mailbox.power_info().unwrap();
+        let uart = Clock::ALL
+            .iter()
+            .position(|&clock| clock == Clock::Uart)
+            .unwrap();
+        assert!(info.clocks[uart].is_some_and(|clock| clock.enabled && clock.rate == 30000000));
+        let uart0 = PowerDomain::ALL
+            .iter()
+            .position(|&domain| domain == PowerDomain::Uart0)
+            .unwrap();
+        assert_eq!(info.power_domains[uart0], Some(true));
+        assert!(info.temperature.is_some());
+
+        // Messages are sent to wherever the translator says the VideoCore sees them, and must be below 4GiB
+        unsafe { mailbox.set_translator(|virt| virt + 0x100000000) };
+        assert!(matches!(
+            mailbox.send_tag::<GetArmMemory>(()),
+            Err(DeviceError::BadOperand)
+        ));
+        unsafe { mailbox.set_translator(|virt| virt) };
+
+        // Several requests can be outstanding at once, and complete in any order
+        let mut first = PropertyBuffer::<16>::new();
+        let first_arm = first.add::<GetArmMemory>(()).unwrap();
+        let mut second = PropertyBuffer::<16>::new();
+        let second_rate = second.add::<GetClockRate>(Clock::Uart as u32).unwrap();
+        let mut first_request = mailbox.submit(&mut first).unwrap();
+        let second_request = mailbox.submit(&mut second).unwrap();
+        second_request.wait().unwrap();
+        while first_request.poll().is_none() {}
+        first_request.poll().unwrap().unwrap();
+        drop(first_request);
+        assert!(first.response(&first_arm).unwrap().size != 0);
+        assert_eq!(second.response(&second_rate).unwrap().rate, 30000000);
+
+        // Every slot can be in use at once, after which there is no room for another request until a reply
+        // is read. Nothing timed out along the way, so there is nothing to reclaim either.
+        let mut messages = [const { PropertyBuffer::<16>::new() }; MAX_IN_FLIGHT + 1];
+        let [outstanding @ .., extra] = &mut messages;
+        let requests = outstanding.each_mut().map(|message| {
+            message.add::<GetVcMemory>(()).unwrap();
+            mailbox.submit(message).unwrap()
+        });
+        assert!(matches!(mailbox.submit(extra), Err(DeviceError::Busy)));
+        for request in requests {
+            request.wait().unwrap();
+        }
+        assert_eq!(unsafe { mailbox.reclaim_abandoned() }, 0);
+
+        // Dropping a request before its reply arrives doesn't wait for it. The reply still frees its slot,
+        // and arrives before the reply to anything sent after it.
+        let mut dropped = PropertyBuffer::<16>::new();
+        dropped.add::<GetVcMemory>(()).unwrap();
+        drop(mailbox.submit(&mut dropped).unwrap());
+        mailbox.send_tag::<GetArmMemory>(()).unwrap();
+        assert_eq!(unsafe { mailbox.reclaim_abandoned() }, 0);
+
+        // Tags that don't fit are refused, rather than overflowing the buffer
+        let mut message = PropertyBuffer::<8>::new();
+        message.add::<GetArmMemory>(()).unwrap();
+        assert!(message.add::<GetVcMemory>(()).is_err());
 
         kprintln!("Success!");
     }
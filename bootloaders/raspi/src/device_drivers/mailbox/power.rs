@@ -0,0 +1,267 @@
+//! Clocks, power domains and temperature, which are all managed by the firmware
+//!
+//! Drivers request the clocks and power domains of their devices through here, rather than through the
+//! clock and power management registers, which the firmware expects to own.
+
+use core::fmt::Display;
+
+use common::util::error::DeviceError;
+
+use super::{
+    message::{
+        DeviceState, GetClockRate, GetClockRateMeasured, GetClockState, GetMaxClockRate,
+        GetMaxTemperature, GetMinClockRate, GetPowerState, GetTemperature, PropertyBuffer,
+        SetClockRate, SetClockRateRequest, SetClockState, SetPowerState,
+    },
+    Mailbox,
+};
+
+/// Set in a DeviceState if the clock or power domain is on
+const STATE_ON: u32 = 1 << 0;
+/// Set in a DeviceState response if there is no such clock or power domain
+const STATE_MISSING: u32 = 1 << 1;
+/// Set in a request to change the state of a power domain, to return only once the change has settled
+const STATE_WAIT: u32 = 1 << 1;
+/// The only temperature sensor, on the SoC itself
+const SENSOR_SOC: u32 = 0;
+
+/// The clocks the firmware manages, by their firmware ids
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+    Hevc = 11,
+    Emmc2 = 12,
+    M2mc = 13,
+    PixelBvb = 14,
+}
+
+/// The power domains the firmware manages, by their firmware ids
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerDomain {
+    SdCard = 0,
+    Uart0 = 1,
+    Uart1 = 2,
+    UsbHcd = 3,
+    I2c0 = 4,
+    I2c1 = 5,
+    I2c2 = 6,
+    Spi = 7,
+    Ccp2tx = 8,
+}
+
+impl Clock {
+    pub const ALL: [Clock; 14] = [
+        Clock::Emmc,
+        Clock::Uart,
+        Clock::Arm,
+        Clock::Core,
+        Clock::V3d,
+        Clock::H264,
+        Clock::Isp,
+        Clock::Sdram,
+        Clock::Pixel,
+        Clock::Pwm,
+        Clock::Hevc,
+        Clock::Emmc2,
+        Clock::M2mc,
+        Clock::PixelBvb,
+    ];
+}
+
+impl PowerDomain {
+    pub const ALL: [PowerDomain; 9] = [
+        PowerDomain::SdCard,
+        PowerDomain::Uart0,
+        PowerDomain::Uart1,
+        PowerDomain::UsbHcd,
+        PowerDomain::I2c0,
+        PowerDomain::I2c1,
+        PowerDomain::I2c2,
+        PowerDomain::Spi,
+        PowerDomain::Ccp2tx,
+    ];
+}
+
+/// The SoC's temperature, in thousandths of a degree Celsius
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Millicelsius(pub u32);
+
+impl Display for Millicelsius {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{:03}C", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+/// What the firmware reports about a single clock
+#[derive(Clone, Copy, Debug)]
+pub struct ClockInfo {
+    pub enabled: bool,
+    /// The rate the clock was last set to, in Hz
+    pub rate: u32,
+    pub min_rate: u32,
+    pub max_rate: u32,
+    /// The rate the clock actually runs at, in Hz, if the firmware can measure it
+    pub measured_rate: Option<u32>,
+}
+
+/// The state of every clock and power domain the firmware manages, along with the SoC's temperature
+///
+/// Clocks and power domains the board doesn't have are None, as is anything the firmware didn't answer.
+#[derive(Clone, Copy, Debug)]
+pub struct PowerInfo {
+    /// Indexed like Clock::ALL
+    pub clocks: [Option<ClockInfo>; Clock::ALL.len()],
+    /// Whether each power domain is on, indexed like PowerDomain::ALL
+    pub power_domains: [Option<bool>; PowerDomain::ALL.len()],
+    pub temperature: Option<Millicelsius>,
+    pub max_temperature: Option<Millicelsius>,
+}
+
+impl Mailbox {
+    /// Queries the firmware for everything in PowerInfo, in two requests
+    ///
+    /// Fails only if one of the requests as a whole fails.
+    pub fn power_info(&mut self) -> Result<PowerInfo, DeviceError> {
+        // Every clock takes five tags of five words each
+        let mut message = PropertyBuffer::<384>::new();
+        let clock_tags = Clock::ALL.map(|clock| {
+            let id = clock as u32;
+            (
+                message.add::<GetClockState>(id).unwrap(),
+                message.add::<GetClockRate>(id).unwrap(),
+                message.add::<GetMinClockRate>(id).unwrap(),
+                message.add::<GetMaxClockRate>(id).unwrap(),
+                message.add::<GetClockRateMeasured>(id).unwrap(),
+            )
+        });
+        self.send_property_mail(&mut message)?;
+        let clocks = clock_tags.map(|(state, rate, min_rate, max_rate, measured_rate)| {
+            Some(ClockInfo {
+                enabled: check_state(message.response(&state).ok()?).ok()?,
+                rate: message.response(&rate).ok()?.rate,
+                min_rate: message.response(&min_rate).ok()?.rate,
+                max_rate: message.response(&max_rate).ok()?.rate,
+                measured_rate: message.response(&measured_rate).ok().map(|rate| rate.rate),
+            })
+        });
+
+        // The buffer is sized to fit every tag below
+        let mut message = PropertyBuffer::<64>::new();
+        let domain_tags =
+            PowerDomain::ALL.map(|domain| message.add::<GetPowerState>(domain as u32).unwrap());
+        let temperature = message.add::<GetTemperature>(SENSOR_SOC).unwrap();
+        let max_temperature = message.add::<GetMaxTemperature>(SENSOR_SOC).unwrap();
+        self.send_property_mail(&mut message)?;
+
+        Ok(PowerInfo {
+            clocks,
+            power_domains: domain_tags
+                .map(|state| check_state(message.response(&state).ok()?).ok()),
+            temperature: message
+                .response(&temperature)
+                .ok()
+                .map(|temperature| Millicelsius(temperature.value)),
+            max_temperature: message
+                .response(&max_temperature)
+                .ok()
+                .map(|temperature| Millicelsius(temperature.value)),
+        })
+    }
+
+    /// Returns the rate a clock was last set to, in Hz
+    pub fn clock_rate(&mut self, clock: Clock) -> Result<u32, DeviceError> {
+        Ok(self.send_tag::<GetClockRate>(clock as u32)?.rate)
+    }
+
+    /// Returns the rate a clock actually runs at, in Hz, which can be below the rate it was set to if the
+    /// firmware is throttling it
+    pub fn measured_clock_rate(&mut self, clock: Clock) -> Result<u32, DeviceError> {
+        Ok(self.send_tag::<GetClockRateMeasured>(clock as u32)?.rate)
+    }
+
+    pub fn min_clock_rate(&mut self, clock: Clock) -> Result<u32, DeviceError> {
+        Ok(self.send_tag::<GetMinClockRate>(clock as u32)?.rate)
+    }
+
+    pub fn max_clock_rate(&mut self, clock: Clock) -> Result<u32, DeviceError> {
+        Ok(self.send_tag::<GetMaxClockRate>(clock as u32)?.rate)
+    }
+
+    /// Sets the rate of a clock, returning the rate the firmware actually chose (rates are rounded to what
+    /// the clock can do). Unless skip_turbo is set, setting the ARM clock may also change the turbo
+    /// settings of the other clocks.
+    pub fn set_clock_rate(
+        &mut self,
+        clock: Clock,
+        rate: u32,
+        skip_turbo: bool,
+    ) -> Result<u32, DeviceError> {
+        let response = self.send_tag::<SetClockRate>(SetClockRateRequest {
+            id: clock as u32,
+            rate,
+            skip_turbo: skip_turbo as u32,
+        })?;
+        // The firmware answers with a rate of 0 for clocks that don't exist
+        if response.rate == 0 {
+            return Err(DeviceError::BadOperand);
+        }
+        Ok(response.rate)
+    }
+
+    pub fn clock_enabled(&mut self, clock: Clock) -> Result<bool, DeviceError> {
+        check_state(self.send_tag::<GetClockState>(clock as u32)?)
+    }
+
+    /// Turns a clock on or off, returning whether it is now on
+    pub fn set_clock_enabled(&mut self, clock: Clock, on: bool) -> Result<bool, DeviceError> {
+        check_state(self.send_tag::<SetClockState>(DeviceState {
+            id: clock as u32,
+            state: if on { STATE_ON } else { 0 },
+        })?)
+    }
+
+    pub fn power_domain_on(&mut self, domain: PowerDomain) -> Result<bool, DeviceError> {
+        check_state(self.send_tag::<GetPowerState>(domain as u32)?)
+    }
+
+    /// Turns a power domain on or off, returning whether it is now on. The firmware only returns once the
+    /// domain has settled.
+    pub fn set_power_domain(&mut self, domain: PowerDomain, on: bool) -> Result<bool, DeviceError> {
+        check_state(self.send_tag::<SetPowerState>(DeviceState {
+            id: domain as u32,
+            state: STATE_WAIT | if on { STATE_ON } else { 0 },
+        })?)
+    }
+
+    pub fn temperature(&mut self) -> Result<Millicelsius, DeviceError> {
+        Ok(Millicelsius(
+            self.send_tag::<GetTemperature>(SENSOR_SOC)?.value,
+        ))
+    }
+
+    /// Returns the temperature at which the firmware starts throttling the clocks
+    pub fn max_temperature(&mut self) -> Result<Millicelsius, DeviceError> {
+        Ok(Millicelsius(
+            self.send_tag::<GetMaxTemperature>(SENSOR_SOC)?.value,
+        ))
+    }
+}
+
+fn check_state(response: DeviceState) -> Result<bool, DeviceError> {
+    if response.state & STATE_MISSING != 0 {
+        Err(DeviceError::BadOperand)
+    } else {
+        Ok(response.state & STATE_ON != 0)
+    }
+}
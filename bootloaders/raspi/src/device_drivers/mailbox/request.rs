@@ -0,0 +1,311 @@
+//! Non-blocking property requests
+//!
+//! A request is submitted to the VideoCore and completes once its reply is read from the mailbox, which
+//! happens whenever any request is polled or submitted, and from the ARM mailbox interrupt once
+//! Mailbox::enable_interrupts has been called. Waiting on a request then sleeps until the interrupt (or the
+//! event stream) wakes the core, rather than spinning on the mailbox. Up to MAX_IN_FLIGHT requests can be
+//! outstanding at once, each tracked in a slot until its reply arrives. The slots belong to the mailbox
+//! hardware rather than to a Mailbox, so they are shared by every Mailbox.
+//!
+//! The VideoCore never sees the caller's message. Each slot has a buffer of its own that the message is
+//! copied into, and that the reply is copied back out of. A request that times out leaves its buffer with
+//! the slot, so a late reply can only ever land there.
+//!
+//! Slots are only ever loaded and stored (never read-modify-written), so that they work before the MMU is
+//! on, when exclusive accesses aren't available. Each state change has a single owner: submitting takes a
+//! FREE slot, the reply moves it from IN_FLIGHT to DONE (or from ABANDONED to FREE), and the request hands
+//! it back once it sees DONE or abandons it once it times out (or is dropped). Everything but the interrupt
+//! handler changes slot states with IRQs masked, so the handler never sees a state change half done.
+
+use aarch64_cpu::registers::{Readable as _, CNTFRQ_EL0, CNTPCT_EL0};
+use common::util::error::DeviceError;
+use core::{
+    cell::UnsafeCell,
+    mem::size_of,
+    sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering},
+};
+use tock_registers::{
+    interfaces::{ReadWriteable, Readable, Writeable},
+    registers::InMemoryRegister,
+};
+
+use super::{
+    message::{PropertyBuffer, STATUS_FAILURE},
+    Mailbox, MailboxRegisters, CONFIG, DATA, MAILBOX_TIMEOUT_MS, STATUS,
+};
+use crate::arch_impl::{
+    cache::clean_invalidate_dcache,
+    interrupts::{interrupts_enabled, wait_for_event, without_interrupts},
+};
+
+/// The depth of the mailbox queues. Never having more requests outstanding than this guarantees that there
+/// is always room for their replies.
+pub const MAX_IN_FLIGHT: usize = 8;
+/// The longest message that can be sent, in words
+pub const MAX_MESSAGE_WORDS: usize = 384;
+/// The property channel, the only channel supported
+const CHANNEL_PROPERTY: u32 = 8;
+
+const SLOT_FREE: u8 = 0;
+const SLOT_IN_FLIGHT: u8 = 1;
+const SLOT_DONE: u8 = 2;
+/// The request gave up waiting (or was dropped), but the reply still has to be read before the slot is free
+/// again (see Mailbox::reclaim_abandoned for replies that never come)
+const SLOT_ABANDONED: u8 = 3;
+
+static SLOTS: [Slot; MAX_IN_FLIGHT] = [const { Slot::new() }; MAX_IN_FLIGHT];
+/// Address of the registers of the mailbox that replies are read from by Mailbox::handle_interrupt, or 0
+/// while its interrupt is disabled
+static INTERRUPT_MAILBOX: AtomicUsize = AtomicUsize::new(0);
+
+/// The copy of a message the VideoCore reads and writes. It has cache lines of its own, so the cache
+/// maintenance around sending it never touches anything else.
+#[repr(C, align(64))]
+struct MessageBuffer(UnsafeCell<[u32; MAX_MESSAGE_WORDS]>);
+
+/// Tracks one outstanding request
+struct Slot {
+    state: AtomicU8,
+    /// The value written to the mailbox for the request, which the VideoCore echoes back in its reply
+    mail: AtomicU32,
+    buffer: MessageBuffer,
+}
+
+// Safety: The buffer is only ever touched by whoever owns the slot: the submitter while it is FREE, the
+// VideoCore until the reply arrives, and the request once it is DONE
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(SLOT_FREE),
+            mail: AtomicU32::new(0),
+            buffer: MessageBuffer(UnsafeCell::new([0; MAX_MESSAGE_WORDS])),
+        }
+    }
+
+    fn buffer_addr(&self) -> usize {
+        self.buffer.0.get() as usize
+    }
+}
+
+/// A property message on its way to or back from the VideoCore
+///
+/// The message stays borrowed until the request completes, so the reply can be copied into it. Dropping a
+/// request that hasn't completed abandons it, leaving the message as it was sent, and its slot is freed once
+/// the reply arrives.
+pub struct Request<'a, const N: usize> {
+    mailbox: &'a Mailbox,
+    message: &'a mut PropertyBuffer<N>,
+    slot: usize,
+    deadline: u64,
+    outcome: Outcome,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Outcome {
+    Pending,
+    Done,
+    TimedOut,
+}
+
+impl Mailbox {
+    /// Sends a buffer of property tags to the VideoCore without waiting for the reply
+    ///
+    /// Fails with Busy if MAX_IN_FLIGHT requests are already outstanding, or the mailbox can't take another
+    /// message right now, and with BadOperand if the message is longer than MAX_MESSAGE_WORDS.
+    pub fn submit<'a, const N: usize>(
+        &'a self,
+        message: &'a mut PropertyBuffer<N>,
+    ) -> Result<Request<'a, N>, DeviceError> {
+        let words = message.finish();
+        if words.len() > MAX_MESSAGE_WORDS {
+            return Err(DeviceError::BadOperand);
+        }
+        // Replies to requests that timed out may be all that's keeping the slots busy
+        self.read_replies();
+        let slot = SLOTS
+            .iter()
+            .position(|slot| slot.state.load(Ordering::Acquire) == SLOT_FREE)
+            .ok_or(DeviceError::Busy)?;
+        let buffer_addr = SLOTS[slot].buffer_addr();
+        // buffer_phys MUST be a physical address below 4GiB! It cannot be greater than 32 bits.
+        let buffer_phys = (self.virt_to_phys)(buffer_addr);
+        if buffer_phys >= 0x100000000 {
+            return Err(DeviceError::BadOperand);
+        }
+        if self.mbox_1.status.is_set(STATUS::FULL) {
+            return Err(DeviceError::Busy);
+        }
+
+        // Safety: The slot is FREE, so nothing else is using its buffer
+        unsafe { (*SLOTS[slot].buffer.0.get())[..words.len()].copy_from_slice(words) };
+        // Write mailbox address upper 28 bits into mbox_1 read register
+        let data: InMemoryRegister<u32, DATA::Register> = InMemoryRegister::new(0);
+        data.modify(DATA::ADDR.val(buffer_phys as u32 >> 4));
+        data.modify(DATA::CHANNEL.val(CHANNEL_PROPERTY));
+        // The slot has to be ready before the reply can arrive
+        SLOTS[slot].mail.store(data.get(), Ordering::Relaxed);
+        SLOTS[slot].state.store(SLOT_IN_FLIGHT, Ordering::Release);
+
+        // Make sure the VideoCore reads the message as we wrote it, then send it
+        clean_invalidate_dcache(buffer_addr, size_of::<MessageBuffer>());
+        self.mbox_1.data.set(data.get());
+
+        Ok(Request {
+            mailbox: self,
+            message,
+            slot,
+            deadline: CNTPCT_EL0.get() + CNTFRQ_EL0.get() * MAILBOX_TIMEOUT_MS / 1000,
+            outcome: Outcome::Pending,
+        })
+    }
+
+    /// Frees the slots of every request that timed out and is still waiting on its reply, returning how
+    /// many were freed
+    ///
+    /// A request that times out keeps its slot until the reply arrives, so once MAX_IN_FLIGHT replies have
+    /// been lost, submit fails with Busy until they are reclaimed.
+    ///
+    /// # Safety
+    /// The VideoCore must never answer any of those requests, as their buffers are reused for new ones (and
+    /// a late reply would be taken for the reply to whichever request is using the slot by then)
+    pub unsafe fn reclaim_abandoned(&self) -> usize {
+        self.read_replies();
+        let mut reclaimed = 0;
+        for slot in &SLOTS {
+            if slot.state.load(Ordering::Acquire) == SLOT_ABANDONED {
+                slot.state.store(SLOT_FREE, Ordering::Release);
+                reclaimed += 1;
+            }
+        }
+
+        reclaimed
+    }
+
+    /// Has the mailbox raise its interrupt whenever a reply is waiting, so that handle_interrupt completes
+    /// requests as soon as their replies arrive
+    ///
+    /// The interrupt also has to be enabled at the interrupt controller, with handle_interrupt as its
+    /// handler.
+    pub fn enable_interrupts(&self) {
+        INTERRUPT_MAILBOX.store(
+            &*self.mbox_0 as *const MailboxRegisters as usize,
+            Ordering::Release,
+        );
+        self.mbox_0.config.modify(CONFIG::DATA_IRQ::SET);
+    }
+
+    /// Stops the mailbox from raising its interrupt, after which requests only complete when polled
+    pub fn disable_interrupts(&self) {
+        self.mbox_0.config.modify(CONFIG::DATA_IRQ::CLEAR);
+        INTERRUPT_MAILBOX.store(0, Ordering::Release);
+    }
+
+    /// The handler of the ARM mailbox interrupt, which reads every reply waiting in the mailbox
+    pub fn handle_interrupt() {
+        let registers = INTERRUPT_MAILBOX.load(Ordering::Acquire);
+        if registers != 0 {
+            // Safety: enable_interrupts stored the address of a mailbox's registers, which stay mapped until
+            // disable_interrupts is called
+            read_replies(unsafe { &*(registers as *const MailboxRegisters) });
+        }
+    }
+
+    /// Reads every reply waiting in the mailbox, completing the requests they belong to
+    fn read_replies(&self) {
+        without_interrupts(|| read_replies(&self.mbox_0));
+    }
+}
+
+/// Reads every reply waiting in mailbox, completing the requests they belong to. Must not be interrupted by
+/// anything else that changes the state of a slot.
+fn read_replies(mailbox: &MailboxRegisters) {
+    while !mailbox.status.is_set(STATUS::EMPTY) {
+        let mail = mailbox.data.get();
+        let slot = SLOTS.iter().find(|slot| {
+            let state = slot.state.load(Ordering::Acquire);
+            (state == SLOT_IN_FLIGHT || state == SLOT_ABANDONED)
+                && slot.mail.load(Ordering::Relaxed) == mail
+        });
+        // Anything else isn't a reply to one of our requests
+        if let Some(slot) = slot {
+            let state = match slot.state.load(Ordering::Acquire) {
+                SLOT_IN_FLIGHT => SLOT_DONE,
+                _ => SLOT_FREE,
+            };
+            slot.state.store(state, Ordering::Release);
+        }
+    }
+}
+
+impl<'a, const N: usize> Request<'a, N> {
+    /// Checks whether the reply has arrived, returning the outcome of the request once it has
+    ///
+    /// Gives up once the request has been outstanding for MAILBOX_TIMEOUT_MS, leaving the message as it was
+    /// sent.
+    pub fn poll(&mut self) -> Option<Result<(), DeviceError>> {
+        if self.outcome == Outcome::Pending {
+            without_interrupts(|| {
+                read_replies(&self.mailbox.mbox_0);
+                let slot = &SLOTS[self.slot];
+                if slot.state.load(Ordering::Acquire) == SLOT_DONE {
+                    // Drop anything the core speculatively cached while the VideoCore was writing the reply
+                    clean_invalidate_dcache(slot.buffer_addr(), size_of::<MessageBuffer>());
+                    let words = self.message.finish();
+                    // Safety: The slot is DONE, so the VideoCore is finished with the buffer and it is ours
+                    // until the slot is FREE again
+                    words.copy_from_slice(unsafe { &(*slot.buffer.0.get())[..words.len()] });
+                    slot.state.store(SLOT_FREE, Ordering::Release);
+                    self.outcome = Outcome::Done;
+                } else if CNTPCT_EL0.get() >= self.deadline {
+                    slot.state.store(SLOT_ABANDONED, Ordering::Release);
+                    self.outcome = Outcome::TimedOut;
+                }
+            });
+        }
+
+        match self.outcome {
+            Outcome::Pending => None,
+            Outcome::TimedOut => Some(Err(DeviceError::Timeout)),
+            Outcome::Done if self.message.status() == STATUS_FAILURE => {
+                Some(Err(DeviceError::Other))
+            }
+            Outcome::Done => Some(Ok(())),
+        }
+    }
+
+    /// Blocks until the reply arrives (or the request times out)
+    ///
+    /// While the mailbox interrupt is enabled, the core sleeps until it (or the event stream) wakes it up.
+    pub fn wait(mut self) -> Result<(), DeviceError> {
+        loop {
+            if let Some(result) = self.poll() {
+                return result;
+            }
+            if interrupts_enabled() && INTERRUPT_MAILBOX.load(Ordering::Relaxed) != 0 {
+                wait_for_event();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl<'a, const N: usize> Drop for Request<'a, N> {
+    fn drop(&mut self) {
+        if self.outcome != Outcome::Pending {
+            return;
+        }
+        // Hand the slot back if the reply is already in, and otherwise leave it for the reply to free
+        without_interrupts(|| {
+            read_replies(&self.mailbox.mbox_0);
+            let slot = &SLOTS[self.slot];
+            let state = match slot.state.load(Ordering::Acquire) {
+                SLOT_DONE => SLOT_FREE,
+                _ => SLOT_ABANDONED,
+            };
+            slot.state.store(state, Ordering::Release);
+        });
+    }
+}
@@ -1,13 +1,99 @@
+use common::device_drivers::registry::Driver;
+
+use self::{
+    gpio::{Gpio, GPIO_MMIO_OFFSET},
+    interrupt_controller::{
+        Intc, ARMCTRL_MMIO_OFFSET, GIC_CPU_INTERFACE_ADDR, GIC_DISTRIBUTOR_ADDR,
+    },
+    mailbox::{Mailbox, MAILBOX_MMIO_OFFSET},
+    uart0::{init_uart0, Pl011, PL011_MMIO_OFFSET},
+};
+use crate::board::{Board, InterruptController};
+
 pub mod gpio;
+pub mod interrupt_controller;
 pub mod mailbox;
 pub mod uart0;
 
-#[cfg(feature = "raspi3")]
-pub const MMIO_BASE: usize = 0x3F000000;
-#[cfg(feature = "raspi4")]
-// "a peripheral described in this document ... [is] visible to the ARM at 0x0_FEnn_nnnn if Low Peripheral
-// mode is enabled."
-// - BCM2711 ARM PERIPHERALS
-pub const MMIO_BASE: usize = 0xFE000000;
-#[cfg(not(any(feature = "raspi3", feature = "raspi4")))]
-compile_error!("One of raspi3 or raspi4 must be enabled!.");
+/// Drivers for the devices the bootloader needs before virtual memory is enabled, in the order they must be
+/// probed in. UART0 can only be brought up once both the GPIO pins and the mailbox are available.
+pub const EARLY_DRIVERS: [Driver<EarlyDevices>; 5] = [
+    interrupt_controller::ARMCTRL_DRIVER,
+    interrupt_controller::GIC_DRIVER,
+    gpio::DRIVER,
+    mailbox::DRIVER,
+    uart0::DRIVER,
+];
+/// Upper bound on the number of devices bound by EARLY_DRIVERS
+pub const MAX_EARLY_DEVICES: usize = 8;
+
+/// The devices the bootloader drives before virtual memory is enabled, filled in by EARLY_DRIVERS
+pub struct EarlyDevices {
+    pub board: Board,
+    pub intc: Option<Intc>,
+    pub gpio: Option<Gpio>,
+    pub mailbox: Option<Mailbox>,
+    pub uart: Option<Pl011>,
+}
+
+impl EarlyDevices {
+    pub fn new(board: Board) -> Self {
+        Self {
+            board,
+            intc: None,
+            gpio: None,
+            mailbox: None,
+            uart: None,
+        }
+    }
+
+    /// Brings up every device the device tree did not provide at its usual address on our board
+    pub fn bind_fallbacks(&mut self) {
+        let board = self.board;
+        let addresses = PeripheralAddresses::fallback(board);
+        // Safety: The fallback addresses are correct for the board we are running on
+        let gpio = self
+            .gpio
+            .get_or_insert_with(|| unsafe { Gpio::new(addresses.gpio, board) });
+        if self.intc.is_none() {
+            self.intc = Some(match board.interrupt_controller() {
+                InterruptController::Bcm2836 => unsafe { Intc::new_armctrl(addresses.armctrl) },
+                InterruptController::Gic400 => unsafe {
+                    Intc::new_gic(GIC_DISTRIBUTOR_ADDR, GIC_CPU_INTERFACE_ADDR)
+                },
+            });
+        }
+        let mailbox = self
+            .mailbox
+            .get_or_insert_with(|| unsafe { Mailbox::new(addresses.mailbox) });
+        if mailbox.interrupt().is_none() {
+            mailbox.set_interrupt(board.mailbox_interrupt());
+        }
+        if self.uart.is_none() {
+            // Safety: As above, and the clock rate of UART0 is set up before it is used
+            self.uart = Some(unsafe { init_uart0(addresses.uart, gpio, mailbox) });
+        }
+    }
+}
+
+/// CPU physical base addresses of the peripherals the bootloader drives
+#[derive(Clone, Copy)]
+pub struct PeripheralAddresses {
+    pub gpio: usize,
+    pub uart: usize,
+    pub mailbox: usize,
+    pub armctrl: usize,
+}
+
+impl PeripheralAddresses {
+    /// The usual addresses of the peripherals on board, for use when the device tree doesn't tell us
+    pub fn fallback(board: Board) -> Self {
+        let mmio_base = board.mmio_base();
+        Self {
+            gpio: mmio_base + GPIO_MMIO_OFFSET,
+            uart: mmio_base + PL011_MMIO_OFFSET,
+            mailbox: mmio_base + MAILBOX_MMIO_OFFSET,
+            armctrl: mmio_base + ARMCTRL_MMIO_OFFSET,
+        }
+    }
+}
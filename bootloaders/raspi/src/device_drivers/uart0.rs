@@ -1,7 +1,11 @@
+use aarch64_cpu::registers::{Readable as _, CNTFRQ_EL0, CNTPCT_EL0};
 use core::fmt::Write;
 
 use common::{
-    device_drivers::character_device::CharacterDevice,
+    device_drivers::{
+        character_device::CharacterDevice,
+        registry::{DeviceResources, Driver},
+    },
     util::{error::DeviceError, register_ref::RegisterRef},
 };
 use tock_registers::{
@@ -12,9 +16,139 @@ use tock_registers::{
 
 use self::DR::DATA;
 
-use super::{gpio::Gpio, MMIO_BASE};
+use super::{
+    gpio::Gpio,
+    mailbox::{
+        power::{Clock, PowerDomain},
+        Mailbox,
+    },
+    EarlyDevices,
+};
+
+/// The rate init_uart0 sets the UART clock to
+const UART_CLOCK_RATE: u32 = 30000000;
+/// Offset of UART0 from the start of the peripheral window
+pub const PL011_MMIO_OFFSET: usize = 0x201000;
+pub const PL011_COMPATIBLE: &[&str] = &["arm,pl011"];
+/// How long to wait for the UART to send what is left in its transmit FIFO before changing its settings, in
+/// milliseconds. This covers a full FIFO at 1200 baud. Anything slower (or held back by CTS) is cut short.
+const UART_DRAIN_TIMEOUT_MS: u64 = 500;
+
+pub const DRIVER: Driver<EarlyDevices> = Driver {
+    name: "pl011",
+    compatible: PL011_COMPATIBLE,
+    probe,
+};
+
+fn probe(resources: &DeviceResources, devices: &mut EarlyDevices) -> Result<(), DeviceError> {
+    // Only UART0 is supported, which the Raspberry Pi device trees name with the uart0 alias (serial0 is
+    // whichever UART is routed to the GPIO header, usually the mini UART). The raspi4's other PL011s are
+    // passed on.
+    if !resources.is_node("uart0") {
+        return Err(DeviceError::Unsupported);
+    }
+    if devices.uart.is_some() {
+        return Err(DeviceError::Busy);
+    }
+    let (base, _) = resources.regs().next().ok_or(DeviceError::BadOperand)?;
+    let (Some(gpio), Some(mailbox)) = (devices.gpio.as_mut(), devices.mailbox.as_mut()) else {
+        return Err(DeviceError::MissingDependency);
+    };
+    // Safety: The address comes from a device tree node that is compatible with this driver
+    devices.uart = Some(unsafe { init_uart0(base, gpio, mailbox) });
+    Ok(())
+}
+
+/// Sets the UART clock to a rate that can be divided down to every common baud rate, then brings up UART0
+/// with the default configuration
+///
+/// # Safety
+/// start_addr must point to the registers of the PL011 UART0 in MMIO
+pub unsafe fn init_uart0(start_addr: usize, gpio: &mut Gpio, mailbox: &mut Mailbox) -> Pl011 {
+    // The firmware already leaves UART0 powered and clocked, and not every version answers these, so only
+    // setting the rate has to succeed
+    let _ = mailbox.set_power_domain(PowerDomain::Uart0, true);
+    let _ = mailbox.set_clock_enabled(Clock::Uart, true);
+    mailbox
+        .set_clock_rate(Clock::Uart, UART_CLOCK_RATE, false)
+        .unwrap();
+    // The firmware may have rounded the rate, so the divisors are worked out from what it actually chose
+    let clock_rate = mailbox.clock_rate(Clock::Uart).unwrap();
+    Pl011::new(start_addr, gpio, clock_rate, UartConfig::default()).unwrap()
+}
+
+/// Number of data bits in each character
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlowControl {
+    None,
+    /// Hardware flow control through the CTS and RTS lines, on GPIO pins 16 and 17
+    RtsCts,
+}
+
+/// The line settings of the UART
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UartConfig {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl Default for UartConfig {
+    /// 115200 baud 8N1, without flow control, as the firmware and most terminals expect
+    fn default() -> Self {
+        Self {
+            baud_rate: 115200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
 
-pub static PL011_PHYS_BASE: usize = MMIO_BASE + 0x201000;
+impl UartConfig {
+    /// Returns the integer and fractional (in 64ths) parts of the baud rate divisor for a UART clock rate
+    ///
+    /// The divisor is clock_rate / (16 * baud_rate), rounded to the nearest 64th. Fails if the baud rate
+    /// can't be reached from the clock rate.
+    pub fn divisors(&self, clock_rate: u32) -> Result<(u32, u32), DeviceError> {
+        if self.baud_rate == 0 {
+            return Err(DeviceError::BadOperand);
+        }
+        // In 64ths: clock_rate * 64 / (16 * baud_rate)
+        let baud_rate = self.baud_rate as u64;
+        let divisor = (clock_rate as u64 * 4 + baud_rate / 2) / baud_rate;
+        let (integer, fraction) = (divisor >> 6, divisor & 0x3F);
+        // The integer part is 16 bits, and can't be 0 (or have a fraction when it's at its maximum)
+        if integer == 0 || integer > 0xFFFF || (integer == 0xFFFF && fraction != 0) {
+            return Err(DeviceError::BadOperand);
+        }
+        Ok((integer as u32, fraction as u32))
+    }
+}
 
 register_structs!(
    pub UartRegisters {
@@ -77,6 +211,8 @@ register_bitfields!(
       LBE OFFSET(7),
       TXE OFFSET(8),
       RXE OFFSET(9),
+      RTSEN OFFSET(14),
+      CTSEN OFFSET(15),
    ]
 );
 
@@ -85,14 +221,21 @@ register_bitfields!(
 /// Note that this device driver only works for UART0 on the raspi4, and not any of the other UARTs.
 pub struct Pl011 {
     registers: RegisterRef<UartRegisters>,
+    clock_rate: u32,
+    config: UartConfig,
 }
 
 impl Pl011 {
-    /// Creates a new representation of the Pl011 UART0 device.
+    /// Creates a new representation of the Pl011 UART0 device, and configures it for the given line settings
     ///
-    /// Note: Currently the device driver assumes a UART clock rate of 3 Mhz, so this must be set
-    /// prior to calling this method
-    pub unsafe fn new(start_addr: usize, gpio: &mut Gpio) -> Self {
+    /// clock_rate must be the rate the UART clock currently runs at, in Hz. Fails if the configuration isn't
+    /// possible at that rate.
+    pub unsafe fn new(
+        start_addr: usize,
+        gpio: &mut Gpio,
+        clock_rate: u32,
+        config: UartConfig,
+    ) -> Result<Self, DeviceError> {
         let registers: RegisterRef<UartRegisters> = RegisterRef::new(start_addr);
 
         // Disable the UART
@@ -101,20 +244,94 @@ impl Pl011 {
         // Pins 14 and 15 should be in neither UP now DOWN pull state when using UART0
         gpio.configure_uart0_pull();
 
-        // TODO: Need to set baud rate before this works on real hardware
-        // Baud rate calculations from https://wiki.osdev.org/Raspberry_Pi_Bare_Bones
-        registers.ibrd.modify(IBRD::DIV.val(1));
-        registers.fbrd.modify(FBRD::DIV.val(40));
+        let mut uart = Self {
+            registers,
+            clock_rate,
+            config,
+        };
+        uart.reconfigure(gpio, config)?;
+        Ok(uart)
+    }
 
-        // Enable FIFO queue and 8-bit words
-        registers.lcrh.modify(LCRH::FEN::SET);
-        registers.lcrh.modify(LCRH::WLEN::EIGHT);
+    pub fn config(&self) -> UartConfig {
+        self.config
+    }
+
+    /// Changes the line settings of the UART. Anything still waiting to be sent is sent with the old
+    /// settings first.
+    ///
+    /// The UART is left as it was if the configuration isn't possible at the current clock rate.
+    pub fn reconfigure(&mut self, gpio: &mut Gpio, config: UartConfig) -> Result<(), DeviceError> {
+        let divisors = config.divisors(self.clock_rate)?;
+        // Pins 16 and 17 may well be used for something else, so they are only touched to route the flow
+        // control lines to them, and to give them back once flow control is turned off
+        let rts_cts = config.flow_control == FlowControl::RtsCts;
+        if rts_cts || self.config.flow_control == FlowControl::RtsCts {
+            gpio.configure_uart0_flow_control(rts_cts);
+        }
+        self.config = config;
+        self.program(divisors);
+        Ok(())
+    }
+
+    /// Tells the driver that the UART clock now runs at a different rate, so that the baud rate divisors can
+    /// be worked out again. This must be called after every change to the rate of the UART clock.
+    ///
+    /// Fails (and leaves the UART disabled) if the current configuration isn't possible at the new rate.
+    pub fn set_clock_rate(&mut self, clock_rate: u32) -> Result<(), DeviceError> {
+        self.clock_rate = clock_rate;
+        match self.config.divisors(clock_rate) {
+            Ok(divisors) => {
+                self.program(divisors);
+                Ok(())
+            }
+            Err(err) => {
+                self.registers.cr.set(0);
+                Err(err)
+            }
+        }
+    }
+
+    /// Writes the current configuration to the UART's registers, following the sequence in the PL011
+    /// technical reference manual
+    fn program(&mut self, (integer, fraction): (u32, u32)) {
+        // Let the UART finish what it is doing, then disable it and flush the transmit FIFO
+        let deadline = CNTPCT_EL0.get() + CNTFRQ_EL0.get() * UART_DRAIN_TIMEOUT_MS / 1000;
+        while self.registers.fr.is_set(FR::BUSY) && CNTPCT_EL0.get() < deadline {}
+        self.registers.cr.set(0);
+        self.registers.lcrh.modify(LCRH::FEN::CLEAR);
+
+        self.registers.ibrd.write(IBRD::DIV.val(integer));
+        self.registers.fbrd.write(FBRD::DIV.val(fraction));
+
+        // The divisors only take effect once LCRH is written, which has to come after them
+        let data_bits = match self.config.data_bits {
+            DataBits::Five => LCRH::WLEN::FIVE,
+            DataBits::Six => LCRH::WLEN::SIX,
+            DataBits::Seven => LCRH::WLEN::SEVEN,
+            DataBits::Eight => LCRH::WLEN::EIGHT,
+        };
+        let parity = match self.config.parity {
+            Parity::None => LCRH::PEN::CLEAR,
+            Parity::Odd => LCRH::PEN::SET + LCRH::EPS::CLEAR,
+            Parity::Even => LCRH::PEN::SET + LCRH::EPS::SET,
+        };
+        let stop_bits = match self.config.stop_bits {
+            StopBits::One => LCRH::STP2::CLEAR,
+            StopBits::Two => LCRH::STP2::SET,
+        };
+        self.registers
+            .lcrh
+            .write(LCRH::FEN::SET + data_bits + parity + stop_bits);
 
         // Re-enable the UART
-        registers.cr.modify(CR::UARTEN::SET);
-        registers.cr.modify(CR::TXE::SET);
-        registers.cr.modify(CR::RXE::SET);
-        Self { registers }
+        let flow_control = match self.config.flow_control {
+            FlowControl::None => CR::RTSEN::CLEAR + CR::CTSEN::CLEAR,
+            FlowControl::RtsCts => CR::RTSEN::SET + CR::CTSEN::SET,
+        };
+        self.registers
+            .cr
+            .write(CR::UARTEN::SET + CR::TXE::SET + CR::RXE::SET + flow_control);
     }
 
     pub fn write_byte(&mut self, byte: u8) {
@@ -171,3 +388,29 @@ impl Write for Pl011 {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::UartConfig;
+    use kernel::{kprint, kprintln};
+
+    #[test_case]
+    fn baud_rate_divisor_tests() {
+        kprint!("Testing PL011 baud rate divisors...");
+
+        let config = |baud_rate| UartConfig {
+            baud_rate,
+            ..UartConfig::default()
+        };
+        // 30MHz / (16 * 115200) = 16.276
+        assert_eq!(config(115200).divisors(30000000).unwrap(), (16, 18));
+        // The divisors the driver used to hard-code, for a 3MHz clock
+        assert_eq!(config(115200).divisors(3000000).unwrap(), (1, 40));
+        assert_eq!(config(921600).divisors(48000000).unwrap(), (3, 16));
+        // Baud rates above a sixteenth of the clock rate (or of 0) can't be reached
+        assert!(config(3000000).divisors(30000000).is_err());
+        assert!(config(0).divisors(30000000).is_err());
+
+        kprintln!("Success!");
+    }
+}
@@ -1,87 +1,149 @@
-use fdt_rs::{
-    base::DevTree,
-    error::DevTreeError,
-    prelude::{FallibleIterator, PropReader},
+use common::{
+    device_tree::{fdt::Fdt, fdt_mut::FdtMut, DeviceTree},
+    util::error::FdtError,
 };
 
-pub struct RaspiDeviceTree<'a> {
-    address_cells: u32,
-    size_cells: u32,
-    dt: DevTree<'a>,
+/// Why merging overlays into the firmware's device tree failed
+#[derive(Debug)]
+pub struct OverlayError {
+    /// Index of the overlay that failed to apply, or None if the device tree itself couldn't be copied
+    pub overlay: Option<usize>,
+    pub error: FdtError,
+}
+
+/// Copies the device tree at dtb_ptr into buf, and merges overlays into the copy in order. Returns the
+/// merged blob.
+///
+/// # Safety
+/// dtb_ptr must point to a device tree blob, as required by Fdt::from_ptr
+pub unsafe fn merge_overlays<'a>(
+    dtb_ptr: *const u8,
+    buf: &'a mut [u8],
+    overlays: &[&[u8]],
+) -> Result<&'a [u8], OverlayError> {
+    let copy_error = |error| OverlayError {
+        overlay: None,
+        error,
+    };
+    let firmware = Fdt::from_ptr(dtb_ptr).map_err(copy_error)?;
+    let mut fdt = FdtMut::new(buf, firmware.as_bytes()).map_err(copy_error)?;
+    for (idx, overlay) in overlays.iter().enumerate() {
+        Fdt::new(overlay)
+            .and_then(|overlay| fdt.apply_overlay(overlay))
+            .map_err(|error| OverlayError {
+                overlay: Some(idx),
+                error,
+            })?;
+    }
+
+    Ok(fdt.into_bytes())
 }
 
-impl RaspiDeviceTree<'_> {
-    pub fn new(dtb_ptr: *const u8) -> Result<Self, DevTreeError> {
-        // Safety: The library will verify that the correct magic number is present. This memory is part
-        // of the first physical page which the OS will treat as read only, so the memory is essentially
-        // of a static lifetime.
-        let dt = unsafe { DevTree::from_raw_pointer(dtb_ptr)? };
+pub struct RaspiDeviceTree<'a> {
+    dt: DeviceTree<'a>,
+}
 
-        // Address and size values are stored as a series of u32 values, we need to know their length for
-        // this devicetree.
-        let root = dt.root()?.ok_or(DevTreeError::ParseError)?;
-        let address_cells = root
-            .props()
-            .find(|x| Ok(x.name()? == "#address-cells"))?
-            .ok_or(DevTreeError::ParseError)?
-            .u32(0)?;
-        let size_cells = root
-            .props()
-            .find(|x| Ok(x.name()? == "#size-cells"))?
-            .ok_or(DevTreeError::ParseError)?
-            .u32(0)?;
+impl<'a> RaspiDeviceTree<'a> {
+    pub fn new(dtb_ptr: *const u8) -> Result<Self, FdtError> {
+        // Safety: The parser verifies that the correct magic number is present, and never reads past the
+        // size the header claims. The blob is marked in the memory map, so nothing will overwrite it.
+        let fdt = unsafe { Fdt::from_ptr(dtb_ptr)? };
 
         Ok(Self {
-            address_cells,
-            size_cells,
-            dt,
+            dt: DeviceTree::new(fdt),
         })
     }
 
+    /// Provides access to the generic device tree queries
+    pub fn device_tree(&self) -> &DeviceTree<'a> {
+        &self.dt
+    }
+
+    /// Size in bytes of the entire device tree blob, as reported by its header
+    pub fn total_size(&self) -> usize {
+        self.dt.fdt().total_size()
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.dt.fdt().as_bytes()
+    }
+
+    /// Returns the kernel command line the firmware placed in /chosen, if there is one
+    pub fn bootargs(&self) -> Option<&str> {
+        self.dt.find_node("/chosen")?.property("bootargs")?.as_str()
+    }
+
+    /// Returns the path (or alias) of the device the firmware wants used as the console, along with any
+    /// options after a colon (eg "serial0:115200n8")
+    pub fn stdout_path(&self) -> Option<&str> {
+        let chosen = self.dt.find_node("/chosen")?;
+        chosen
+            .property("stdout-path")
+            .or_else(|| chosen.property("linux,stdout-path"))?
+            .as_str()
+    }
+
+    /// Iterates over every region of memory the device tree reserves, passing the base address and size in
+    /// bytes to the provided closure. This covers both the memory reservation block in the DTB header and
+    /// the statically placed children of /reserved-memory.
+    pub fn for_each_reserved<F: FnMut(u64, u64)>(&self, mut closure: F) {
+        for (address, size) in self.dt.fdt().reserved_entries() {
+            closure(address, size);
+        }
+
+        let Some(reserved_memory) = self.dt.find_node("/reserved-memory") else {
+            return;
+        };
+        // Children use the cell sizes of /reserved-memory itself, which need not match the root's
+        let address_cells = reserved_memory.address_cells();
+        let size_cells = reserved_memory.size_cells();
+        for region in reserved_memory.children().filter(|node| node.is_enabled()) {
+            // Regions without a reg property (such as the CMA pool) have no fixed location, and are instead
+            // carved out of free memory by whoever ends up using them
+            if let Some(reg) = region.property("reg") {
+                for (address, size) in reg.reg_entries(address_cells, size_cells) {
+                    closure(address, size);
+                }
+            }
+        }
+    }
+
+    /// Iterates over the peripheral windows the SoC exposes to the CPU, passing the CPU physical base
+    /// address and size in bytes of each one to the provided closure.
+    ///
+    /// These come from the ranges property of /soc, which maps the VideoCore bus addresses that device
+    /// nodes use onto the CPU's physical address space. It covers both the main peripherals and the ARM
+    /// local peripherals.
+    pub fn for_each_mmio<F: FnMut(u64, u64)>(&self, mut closure: F) {
+        let Some(root) = self.dt.root() else {
+            return;
+        };
+        let Some(soc) = root.child("soc") else {
+            return;
+        };
+        let Some(ranges) = soc.property("ranges") else {
+            return;
+        };
+        for (_, cpu_address, size) in
+            ranges.range_entries(soc.address_cells(), root.address_cells(), soc.size_cells())
+        {
+            closure(cpu_address, size);
+        }
+    }
+
     /// Iterates over the device tree, parsing each memory region and passing the base address and size in bytes
     /// to the provided closure.
     pub fn for_each_memory<F: FnMut(u64, u64)>(&self, mut closure: F) {
-        let mut node_iter = self.dt.nodes();
-        // Find the next memory node in the tree
-        while let Ok(Some(node)) = node_iter.find(|x| Ok(x.name()?.starts_with("memory@"))) {
-            // Recover its properties
-            if let Ok(Some(regs)) = node.props().find(|x| Ok(x.name()? == "reg")) {
-                let entry_size_cells = self.address_cells + self.size_cells;
-                let entry_size_bytes = (entry_size_cells * 4) as usize;
-                // There may be more than one address/size memory region in a single node
-                for node_entry in 0..regs.raw().len() / entry_size_bytes {
-                    // This monstrosity is the best way I could think to parse each address/size, considering
-                    // we don't know the cell size of these properties until runtime...
-                    let address = match self.address_cells {
-                        1 => regs
-                            .u32(node_entry * entry_size_cells as usize)
-                            .ok()
-                            .map(|x| x as u64),
-                        2 => regs.u64(node_entry * entry_size_cells as usize).ok(),
-                        _ => None,
-                    };
-                    let size = match self.size_cells {
-                        1 => regs
-                            .u32(
-                                (node_entry * entry_size_cells as usize)
-                                    + self.address_cells as usize,
-                            )
-                            .ok()
-                            .map(|x| x as u64),
-                        2 => regs
-                            .u64(
-                                (node_entry * (entry_size_cells / 2) as usize)
-                                    + self.address_cells as usize,
-                            )
-                            .ok(),
-                        _ => None,
-                    };
-
-                    // Pass the data to the caller if we were able to successfully parse the memory region
-                    if address.is_some() && size.is_some() {
-                        closure(address.unwrap(), size.unwrap());
-                    }
-                }
+        let Some(root) = self.dt.root() else {
+            return;
+        };
+        // There may be more than one memory node, and more than one address/size region in a single node
+        for node in root
+            .children()
+            .filter(|node| node.base_name() == "memory" && node.is_enabled())
+        {
+            for (address, size) in self.dt.reg(&node) {
+                closure(address, size);
             }
         }
     }
@@ -6,47 +6,87 @@
 
 use common::{
     allocators::{
-        page_frame_allocator::{
-            bump::{BumpPFA, SingleThreadedBumpPFA},
-            FrameAllocator,
-        },
+        page_frame_allocator::bump::{BumpPFA, SingleThreadedBumpPFA},
+        static_box::StaticBox,
         static_bump::StaticBumpAlloc,
+        static_frame::FrameStaticAlloc,
+        static_vec::StaticVec,
     },
+    arch::aarch64::page_table::PageTable,
+    boot_info::BootInfo,
+    command_line::{BootParams, CommandLine},
     concurrency::single_threaded_lock::SingleThreadedLock,
+    device_drivers::registry::DriverRegistry,
+    device_tree::{
+        cpus::{spin_table_region, Cpu},
+        fdt::Fdt,
+    },
     memory::{
         address_space::MemoryAttributes,
         memory_map::{MemoryMap, MemoryMapEntry, MemoryMapType},
         memory_size::MemorySize,
     },
     read_linker_var,
-    util::linker_variables::{__KERNEL_VIRT_START, __PG_SIZE},
+    util::{
+        error::DeviceError,
+        linker_variables::{__KERNEL_VIRT_START, __PG_SIZE},
+    },
 };
-use core::arch::global_asm;
-use device_drivers::{
-    gpio::{Gpio, GPIO_PHYS_BASE},
-    uart0::{Pl011, PL011_PHYS_BASE},
+use core::{
+    arch::global_asm,
+    ptr::{addr_of_mut, copy_nonoverlapping},
 };
 
 use crate::{
-    device_drivers::mailbox::{
-        message::{SetClockRate, CLOCK_UART},
-        Mailbox, MAILBOX_PHYS_BASE,
+    arch_impl::interrupts::{install_vectors, mask_interrupts, unmask_interrupts},
+    board::Board,
+    device_drivers::{
+        interrupt_controller::{Intc, INTC},
+        mailbox::{
+            message::{GetArmMemory, GetVcMemory, PropertyBuffer},
+            power::{Clock, PowerDomain},
+            Mailbox,
+        },
+        EarlyDevices, EARLY_DRIVERS, MAX_EARLY_DEVICES,
     },
-    device_tree::RaspiDeviceTree,
-    paging::{mmu::enable_mmu, page_table::PageTable},
+    device_tree::{merge_overlays, RaspiDeviceTree},
+    paging::mmu::enable_mmu,
 };
 
 mod arch_impl;
+mod board;
 mod device_drivers;
 mod device_tree;
 pub mod paging;
 #[cfg(test)]
 mod test;
 mod util;
-mod writer_mutexes;
+
+/// Upper bound on the number of non-free regions the bootloader adds to the memory map, on top of the ones
+/// described by the device tree. Each one can split an existing entry in two, so the map needs room for two
+/// entries per reservation.
+const MAX_MEMORY_MAP_RESERVATIONS: usize = 16;
+/// Size of the memory the memory map is built in before any frame allocator exists
+const EARLY_MEMORY_MAP_STORAGE_SIZE: usize = 0x2000;
+/// Size of the buffer the firmware's device tree is merged with OVERLAYS in
+const DTB_STORAGE_SIZE: usize = 0x20000;
+/// Room left after the device tree handed to the kernel, for edits of its own
+const KERNEL_DTB_SLACK: usize = 0x1000;
+/// Device tree overlays to merge into the firmware's device tree, embedded from overlays/ by build.rs
+const OVERLAYS: &[&[u8]] = include!(concat!(env!("OUT_DIR"), "/overlays.rs"));
+/// Size of each of the ranges of physical memory handed to the bootloader's frame allocators
+const BOOTLOADER_FRAME_POOL_SIZE: usize = 0x500000;
+/// Amount of physical memory mapped into the kernel's linear map. Regardless of how much RAM the raspi
+/// actually has (max 8GiB), this covers all of it.
+const LINEAR_MAP_SIZE: usize = 0x200000000;
+
+static mut EARLY_MEMORY_MAP_STORAGE: [u8; EARLY_MEMORY_MAP_STORAGE_SIZE] =
+    [0; EARLY_MEMORY_MAP_STORAGE_SIZE];
+static mut DTB_STORAGE: [u8; DTB_STORAGE_SIZE] = [0; DTB_STORAGE_SIZE];
 
 global_asm!(include_str!("main.S"));
 global_asm!(include_str!("kernel.S"));
+global_asm!(include_str!("exceptions.S"));
 
 extern "C" {
     pub static __STACK_END: u8;
@@ -59,35 +99,273 @@ extern "C" {
 
 #[no_mangle]
 pub extern "C" fn bootloader_main(dtb_ptr: *const u8) -> ! {
+    // Overlays are merged into a copy of the firmware's device tree before anything reads it. If that fails,
+    // we carry on with the firmware's device tree as it is, and report why once we can.
+    // SAFETY: The firmware passes us a device tree blob, we are single threaded, and nothing else ever
+    // touches DTB_STORAGE
+    let firmware_dtb_size = unsafe { Fdt::from_ptr(dtb_ptr) }.map_or(0, |fdt| fdt.total_size());
+    let merged = unsafe { merge_overlays(dtb_ptr, &mut *addr_of_mut!(DTB_STORAGE), OVERLAYS) };
+
+    // Work out which board we are on and bind the drivers we need early to the device tree. Anything the
+    // device tree doesn't provide (or everything, without a usable device tree) is brought up at its usual
+    // address for the board instead, so that we can still report what went wrong.
+    let dtb = RaspiDeviceTree::new(merged.as_ref().map_or(dtb_ptr, |blob| blob.as_ptr()));
+    let board = Board::detect(dtb.as_ref().ok());
+    let mut devices = EarlyDevices::new(board);
+    let bound = dtb.as_ref().ok().map(|dtb| {
+        DriverRegistry::new(&EARLY_DRIVERS)
+            .probe::<MAX_EARLY_DEVICES>(dtb.device_tree(), &mut devices)
+    });
+    devices.bind_fallbacks();
     // Set up a simple uart that we will use until we enable virtual memory mapping - to get some
     // meaningful output as early as possible.
-    let uart = early_init_uart();
+    let uart = devices.uart.take().unwrap();
     unsafe {
         util::print::UART0.set(SingleThreadedLock::new(uart));
     }
     println!("PL011 UART0 Device Driver initialized");
+    println!(
+        "Running on a {} (interrupt controller: {})",
+        board,
+        board.interrupt_controller()
+    );
+    let mailbox = devices.mailbox.as_mut().unwrap();
+    print_board_info(mailbox);
+    print_power_info(mailbox);
+    match bound {
+        Some(Ok(bound)) => print!("Bound devices:\n{}", bound),
+        Some(Err(_)) => {
+            println!("Warning: More devices matched the early drivers than could be listed")
+        }
+        None => println!("Warning: No usable device tree, using the usual peripheral addresses"),
+    }
+    // From here on, mailbox replies are read as soon as they arrive rather than when they are polled
+    let intc = devices.intc.take().unwrap();
+    if let Err(err) = enable_mailbox_interrupt(intc, mailbox) {
+        println!(
+            "Warning: Failed to enable the mailbox interrupt ({:?}), polling the mailbox instead",
+            err
+        );
+    }
+    match merged {
+        Ok(_) => println!("Merged {} device tree overlay(s)", OVERLAYS.len()),
+        Err(err) => match err.overlay {
+            Some(idx) => println!(
+                "Warning: Failed to apply device tree overlay {} ({:?}), ignoring all overlays",
+                idx, err.error
+            ),
+            None => println!(
+                "Warning: Failed to copy the device tree ({:?}), ignoring all overlays",
+                err.error
+            ),
+        },
+    }
+    let dtb = dtb.expect("Failed to parse the device tree");
+    let mut params = BootParams::default();
+    CommandLine::new(dtb.bootargs().unwrap_or("")).parse(&mut [&mut params], |warning| {
+        println!("Warning: {}", warning)
+    });
+    println!(
+        "Console: {}",
+        params.console.or(dtb.stdout_path()).unwrap_or("none")
+    );
+    let num_cpus = dtb.device_tree().cpus().count();
+    println!("CPUs: {}", num_cpus);
+    for cpu in dtb.device_tree().cpus() {
+        println!(
+            "  MPIDR {:#X}: {:?}, release address {:#X}",
+            cpu.mpidr, cpu.enable_method, cpu.release_addr
+        );
+    }
+
+    let page_size = read_linker_var!(__PG_SIZE);
+    let kernel_phys_start = read_linker_var!(__KERNEL_PHYS_START);
+    let kernel_phys_end = read_linker_var!(__KERNEL_PHYS_END);
+    if kernel_phys_start % page_size != 0 || kernel_phys_end % page_size != 0 {
+        panic!("Kernel start and end must be divisible by page size");
+    } else if kernel_phys_start == kernel_phys_end {
+        panic!("Kernel section is missing");
+    }
+    let stack_phys_end = read_linker_var!(__STACK_END);
+    let stack_phys_start = read_linker_var!(__STACK_START);
+    if stack_phys_end % page_size != 0 || stack_phys_start % page_size != 0 {
+        panic!("Stack start and end must be divisible by page size");
+    } else if stack_phys_end == stack_phys_start {
+        panic!("Stack section is missing (How?!)");
+    }
+
+    // Prepare the memory map before anything else, so that every range of physical memory we use from here
+    // on is taken from memory the map knows to be free.
+    // No frame allocator exists yet, so the map lives in the bootloader's own bss for now.
+    // SAFETY: We are single threaded, and nothing else ever touches this buffer
+    let mut early_allocator = unsafe {
+        StaticBumpAlloc::new(
+            addr_of_mut!(EARLY_MEMORY_MAP_STORAGE) as usize,
+            EARLY_MEMORY_MAP_STORAGE_SIZE,
+        )
+    };
+    // Query physical memory ranges from dtb, and size the map to fit them
+    let mut num_memory_regions = 0;
+    dtb.for_each_memory(|_, _| num_memory_regions += 1);
+    let mut num_reserved_regions = MAX_MEMORY_MAP_RESERVATIONS;
+    dtb.for_each_reserved(|_, _| num_reserved_regions += 1);
+    dtb.for_each_mmio(|_, _| num_reserved_regions += 1);
+    let mut mem_map = MemoryMap::new_in(
+        num_memory_regions + 2 * num_reserved_regions,
+        &mut early_allocator,
+    )
+    .unwrap();
+    dtb.for_each_memory(|start, size| {
+        mem_map
+            .add_entry(MemoryMapEntry::new(
+                start as usize,
+                (start + size) as usize,
+                MemoryMapType::FREE,
+            ))
+            .unwrap();
+    });
+    // Memory above the limit given on the command line is never used
+    if let Some(mem_limit) = params.mem_limit {
+        let mem_limit = mem_limit.next_multiple_of(page_size);
+        dtb.for_each_memory(|start, size| {
+            let end = (start + size) as usize;
+            if end > mem_limit {
+                mem_map
+                    .add_entry(MemoryMapEntry::new(
+                        (start as usize).max(mem_limit),
+                        end,
+                        MemoryMapType::RESERVED,
+                    ))
+                    .unwrap();
+            }
+        });
+    }
+    // Now start filling up the map with non-free regions
+    // Firstly, the memory secondary CPUs are parked on, which they keep polling until the kernel releases them
+    if let Some((start, end)) = spin_table_region(dtb.device_tree().cpus(), page_size) {
+        mem_map
+            .add_entry(MemoryMapEntry::new(start, end, MemoryMapType::RESERVED))
+            .unwrap();
+    }
+    // The bootloader image, which is no longer needed once the kernel is running
+    mem_map
+        .add_entry(MemoryMapEntry::new(
+            read_linker_var!(__BOOTLOADER_START),
+            read_linker_var!(__BOOTLOADER_END),
+            MemoryMapType::RECLAIM,
+        ))
+        .unwrap();
+    // Next up, the stack
+    mem_map
+        .add_entry(MemoryMapEntry::new(
+            stack_phys_end,
+            stack_phys_start,
+            MemoryMapType::STACK,
+        ))
+        .unwrap();
+    // Next, the kernel
+    mem_map
+        .add_entry(MemoryMapEntry::new(
+            kernel_phys_start,
+            kernel_phys_end,
+            MemoryMapType::KERNEL,
+        ))
+        .unwrap();
+    // Then everything the firmware asked us not to touch
+    dtb.for_each_reserved(|start, size| {
+        mem_map
+            .add_entry(MemoryMapEntry::new(
+                start as usize - start as usize % page_size,
+                ((start + size) as usize).next_multiple_of(page_size),
+                MemoryMapType::RESERVED,
+            ))
+            .unwrap();
+    });
+    // The VideoCore's share of RAM, which the firmware only tells us about through the mailbox
+    let mailbox = devices.mailbox.as_mut().unwrap();
+    check_firmware_memory(&dtb, &mut mem_map, mailbox);
+    // The peripheral windows, so that mapping code can find device memory in the map later on
+    dtb.for_each_mmio(|start, size| {
+        mem_map
+            .add_entry(MemoryMapEntry::new(
+                start as usize,
+                (start + size) as usize,
+                MemoryMapType::MMIO,
+            ))
+            .unwrap();
+    });
+    // And the firmware's device tree blob, which is no longer needed once the kernel has a copy of its own
+    let firmware_dtb_start = dtb_ptr as usize - dtb_ptr as usize % page_size;
+    let firmware_dtb_end = (dtb_ptr as usize + firmware_dtb_size).next_multiple_of(page_size);
+    mem_map
+        .add_entry(MemoryMapEntry::new(
+            firmware_dtb_start,
+            firmware_dtb_end,
+            MemoryMapType::RECLAIM,
+        ))
+        .unwrap();
+    // The kernel gets its own copy of the (merged) device tree, with some room to edit it
+    let dtb_capacity = (dtb.total_size() + KERNEL_DTB_SLACK).next_multiple_of(page_size);
+    let kernel_dtb = mem_map
+        .allocate_region(
+            dtb_capacity,
+            page_size,
+            Some(LINEAR_MAP_SIZE),
+            MemoryMapType::DTB,
+        )
+        .expect("No free memory for the kernel's device tree");
+    // SAFETY: The memory map guarantees that the region is free, and we are still identity mapped
+    unsafe {
+        copy_nonoverlapping(
+            dtb.as_bytes().as_ptr(),
+            kernel_dtb as *mut u8,
+            dtb.total_size(),
+        )
+    };
 
     // Create two bump allocators, one for temporary allocations that will be freed later, and one for
     // permanent allocations that will never be freed (eg kernel page table)
-    let page_size = read_linker_var!(__PG_SIZE);
-    let kernel_end = read_linker_var!(__KERNEL_PHYS_END);
-    let second_alloc_start = kernel_end + 0x500000;
-    let second_alloc_end = second_alloc_start + 0x500000;
-    // SAFETY: Memory range for both allocators is guarunteed to be free, and we are guarunteed to be
+    // Both ranges start out as RECLAIM, and whatever ends up permanently allocated is marked as RESERVED
+    // once we are done. Everything the kernel reaches through the linear map has to live below its end.
+    let pfa_start = mem_map
+        .allocate_region(
+            BOOTLOADER_FRAME_POOL_SIZE,
+            page_size,
+            Some(LINEAR_MAP_SIZE),
+            MemoryMapType::RECLAIM,
+        )
+        .expect("No free memory for the permanent frame allocator");
+    let temp_pfa_start = mem_map
+        .allocate_region(
+            BOOTLOADER_FRAME_POOL_SIZE,
+            page_size,
+            Some(LINEAR_MAP_SIZE),
+            MemoryMapType::RECLAIM,
+        )
+        .expect("No free memory for the temporary frame allocator");
+    // SAFETY: The memory map guarantees that both ranges are free, and we are guarunteed to be
     // in a single threaded environment during bootloading
     let pfa = unsafe {
         SingleThreadedBumpPFA::new(SingleThreadedLock::new(
-            BumpPFA::new(kernel_end, second_alloc_start, page_size).unwrap(),
+            BumpPFA::new(pfa_start, pfa_start + BOOTLOADER_FRAME_POOL_SIZE, page_size).unwrap(),
         ))
     };
     let temp_pfa = unsafe {
         SingleThreadedBumpPFA::new(SingleThreadedLock::new(
-            BumpPFA::new(second_alloc_start, second_alloc_end, page_size).unwrap(),
+            BumpPFA::new(
+                temp_pfa_start,
+                temp_pfa_start + BOOTLOADER_FRAME_POOL_SIZE,
+                page_size,
+            )
+            .unwrap(),
         ))
     };
     println!(
-        "Reserved range {:X} - {:X} for bootloader frame allocation",
-        kernel_end, second_alloc_end
+        "Reserved ranges {:X} - {:X} and {:X} - {:X} for bootloader frame allocation",
+        pfa_start,
+        pfa_start + BOOTLOADER_FRAME_POOL_SIZE,
+        temp_pfa_start,
+        temp_pfa_start + BOOTLOADER_FRAME_POOL_SIZE
     );
 
     // Temporarily identity map bottom 8GiB of address space
@@ -102,14 +380,9 @@ pub extern "C" fn bootloader_main(dtb_ptr: *const u8) -> ! {
     }
     // Construct a higher half page table for ttbr1
     let mut ttbr1 = unsafe { PageTable::new(|phys| phys, &pfa).unwrap() };
+    // The kernel keeps using this page table, and grows it through the linear map from then on
+    let ttbr1_phys = ttbr1.root_phys();
     // Map the kernel to the canonical higher half location
-    let kernel_phys_start = read_linker_var!(__KERNEL_PHYS_START);
-    let kernel_phys_end = read_linker_var!(__KERNEL_PHYS_END);
-    if kernel_phys_start % page_size != 0 || kernel_phys_end % page_size != 0 {
-        panic!("Kernel start and end must be divisible by page size");
-    } else if kernel_phys_start == kernel_phys_end {
-        panic!("Kernel section is missing");
-    }
     // TODO: Decide on page granularity
     let kernel_virt_start = read_linker_var!(__KERNEL_VIRT_START);
     let mut kernel_virt_page = kernel_virt_start;
@@ -122,19 +395,13 @@ pub extern "C" fn bootloader_main(dtb_ptr: *const u8) -> ! {
 
         kernel_virt_page += page_size;
     }
+    let kernel_virt_end = kernel_virt_page;
     println!(
         "Mapped kernel to range {:#X} - {:#X}",
-        kernel_virt_start, kernel_virt_page
+        kernel_virt_start, kernel_virt_end
     );
     // Next, map the stack to the higher half
-    let stack_phys_end = read_linker_var!(__STACK_END);
-    let stack_phys_start = read_linker_var!(__STACK_START);
     let stack_virt_start = kernel_virt_page;
-    if stack_phys_end % page_size != 0 || stack_phys_start % page_size != 0 {
-        panic!("Stack start and end must be divisible by page size");
-    } else if stack_phys_end == stack_phys_start {
-        panic!("Stack section is missing (How?!)");
-    }
     for phys_addr in (stack_phys_end..stack_phys_start).step_by(page_size) {
         ttbr1.map_4kib_page(
             kernel_virt_page as u64,
@@ -144,27 +411,21 @@ pub extern "C" fn bootloader_main(dtb_ptr: *const u8) -> ! {
 
         kernel_virt_page += page_size;
     }
+    let stack_virt_end = kernel_virt_page;
     println!(
         "Mapped stack to range {:#X} - {:#X}",
-        stack_virt_start, kernel_virt_page
-    );
-    // Construct a bump allocator with a single page of memory, and map it
-    // This will store things like our Arch object and our MemoryMap entries.
-    // TODO: Adjust this so it works with non 4kib pages
-    let phys_page = (&pfa).allocate_zeroed_pages(1, |x| x).unwrap();
-    let mut bump_allocator = unsafe { StaticBumpAlloc::new(phys_page, 4096) };
-    ttbr1.map_4kib_page(
-        kernel_virt_page as u64,
-        phys_page as u64,
-        MemoryAttributes::NormalCacheable,
+        stack_virt_start, stack_virt_end
     );
+    // The rest of the space up to the linear map is reserved for long-lived allocations, such as our Arch
+    // object and our MemoryMap entries
+    let static_virt_start = kernel_virt_page;
     kernel_virt_page = kernel_virt_page.next_multiple_of(1024 * 1024 * 1024);
     let linear_map_start = kernel_virt_page;
     // Finally, map all of physical memory with 1GiB huge pages, so we have an easy phys -> virt translation
-    for addr in (0..0x200000000).step_by(1024 * 1024 * 1024) {
+    for addr in (0..LINEAR_MAP_SIZE).step_by(1024 * 1024 * 1024) {
         ttbr1.map_1gib_page(
             kernel_virt_page as u64,
-            addr,
+            addr as u64,
             MemoryAttributes::DeviceStronglyOrdered,
         );
 
@@ -174,39 +435,85 @@ pub extern "C" fn bootloader_main(dtb_ptr: *const u8) -> ! {
         "Linearly mapped physical memory to range {:#X} - {:#X}",
         linear_map_start, kernel_virt_page
     );
+    // SAFETY: The window between the stack and the linear map is not used by anything else, and we are
+    // still running on an identity mapping
+    let mut static_allocator = unsafe {
+        FrameStaticAlloc::new(
+            &pfa,
+            &mut ttbr1,
+            |phys| phys,
+            page_size,
+            static_virt_start,
+            linear_map_start - static_virt_start,
+        )
+        .unwrap()
+    };
 
-    // Prepare the memory map
-    let mut mem_map = MemoryMap::<32>::new_in(&mut bump_allocator).unwrap();
-    // Query physical memory ranges from dtb
-    let dtb = RaspiDeviceTree::new(dtb_ptr).unwrap();
-    dtb.for_each_memory(|start, size| {
-        mem_map.add_entry(MemoryMapEntry::new(
-            start as usize,
-            (start + size) as usize,
-            MemoryMapType::FREE,
-        ));
-    });
-    // Now start filling up the map with non-free regions
-    // Firstly, the first page is reserved because secondary CPUs are parked there
-    mem_map.add_entry(MemoryMapEntry::new(0, page_size, MemoryMapType::RESERVED));
-    // Next up, the stack
-    mem_map.add_entry(MemoryMapEntry::new(
-        stack_phys_end,
-        stack_phys_start,
-        MemoryMapType::STACK,
-    ));
-    // Next, the kernel
-    mem_map.add_entry(MemoryMapEntry::new(
-        kernel_phys_start,
-        kernel_phys_end,
-        MemoryMapType::KERNEL,
-    ));
-    // Now we have to mark reserved memory for the page tables, etc that will be shared with the kernel
-    mem_map.add_entry(MemoryMapEntry::new(
-        pfa.allocated_range().0,
-        pfa.allocated_range().1,
-        MemoryMapType::RESERVED,
-    ));
+    // Set aside everything we hand over to the kernel. It has to live in static allocations, since our own
+    // memory (including the memory map's current storage) is reclaimed once the kernel is running.
+    let command_line =
+        StaticBox::from_str(dtb.bootargs().unwrap_or(""), &mut static_allocator).unwrap();
+    let stdout_path =
+        StaticBox::from_str(dtb.stdout_path().unwrap_or(""), &mut static_allocator).unwrap();
+    // Marking the permanent frames as reserved below can still split one more entry in two
+    let mut kernel_mem_map =
+        StaticVec::with_capacity(mem_map.get_entries().len() + 2, &mut static_allocator).unwrap();
+    let mut cpus = StaticVec::with_capacity(num_cpus, &mut static_allocator).unwrap();
+    for cpu in dtb.device_tree().cpus() {
+        cpus.try_push(cpu).unwrap();
+    }
+    let mut boot_info = StaticBox::new(BootInfo::new(), &mut static_allocator).unwrap();
+
+    // Now that we are done allocating frames, mark the ones that are shared with the kernel (page tables,
+    // static allocations, etc) as reserved
+    mem_map
+        .add_entry(MemoryMapEntry::new(
+            pfa.allocated_range().0,
+            pfa.allocated_range().1,
+            MemoryMapType::RESERVED,
+        ))
+        .unwrap();
+
+    // Fill in the boot info, translating every pointer to where the kernel will find it
+    kernel_mem_map
+        .try_extend_from_slice(mem_map.get_entries())
+        .unwrap();
+    boot_info.memory_map = static_allocator
+        .mapped_address(kernel_mem_map.as_ptr() as usize)
+        .unwrap() as *const MemoryMapEntry;
+    boot_info.memory_map_len = kernel_mem_map.len();
+    if !command_line.is_empty() {
+        boot_info.command_line = static_allocator
+            .mapped_address(command_line.as_ptr() as usize)
+            .unwrap() as *const u8;
+        boot_info.command_line_len = command_line.len();
+    }
+    if !stdout_path.is_empty() {
+        boot_info.stdout_path = static_allocator
+            .mapped_address(stdout_path.as_ptr() as usize)
+            .unwrap() as *const u8;
+        boot_info.stdout_path_len = stdout_path.len();
+    }
+    if !cpus.is_empty() {
+        boot_info.cpus = static_allocator
+            .mapped_address(cpus.as_ptr() as usize)
+            .unwrap() as *const Cpu;
+        boot_info.cpus_len = cpus.len();
+    }
+    boot_info.dtb_phys = kernel_dtb;
+    boot_info.dtb_size = dtb.total_size();
+    boot_info.dtb_capacity = dtb_capacity;
+    boot_info.linear_map_base = linear_map_start;
+    boot_info.page_table_phys = ttbr1_phys;
+    boot_info.kernel_phys_start = kernel_phys_start;
+    boot_info.kernel_phys_end = kernel_phys_end;
+    boot_info.kernel_virt_start = kernel_virt_start;
+    boot_info.kernel_virt_end = kernel_virt_end;
+    boot_info.stack_virt_start = stack_virt_start;
+    boot_info.stack_virt_end = stack_virt_end;
+    let boot_info_virt = static_allocator
+        .mapped_address(&*boot_info as *const BootInfo as usize)
+        .unwrap();
     println!(
         "Printing physical memory map:\n\n\
         Page size:      {}\n\
@@ -220,6 +527,31 @@ pub extern "C" fn bootloader_main(dtb_ptr: *const u8) -> ! {
         (mem_map.get_total_mem() - mem_map.get_free_mem()) / page_size,
         mem_map
     );
+    println!(
+        "Printing frame allocator statistics:\n\n\
+        Permanent allocations:\n{}\n\
+        Temporary allocations:\n{}",
+        pfa.stats(),
+        temp_pfa.stats()
+    );
+    println!(
+        "Static allocations: {} used of {}, mapped to range {:#X} - {:#X}\n",
+        MemorySize::new(static_allocator.used()),
+        MemorySize::new(static_allocator.capacity()),
+        static_allocator.mapped_range().0,
+        static_allocator.mapped_range().1
+    );
+    println!(
+        "Boot info for the kernel placed at {:#X}, with command line \"{}\"\n",
+        boot_info_virt, &*command_line
+    );
+
+    // The kernel installs vectors of its own, and expects the interrupt controller as the firmware left it
+    mask_interrupts();
+    devices.mailbox.as_ref().unwrap().disable_interrupts();
+    if let Some(intc) = INTC.get() {
+        intc.disable_all();
+    }
 
     print!("Enabling MMU with identity mapping...");
     unsafe {
@@ -228,28 +560,142 @@ pub extern "C" fn bootloader_main(dtb_ptr: *const u8) -> ! {
     println!("Success");
 
     println!("Transferring control to kernel...\n");
-    let kernel_entry: extern "C" fn() -> ! =
+    let kernel_entry: extern "C" fn(*const BootInfo) -> ! =
         unsafe { core::mem::transmute(read_linker_var!(__KERNEL_VIRT_START)) };
-    kernel_entry();
+    kernel_entry(boot_info_virt as *const BootInfo);
 }
 
-fn early_init_uart() -> Pl011 {
-    // Create temp device drivers needed to init the uart
-    let mut gpio: Gpio;
-    let mut mailbox: Mailbox;
-    let uart: Pl011;
+/// Installs our exception vectors, and has the interrupt controller call Mailbox::handle_interrupt
+/// whenever the mailbox raises its interrupt
+fn enable_mailbox_interrupt(mut intc: Intc, mailbox: &Mailbox) -> Result<(), DeviceError> {
+    let line = mailbox.interrupt().ok_or(DeviceError::MissingDependency)?;
+    intc.enable(line, Mailbox::handle_interrupt)?;
+    // SAFETY: We are single threaded with IRQs masked, and still identity mapped as the bootloader was linked
     unsafe {
-        // Safety: The MMIO addresses are correct for the given Raspberry Pi board revision.
-        gpio = Gpio::new(GPIO_PHYS_BASE);
-        mailbox = Mailbox::new(MAILBOX_PHYS_BASE);
+        install_vectors();
+        INTC.set(intc);
     }
-    // Set the UART frequency to a known value & construct uart driver
-    let mut uart_rate_msg = SetClockRate::new(CLOCK_UART, 30000000);
-    mailbox.send_property_mail(&mut uart_rate_msg).unwrap();
-    unsafe {
-        // Safety: The MMIO address is correct and we have set the correct UART clock frequency
-        uart = Pl011::new(PL011_PHYS_BASE, &mut gpio);
+    mailbox.enable_interrupts();
+    // SAFETY: The only interrupt enabled at the controller is the mailbox's, which now has its handler
+    unsafe { unmask_interrupts() };
+    Ok(())
+}
+
+/// Prints a summary of what the firmware reports about the board and itself
+fn print_board_info(mailbox: &mut Mailbox) {
+    let Ok(info) = mailbox.board_info() else {
+        println!("Warning: Failed to query the board information from the firmware");
+        return;
+    };
+    match (info.decoded_revision(), info.revision) {
+        (Some(revision), _) => println!("Board: {}", revision),
+        (None, Some(code)) => println!("Board: old style revision code {:#X}", code),
+        (None, None) => println!("Board: unknown revision"),
+    }
+    if let Some(model) = info.model {
+        println!("  Model: {:#X}", model);
+    }
+    if let Some(serial) = info.serial {
+        println!("  Serial number: {:016X}", serial);
+    }
+    if let Some(mac) = info.mac_address {
+        println!(
+            "  MAC address: {:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+        );
+    }
+    if let Some(firmware_revision) = info.firmware_revision {
+        println!("  Firmware revision: {:#X}", firmware_revision);
+    }
+    if let Some(dma_channels) = info.dma_channels {
+        println!("  Usable DMA channels: {:#06X}", dma_channels);
+    }
+}
+
+/// Prints the clocks and power domains the firmware has turned on, along with the SoC's temperature
+fn print_power_info(mailbox: &mut Mailbox) {
+    let Ok(info) = mailbox.power_info() else {
+        println!("Warning: Failed to query the clocks and power domains from the firmware");
+        return;
+    };
+    println!("Clocks:");
+    for (clock, clock_info) in Clock::ALL.iter().zip(info.clocks) {
+        // Clocks the board doesn't have are None, and clocks the firmware left off are skipped
+        let Some(clock_info) = clock_info.filter(|clock_info| clock_info.enabled) else {
+            continue;
+        };
+        print!(
+            "  {:?}: {}Hz ({}-{}Hz",
+            clock, clock_info.rate, clock_info.min_rate, clock_info.max_rate
+        );
+        match clock_info.measured_rate {
+            Some(measured) => println!(", measured {}Hz)", measured),
+            None => println!(")"),
+        }
+    }
+    print!("Powered domains:");
+    for (domain, on) in PowerDomain::ALL.iter().zip(info.power_domains) {
+        if on == Some(true) {
+            print!(" {:?}", domain);
+        }
+    }
+    println!();
+    match (info.temperature, info.max_temperature) {
+        (Some(temperature), Some(max)) => {
+            println!("SoC temperature: {} (max {})", temperature, max)
+        }
+        _ => println!("Warning: Failed to read the SoC temperature from the firmware"),
     }
+}
 
-    uart
+/// Queries the ARM and VideoCore memory split from the firmware, reserving the VideoCore's memory in the
+/// memory map and warning about any disagreement with the memory nodes of the device tree
+fn check_firmware_memory(dtb: &RaspiDeviceTree, mem_map: &mut MemoryMap, mailbox: &mut Mailbox) {
+    let mut message = PropertyBuffer::<16>::new();
+    let arm = message.add::<GetArmMemory>(()).unwrap();
+    let vc = message.add::<GetVcMemory>(()).unwrap();
+    let responses = mailbox
+        .send_property_mail(&mut message)
+        .and_then(|_| Ok((message.response(&arm)?, message.response(&vc)?)));
+    let Ok((arm, vc)) = responses else {
+        println!("Warning: Failed to query the memory split from the firmware");
+        return;
+    };
+    let arm_start = arm.base as u64;
+    let arm_end = arm_start + arm.size as u64;
+    let vc_start = vc.base as u64;
+    let vc_end = vc_start + vc.size as u64;
+    println!(
+        "Firmware reports ARM memory {:#X} - {:#X} and VideoCore memory {:#X} - {:#X}",
+        arm_start, arm_end, vc_start, vc_end
+    );
+
+    // The device tree may describe more memory than the firmware reports (eg RAM above 1GiB on the Pi 4),
+    // but it must at least cover the ARM's memory and must never hand out the VideoCore's
+    let mut arm_covered = false;
+    dtb.for_each_memory(|start, size| {
+        let end = start + size;
+        if start <= arm_start && arm_end <= end {
+            arm_covered = true;
+        }
+        if start < vc_end && vc_start < end {
+            println!(
+                "Warning: Device tree memory {:#X} - {:#X} overlaps VideoCore memory",
+                start, end
+            );
+        }
+    });
+    if !arm_covered {
+        println!(
+            "Warning: Device tree memory does not cover the ARM memory reported by the firmware"
+        );
+    }
+
+    mem_map
+        .add_entry(MemoryMapEntry::new(
+            vc_start as usize,
+            vc_end as usize,
+            MemoryMapType::RESERVED,
+        ))
+        .unwrap();
 }
@@ -2,9 +2,9 @@ use aarch64_cpu::{
     asm::barrier,
     registers::{Writeable, MAIR_EL1, SCTLR_EL1, TCR_EL1, TTBR0_EL1, TTBR1_EL1},
 };
-use common::allocators::page_frame_allocator::FrameAllocator;
-
-use super::page_table::PageTable;
+use common::{
+    allocators::page_frame_allocator::FrameAllocator, arch::aarch64::page_table::PageTable,
+};
 
 pub unsafe fn enable_mmu<A: FrameAllocator>(ttbr0: &mut PageTable<A>, ttbr1: &mut PageTable<A>) {
     // idx 0: Strongly Ordered Device memory
@@ -14,8 +14,8 @@ pub unsafe fn enable_mmu<A: FrameAllocator>(ttbr0: &mut PageTable<A>, ttbr1: &mu
     );
 
     // Set base addr for page tables
-    TTBR0_EL1.set_baddr(ttbr0.as_raw() as u64);
-    TTBR1_EL1.set_baddr(ttbr1.as_raw() as u64);
+    TTBR0_EL1.set_baddr(ttbr0.root_phys() as u64);
+    TTBR1_EL1.set_baddr(ttbr1.root_phys() as u64);
 
     // Minimum value for T0SZ and T1SZ is 16, splitting entire 48-bit virtual address space
     // between user and kernel mode.
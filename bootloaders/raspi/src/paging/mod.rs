@@ -1,3 +1 @@
-pub mod memory_attribute;
 pub mod mmu;
-pub mod page_table;
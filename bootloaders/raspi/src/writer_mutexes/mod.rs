@@ -1 +0,0 @@
-pub mod single_threaded;
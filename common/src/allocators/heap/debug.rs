@@ -0,0 +1,354 @@
+use super::Heap;
+use crate::util::{backtrace::capture_callers, error::AllocError};
+use core::{
+    alloc::Layout,
+    mem::{align_of, size_of},
+    ptr::{null_mut, NonNull},
+    slice::from_raw_parts_mut,
+};
+
+/// Number of return addresses recorded for every allocation
+pub const CALLER_DEPTH: usize = 6;
+
+/// Size of the guard regions placed on either side of every allocation
+const REDZONE_SIZE: usize = 16;
+const REDZONE_BYTE: u8 = 0xFB;
+const POISON_BYTE: u8 = 0xDE;
+
+const STATE_ALLOCATED: usize = 0xA110CA7E_D0000000;
+const STATE_FREED: usize = 0xF4EED000_D0000000;
+
+/// Bookkeeping stored in front of every allocation made through a DebugHeap
+#[repr(C)]
+struct DebugHeader {
+    // The slab freelist overwrites the first word of a freed block, so nothing we need after a block
+    // has been freed may live here
+    _freelist: usize,
+    state: usize,
+    prev: *mut DebugHeader,
+    next: *mut DebugHeader,
+    size: usize,
+    user_offset: usize,
+    block_size: usize,
+    callers: [usize; CALLER_DEPTH],
+}
+
+/// A live allocation, as reported by DebugHeap::for_each_outstanding
+pub struct OutstandingAllocation {
+    pub addr: usize,
+    pub size: usize,
+    pub callers: [usize; CALLER_DEPTH],
+}
+
+/// A Heap wrapper that trades memory and speed for catching heap corruption as early as possible
+///
+/// Every allocation is surrounded by redzones that are checked when it is freed, and freed memory is
+/// poisoned and checked again before it is handed out a second time, so writes through dangling
+/// pointers are caught too. Freeing a block twice, or freeing a pointer the heap never handed out, panics
+/// immediately. Every live allocation also records the call stack that allocated it, so leaks can be
+/// tracked down with for_each_outstanding.
+pub struct DebugHeap {
+    heap: Heap,
+    outstanding: *mut DebugHeader,
+    outstanding_count: usize,
+}
+
+impl DebugHeap {
+    pub fn new(heap: Heap) -> Self {
+        Self {
+            heap,
+            outstanding: null_mut(),
+            outstanding_count: 0,
+        }
+    }
+
+    pub fn outstanding_count(&self) -> usize {
+        self.outstanding_count
+    }
+
+    /// Allocates a block of memory satisfying the requirements of layout
+    pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let (padded_layout, user_offset) = Self::padded_layout(layout)?;
+        let block = self.heap.allocate(padded_layout)?.as_ptr();
+        let header = block as *mut DebugHeader;
+
+        unsafe {
+            // Safety: The block is at least user_offset + layout.size() + REDZONE_SIZE bytes long, and is
+            // aligned for a DebugHeader
+            if (*header).state == STATE_FREED {
+                // This block was freed through us before, so everything past the header must still be
+                // poisoned. Anything else means someone wrote through a dangling pointer.
+                let poisoned_size = (*header).block_size.min(padded_layout.size());
+                let freed = from_raw_parts_mut(
+                    block.add(size_of::<DebugHeader>()),
+                    poisoned_size - size_of::<DebugHeader>(),
+                );
+                if let Some(offset) = freed.iter().position(|&byte| byte != POISON_BYTE) {
+                    panic!(
+                        "Heap corruption: freed block at {:#x} was modified at offset {:#x} after being freed \
+                        (last allocated by {:#x?})",
+                        block as usize + (*header).user_offset,
+                        size_of::<DebugHeader>() + offset,
+                        (*header).callers
+                    );
+                }
+            }
+
+            let mut callers = [0; CALLER_DEPTH];
+            capture_callers(&mut callers);
+            header.write(DebugHeader {
+                _freelist: 0,
+                state: STATE_ALLOCATED,
+                prev: null_mut(),
+                next: self.outstanding,
+                size: layout.size(),
+                user_offset,
+                block_size: padded_layout.size(),
+                callers,
+            });
+            if !self.outstanding.is_null() {
+                (*self.outstanding).prev = header;
+            }
+            self.outstanding = header;
+            self.outstanding_count += 1;
+
+            // Fill both redzones, and the padding between the header and the front redzone
+            let user_start = block.add(user_offset);
+            from_raw_parts_mut(
+                block.add(size_of::<DebugHeader>()),
+                user_offset - size_of::<DebugHeader>(),
+            )
+            .fill(REDZONE_BYTE);
+            from_raw_parts_mut(user_start.add(layout.size()), REDZONE_SIZE).fill(REDZONE_BYTE);
+
+            Ok(NonNull::new_unchecked(user_start))
+        }
+    }
+
+    /// Frees a block of memory previously handed out by allocate, panicking if the block was already freed
+    /// or its redzones were overwritten
+    ///
+    /// # Safety
+    /// ptr must have been returned by a previous call to allocate on this heap with the same layout.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let (padded_layout, user_offset) =
+            Self::padded_layout(layout).expect("Attempted to free a block with an invalid layout!");
+        let block = ptr.as_ptr().sub(user_offset);
+        let header = block as *mut DebugHeader;
+
+        // Page-backed blocks are unmapped once freed, so their header can't be checked. The heap never
+        // hands the same virtual address out twice though, so an unmapped block must have been freed
+        // already.
+        if self.heap.size_class(padded_layout).is_none() && !self.heap.is_mapped(block as usize) {
+            panic!(
+                "Heap corruption: double free of block at {:#x}, or it was never allocated",
+                ptr.as_ptr() as usize
+            );
+        }
+        match (*header).state {
+            STATE_ALLOCATED => (),
+            STATE_FREED => panic!(
+                "Heap corruption: double free of block at {:#x} (last allocated by {:#x?})",
+                ptr.as_ptr() as usize,
+                (*header).callers
+            ),
+            _ => panic!(
+                "Heap corruption: attempted to free {:#x}, which was never allocated or whose header was overwritten",
+                ptr.as_ptr() as usize
+            ),
+        }
+        if (*header).size != layout.size() {
+            panic!(
+                "Heap corruption: block at {:#x} was allocated with size {} but freed with size {}",
+                ptr.as_ptr() as usize,
+                (*header).size,
+                layout.size()
+            );
+        }
+
+        let front_redzone = from_raw_parts_mut(
+            block.add(size_of::<DebugHeader>()),
+            user_offset - size_of::<DebugHeader>(),
+        );
+        let back_redzone = from_raw_parts_mut(ptr.as_ptr().add(layout.size()), REDZONE_SIZE);
+        if front_redzone.iter().any(|&byte| byte != REDZONE_BYTE) {
+            panic!(
+                "Heap corruption: buffer underflow detected in block at {:#x} (allocated by {:#x?})",
+                ptr.as_ptr() as usize,
+                (*header).callers
+            );
+        }
+        if back_redzone.iter().any(|&byte| byte != REDZONE_BYTE) {
+            panic!(
+                "Heap corruption: buffer overflow detected in block at {:#x} (allocated by {:#x?})",
+                ptr.as_ptr() as usize,
+                (*header).callers
+            );
+        }
+
+        // Unlink the block from the outstanding list
+        let prev = (*header).prev;
+        let next = (*header).next;
+        if prev.is_null() {
+            self.outstanding = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        self.outstanding_count -= 1;
+
+        // Mark the block as freed and poison everything after the header, so that reuse can verify nobody
+        // touched it in the meantime. The callers are kept around to help diagnose double frees.
+        // Page-backed blocks are unmapped as soon as they are freed, and their frames may end up anywhere,
+        // so they are only poisoned and never checked on reuse.
+        (*header).state = if self.heap.size_class(padded_layout).is_some() {
+            STATE_FREED
+        } else {
+            0
+        };
+        from_raw_parts_mut(
+            block.add(size_of::<DebugHeader>()),
+            padded_layout.size() - size_of::<DebugHeader>(),
+        )
+        .fill(POISON_BYTE);
+
+        self.heap
+            .deallocate(NonNull::new_unchecked(block), padded_layout);
+    }
+
+    /// Passes every allocation that has not yet been freed to the provided closure, most recent first
+    pub fn for_each_outstanding<F: FnMut(OutstandingAllocation)>(&self, mut closure: F) {
+        let mut header = self.outstanding;
+        while !header.is_null() {
+            // Safety: Only headers of live allocations are ever linked into the outstanding list
+            let current = unsafe { &*header };
+            closure(OutstandingAllocation {
+                addr: header as usize + current.user_offset,
+                size: current.size,
+                callers: current.callers,
+            });
+            header = current.next;
+        }
+    }
+
+    /// Returns the layout of the block that backs a user allocation of layout, along with the offset of the
+    /// user's memory within that block
+    fn padded_layout(layout: Layout) -> Result<(Layout, usize), AllocError> {
+        let user_offset =
+            (size_of::<DebugHeader>() + REDZONE_SIZE).next_multiple_of(layout.align());
+        let padded = Layout::from_size_align(
+            user_offset + layout.size() + REDZONE_SIZE,
+            layout.align().max(align_of::<DebugHeader>()),
+        )
+        .map_err(|_| AllocError)?;
+
+        Ok((padded, user_offset))
+    }
+}
+
+// Safety: Every header on the outstanding list belongs to a block owned by the wrapped Heap
+unsafe impl Send for DebugHeap {}
+
+#[cfg(test)]
+mod tests {
+    use super::{DebugHeap, POISON_BYTE};
+    use crate::allocators::heap::tests::TestHeap;
+    use core::alloc::Layout;
+
+    const PAGE: usize = 0x1000;
+
+    fn new_heap() -> DebugHeap {
+        DebugHeap::new(TestHeap::new(16).heap)
+    }
+
+    #[test]
+    fn allocations_are_tracked_until_freed() {
+        let mut heap = new_heap();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let small = heap.allocate(layout).unwrap();
+        let large_layout = Layout::from_size_align(2 * PAGE, 8).unwrap();
+        let large = heap.allocate(large_layout).unwrap();
+        assert_eq!(heap.outstanding_count(), 2);
+
+        let mut outstanding = std::vec::Vec::new();
+        heap.for_each_outstanding(|allocation| {
+            outstanding.push((allocation.addr, allocation.size))
+        });
+        assert_eq!(
+            outstanding,
+            [
+                (large.as_ptr() as usize, 2 * PAGE),
+                (small.as_ptr() as usize, 40)
+            ]
+        );
+
+        unsafe {
+            small.as_ptr().write_bytes(0xAA, 40);
+            heap.deallocate(small, layout);
+            heap.deallocate(large, large_layout);
+        }
+        assert_eq!(heap.outstanding_count(), 0);
+    }
+
+    #[test]
+    fn freed_memory_is_poisoned() {
+        let mut heap = new_heap();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let block = heap.allocate(layout).unwrap();
+        unsafe {
+            heap.deallocate(block, layout);
+            assert_eq!(*block.as_ptr().add(8), POISON_BYTE);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer overflow")]
+    fn detects_overflow() {
+        let mut heap = new_heap();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let block = heap.allocate(layout).unwrap();
+        unsafe {
+            block.as_ptr().add(40).write(0);
+            heap.deallocate(block, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "modified at offset")]
+    fn detects_use_after_free() {
+        let mut heap = new_heap();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let block = heap.allocate(layout).unwrap();
+        unsafe {
+            heap.deallocate(block, layout);
+            block.as_ptr().write(0);
+        }
+        heap.allocate(layout).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn detects_double_free() {
+        let mut heap = new_heap();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let block = heap.allocate(layout).unwrap();
+        unsafe {
+            heap.deallocate(block, layout);
+            heap.deallocate(block, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn detects_double_free_of_pages() {
+        let mut heap = new_heap();
+        let layout = Layout::from_size_align(3 * PAGE, 8).unwrap();
+        let block = heap.allocate(layout).unwrap();
+        unsafe {
+            heap.deallocate(block, layout);
+            heap.deallocate(block, layout);
+        }
+    }
+}
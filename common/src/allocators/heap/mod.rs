@@ -0,0 +1,480 @@
+use self::slab::SlabCache;
+use crate::{
+    allocators::{
+        page_frame_allocator::{stats::FrameOwner, FrameAllocator},
+        static_box::StaticBox,
+    },
+    memory::address_space::{AddressSpace, MemoryAttributes},
+    util::error::AllocError,
+};
+use core::{alloc::Layout, ptr::NonNull};
+
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+pub mod slab;
+
+/// Object sizes served by the slab caches. Anything larger is served with whole pages.
+const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// A general purpose heap that grows on demand into a dedicated region of virtual memory
+///
+/// Small allocations are served from size-class slab caches, which grow one page at a time. Allocations
+/// too large for the biggest size class are instead given their own run of pages straight from the frame
+/// allocator. Either way, every page the heap uses is mapped into the heap's virtual region before it is
+/// handed out, so the heap never depends on a linear mapping of physical memory. Since only the virtual
+/// pages of a block need to be contiguous, frames are allocated (and freed) one at a time.
+pub struct Heap {
+    frame_allocator: StaticBox<dyn FrameAllocator + Send>,
+    address_space: StaticBox<dyn AddressSpace + Send>,
+    page_size: usize,
+    next_virt: usize,
+    virt_end: usize,
+    caches: [SlabCache; SIZE_CLASSES.len()],
+}
+
+impl Heap {
+    /// Constructs a new, empty heap that will grow into the virtual range virt_start..virt_start + virt_size
+    ///
+    /// # Safety
+    /// The virtual range must be unused, page aligned, and reserved exclusively for this heap. The address
+    /// space must be the one that is active whenever the heap is in use.
+    pub unsafe fn new(
+        frame_allocator: StaticBox<dyn FrameAllocator + Send>,
+        address_space: StaticBox<dyn AddressSpace + Send>,
+        page_size: usize,
+        virt_start: usize,
+        virt_size: usize,
+    ) -> Result<Self, AllocError> {
+        if virt_start % page_size != 0 || virt_size % page_size != 0 {
+            return Err(AllocError);
+        }
+
+        Ok(Self {
+            frame_allocator,
+            address_space,
+            page_size,
+            next_virt: virt_start,
+            virt_end: virt_start + virt_size,
+            caches: SIZE_CLASSES.map(SlabCache::new),
+        })
+    }
+
+    /// Allocates a block of memory satisfying the requirements of layout
+    pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        match self.size_class(layout) {
+            Some(idx) => {
+                if let Some(obj) = self.caches[idx].allocate() {
+                    return Ok(obj);
+                }
+
+                // This cache is empty, so grow it by another slab
+                let slab = self.map_pages(1, self.page_size)?;
+                unsafe {
+                    // Safety: The slab was just mapped into our private heap region, so it is exclusively
+                    // owned by this cache, and page alignment satisfies the alignment of every size class
+                    self.caches[idx].add_slab(slab.as_ptr() as usize, self.page_size);
+                }
+                self.caches[idx].allocate().ok_or(AllocError)
+            }
+            None => {
+                let num_pages = layout.size().div_ceil(self.page_size);
+                self.map_pages(num_pages, layout.align().max(self.page_size))
+            }
+        }
+    }
+
+    /// Frees a block of memory previously handed out by allocate
+    ///
+    /// # Safety
+    /// ptr must have been returned by a previous call to allocate on this heap with the same layout, and
+    /// must not be used after this call.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        match self.size_class(layout) {
+            Some(idx) => self.caches[idx].deallocate(ptr),
+            None => {
+                let num_pages = layout.size().div_ceil(self.page_size);
+                self.unmap_pages(ptr.as_ptr() as usize, num_pages);
+            }
+        }
+    }
+
+    /// Returns the index of the slab cache that serves layout, or None if layout needs whole pages
+    fn size_class(&self, layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        if size > self.page_size / 2 {
+            return None;
+        }
+
+        SIZE_CLASSES.iter().position(|&class| class >= size)
+    }
+
+    /// Returns whether addr lies in a page of the heap region that is currently mapped
+    #[cfg(feature = "heap-debug")]
+    fn is_mapped(&mut self, addr: usize) -> bool {
+        self.address_space.translate(addr).is_ok()
+    }
+
+    /// Maps num_pages fresh frames to the next free, suitably aligned run of pages in the heap region
+    fn map_pages(&mut self, num_pages: usize, align: usize) -> Result<NonNull<u8>, AllocError> {
+        // TODO: Virtual ranges released by large allocations are never reused. DebugHeap relies on this to
+        // catch double frees of them, so it will need another way once they are.
+        let size = num_pages * self.page_size;
+        let virt_start = self.next_virt.next_multiple_of(align);
+        if virt_start + size > self.virt_end {
+            return Err(AllocError);
+        }
+
+        for page in 0..num_pages {
+            if let Err(err) = self.map_page(virt_start + page * self.page_size) {
+                // Safety: Every page before this one was mapped above, and none of them were handed out
+                unsafe { self.unmap_pages(virt_start, page) };
+                return Err(err);
+            }
+        }
+        self.next_virt = virt_start + size;
+
+        NonNull::new(virt_start as *mut u8).ok_or(AllocError)
+    }
+
+    /// Maps a fresh frame at virt
+    fn map_page(&mut self, virt: usize) -> Result<(), AllocError> {
+        let phys = self.frame_allocator.allocate_pages(1, FrameOwner::Heap)?;
+        if !self.address_space.map_range(
+            virt,
+            phys,
+            self.page_size,
+            MemoryAttributes::NormalCacheable,
+        ) {
+            unsafe {
+                // Safety: This frame was allocated above and was never made reachable
+                self.frame_allocator
+                    .deallocate_pages(phys, 1, FrameOwner::Heap);
+            }
+            return Err(AllocError);
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps num_pages pages starting at virt_start and gives their frames back to the frame allocator
+    ///
+    /// # Safety
+    /// Every page must have been mapped by map_pages, and must not be used after this call.
+    unsafe fn unmap_pages(&mut self, virt_start: usize, num_pages: usize) {
+        for page in 0..num_pages {
+            let virt = virt_start + page * self.page_size;
+            let phys = self
+                .address_space
+                .translate(virt)
+                .expect("Attempted to free a heap allocation that is not mapped!");
+            self.address_space.unmap_range(virt, phys, self.page_size);
+            self.frame_allocator
+                .deallocate_pages(phys, 1, FrameOwner::Heap);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::Heap;
+    use crate::{
+        allocators::{
+            page_frame_allocator::{
+                stats::{FrameOwner, FrameStats},
+                FrameAllocator,
+            },
+            static_box::StaticBox,
+            static_bump::StaticBumpAlloc,
+        },
+        memory::{
+            address_space::{AddressSpace, MemoryAttributes},
+            PhysAddr,
+        },
+        util::error::{AddressSpaceError, AllocError},
+    };
+    use core::alloc::Layout;
+    use std::{boxed::Box, collections::BTreeMap, sync::Mutex, vec, vec::Vec};
+
+    pub(crate) const PAGE: usize = 0x1000;
+    /// Where the fake physical frames handed out by Frames start
+    pub(crate) const PHYS_BASE: usize = 0x4000_0000;
+
+    /// Hands out made up physical frames, keeping track of which ones are allocated and for whom
+    #[derive(Default)]
+    pub(crate) struct Frames {
+        next: Mutex<usize>,
+        pub(crate) allocated: Mutex<BTreeMap<PhysAddr, (usize, FrameOwner)>>,
+    }
+
+    unsafe impl FrameAllocator for &'static Frames {
+        fn allocate_pages(
+            &self,
+            num_contiguous_pages: usize,
+            owner: FrameOwner,
+        ) -> Result<PhysAddr, AllocError> {
+            let mut next = self.next.lock().unwrap();
+            let addr = PHYS_BASE + *next * PAGE;
+            *next += num_contiguous_pages;
+            self.allocated
+                .lock()
+                .unwrap()
+                .insert(addr, (num_contiguous_pages, owner));
+            Ok(addr)
+        }
+
+        fn allocate_zeroed_pages(
+            &self,
+            _num_contiguous_pages: usize,
+            _owner: FrameOwner,
+            _translation: fn(usize) -> usize,
+        ) -> Result<PhysAddr, AllocError> {
+            unimplemented!()
+        }
+
+        unsafe fn deallocate_pages(
+            &self,
+            addr: PhysAddr,
+            num_contiguous_pages: usize,
+            owner: FrameOwner,
+        ) {
+            assert!(
+                self.allocated.lock().unwrap().remove(&addr) == Some((num_contiguous_pages, owner))
+            );
+        }
+
+        fn stats(&self) -> FrameStats {
+            unimplemented!()
+        }
+    }
+
+    /// Records which pages are mapped where. The virtual pages are real host memory, so the heap can use
+    /// them no matter where they are "mapped" to.
+    #[derive(Default)]
+    pub(crate) struct Pages {
+        pub(crate) mapped: Mutex<BTreeMap<usize, PhysAddr>>,
+        /// How many more pages may be mapped before mapping fails, if limited
+        pub(crate) map_limit: Mutex<Option<usize>>,
+    }
+
+    impl AddressSpace for &'static Pages {
+        fn set_active(&mut self) -> bool {
+            unimplemented!()
+        }
+
+        fn map_range(
+            &mut self,
+            virt_start: usize,
+            phys_start: usize,
+            size: usize,
+            _attr: MemoryAttributes,
+        ) -> bool {
+            if let Some(limit) = self.map_limit.lock().unwrap().as_mut() {
+                if *limit < size / PAGE {
+                    return false;
+                }
+                *limit -= size / PAGE;
+            }
+            let mut mapped = self.mapped.lock().unwrap();
+            for offset in (0..size).step_by(PAGE) {
+                assert!(mapped
+                    .insert(virt_start + offset, phys_start + offset)
+                    .is_none());
+            }
+            true
+        }
+
+        fn unmap_range(&mut self, virt_start: usize, phys_start: usize, size: usize) -> bool {
+            let mut mapped = self.mapped.lock().unwrap();
+            for offset in (0..size).step_by(PAGE) {
+                assert_eq!(
+                    mapped.remove(&(virt_start + offset)),
+                    Some(phys_start + offset)
+                );
+            }
+            true
+        }
+
+        fn translate(&mut self, virt_addr: usize) -> Result<PhysAddr, AddressSpaceError> {
+            let page = virt_addr - virt_addr % PAGE;
+            let phys = self.mapped.lock().unwrap().get(&page).copied();
+            phys.map(|phys| phys + virt_addr % PAGE)
+                .ok_or(AddressSpaceError)
+        }
+    }
+
+    /// A Heap over a real region of host memory, which tracks the frames and pages it uses
+    pub(super) struct TestHeap {
+        pub(super) heap: Heap,
+        frames: &'static Frames,
+        pages: &'static Pages,
+        virt_start: usize,
+    }
+
+    impl TestHeap {
+        pub(super) fn new(num_pages: usize) -> Self {
+            let frames: &'static Frames = Box::leak(Box::default());
+            let pages: &'static Pages = Box::leak(Box::default());
+            let buf = vec![0u8; 256].leak();
+            let mut allocator =
+                unsafe { StaticBumpAlloc::new(buf.as_mut_ptr() as usize, buf.len()) };
+            let region = vec![0u8; (num_pages + 1) * PAGE].leak();
+            let virt_start = (region.as_mut_ptr() as usize).next_multiple_of(PAGE);
+            let heap = unsafe {
+                Heap::new(
+                    StaticBox::new(frames, &mut allocator).unwrap(),
+                    StaticBox::new(pages, &mut allocator).unwrap(),
+                    PAGE,
+                    virt_start,
+                    num_pages * PAGE,
+                )
+            }
+            .unwrap();
+
+            Self {
+                heap,
+                frames,
+                pages,
+                virt_start,
+            }
+        }
+
+        fn mapped_pages(&self) -> Vec<usize> {
+            self.pages.mapped.lock().unwrap().keys().copied().collect()
+        }
+
+        fn allocated_frames(&self) -> usize {
+            let allocated = self.frames.allocated.lock().unwrap();
+            assert!(allocated
+                .values()
+                .all(|&(_, owner)| owner == FrameOwner::Heap));
+            allocated.values().map(|&(pages, _)| pages).sum()
+        }
+    }
+
+    #[test]
+    fn rejects_unaligned_region() {
+        let frames: &'static Frames = Box::leak(Box::default());
+        let pages: &'static Pages = Box::leak(Box::default());
+        let buf = vec![0u8; 256].leak();
+        let mut allocator = unsafe { StaticBumpAlloc::new(buf.as_mut_ptr() as usize, buf.len()) };
+        let heap = unsafe {
+            Heap::new(
+                StaticBox::new(frames, &mut allocator).unwrap(),
+                StaticBox::new(pages, &mut allocator).unwrap(),
+                PAGE,
+                PAGE + 8,
+                PAGE,
+            )
+        };
+        assert!(heap.is_err());
+    }
+
+    #[test]
+    fn small_allocations_share_a_slab() {
+        let mut test = TestHeap::new(4);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let first = test.heap.allocate(layout).unwrap();
+        let second = test.heap.allocate(layout).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(first.as_ptr() as usize % 32, 0);
+        assert_eq!(test.mapped_pages(), [test.virt_start]);
+        assert_eq!(test.allocated_frames(), 1);
+        unsafe {
+            first.as_ptr().write_bytes(0xAA, 24);
+            second.as_ptr().write_bytes(0x55, 24);
+        }
+    }
+
+    #[test]
+    fn freed_small_allocations_are_reused() {
+        let mut test = TestHeap::new(4);
+        let layout = Layout::from_size_align(100, 4).unwrap();
+        let first = test.heap.allocate(layout).unwrap();
+        unsafe { test.heap.deallocate(first, layout) };
+
+        assert_eq!(test.heap.allocate(layout).unwrap(), first);
+        assert_eq!(test.allocated_frames(), 1);
+    }
+
+    #[test]
+    fn size_classes_get_slabs_of_their_own() {
+        let mut test = TestHeap::new(4);
+        let small = test.heap.allocate(Layout::new::<u64>()).unwrap();
+        let large = test
+            .heap
+            .allocate(Layout::from_size_align(2000, 8).unwrap())
+            .unwrap();
+
+        assert_ne!(
+            small.as_ptr() as usize / PAGE,
+            large.as_ptr() as usize / PAGE
+        );
+        assert_eq!(large.as_ptr() as usize % 2048, 0);
+        assert_eq!(test.mapped_pages().len(), 2);
+    }
+
+    #[test]
+    fn large_allocations_get_whole_pages() {
+        let mut test = TestHeap::new(8);
+        let layout = Layout::from_size_align(3 * PAGE - 100, 8).unwrap();
+        let block = test.heap.allocate(layout).unwrap();
+        unsafe { block.as_ptr().write_bytes(0xAA, layout.size()) };
+
+        assert_eq!(block.as_ptr() as usize, test.virt_start);
+        assert_eq!(test.mapped_pages().len(), 3);
+        assert_eq!(test.allocated_frames(), 3);
+
+        unsafe { test.heap.deallocate(block, layout) };
+        assert!(test.mapped_pages().is_empty());
+        assert_eq!(test.allocated_frames(), 0);
+    }
+
+    #[test]
+    fn large_allocations_are_aligned() {
+        let mut test = TestHeap::new(16);
+        // Make sure the next free address isn't aligned already
+        test.heap.allocate(Layout::new::<u64>()).unwrap();
+        let layout = Layout::from_size_align(PAGE, 4 * PAGE).unwrap();
+        let block = test.heap.allocate(layout).unwrap();
+
+        assert_eq!((block.as_ptr() as usize) % (4 * PAGE), 0);
+    }
+
+    #[test]
+    fn failed_mappings_give_frames_back() {
+        let mut test = TestHeap::new(4);
+        *test.pages.map_limit.lock().unwrap() = Some(0);
+
+        assert!(test.heap.allocate(Layout::new::<u64>()).is_err());
+        assert!(test
+            .heap
+            .allocate(Layout::from_size_align(2 * PAGE, 8).unwrap())
+            .is_err());
+        assert_eq!(test.allocated_frames(), 0);
+    }
+
+    #[test]
+    fn partially_mapped_blocks_are_unmapped() {
+        let mut test = TestHeap::new(4);
+        *test.pages.map_limit.lock().unwrap() = Some(2);
+
+        assert!(test
+            .heap
+            .allocate(Layout::from_size_align(3 * PAGE, 8).unwrap())
+            .is_err());
+        assert!(test.mapped_pages().is_empty());
+        assert_eq!(test.allocated_frames(), 0);
+    }
+
+    #[test]
+    fn region_runs_out() {
+        let mut test = TestHeap::new(2);
+        let layout = Layout::from_size_align(2 * PAGE, 8).unwrap();
+        test.heap.allocate(layout).unwrap();
+
+        assert!(test.heap.allocate(layout).is_err());
+        assert!(test.heap.allocate(Layout::new::<u64>()).is_err());
+        assert_eq!(test.allocated_frames(), 2);
+    }
+}
@@ -0,0 +1,150 @@
+use core::ptr::NonNull;
+
+/// Bookkeeping node stored at the start of every free object in a SlabCache
+pub struct FreeObject(Option<NonNull<FreeObject>>);
+
+/// A cache of equally sized objects, carved out of whole pages (slabs)
+///
+/// Free objects are kept on an intrusive freelist, so a SlabCache needs no memory of its own beyond
+/// the slabs it is given. Slabs are never returned once they have been added to the cache.
+pub struct SlabCache {
+    object_size: usize,
+    head: Option<NonNull<FreeObject>>,
+    free_count: usize,
+    total_count: usize,
+}
+
+impl SlabCache {
+    /// Creates a new, empty cache for objects of object_size bytes
+    ///
+    /// object_size must be a power of two no smaller than a FreeObject, so that every object carved out
+    /// of a page-aligned slab is naturally aligned to its own size.
+    pub const fn new(object_size: usize) -> Self {
+        assert!(object_size.is_power_of_two());
+        assert!(object_size >= core::mem::size_of::<FreeObject>());
+
+        Self {
+            object_size,
+            head: None,
+            free_count: 0,
+            total_count: 0,
+        }
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free_count
+    }
+
+    pub fn total_count(&self) -> usize {
+        self.total_count
+    }
+
+    /// Pops a free object off the freelist, or returns None if the cache needs another slab
+    pub fn allocate(&mut self) -> Option<NonNull<u8>> {
+        let old_head_ptr = self.head?;
+        // Safety: The only way an object could have made its way onto this freelist is through add_slab
+        // or deallocate, both of which write a valid FreeObject to the start of the object
+        self.head = unsafe { old_head_ptr.as_ref().0 };
+        self.free_count -= 1;
+
+        Some(old_head_ptr.cast())
+    }
+
+    /// Returns an object to the freelist
+    ///
+    /// # Safety
+    /// obj must have been handed out by a previous call to allocate on this same cache, and must not be
+    /// used again after this call.
+    pub unsafe fn deallocate(&mut self, obj: NonNull<u8>) {
+        let new_head_ptr = obj.cast::<FreeObject>();
+        new_head_ptr.write(FreeObject(self.head));
+        self.head = Some(new_head_ptr);
+        self.free_count += 1;
+    }
+
+    /// Carves a new slab into objects and adds every one of them to the freelist
+    ///
+    /// # Safety
+    /// slab_start must be aligned to object_size, and the slab_size bytes starting at slab_start must be
+    /// mapped, writable and owned exclusively by this cache for the rest of the program's lifetime.
+    pub unsafe fn add_slab(&mut self, slab_start: usize, slab_size: usize) {
+        for obj in (slab_start..slab_start + slab_size)
+            .step_by(self.object_size)
+            .take(slab_size / self.object_size)
+        {
+            self.deallocate(NonNull::new_unchecked(obj as *mut u8));
+            self.total_count += 1;
+        }
+    }
+}
+
+// Safety: A SlabCache exclusively owns every slab it has been given, so nothing else can observe the
+// memory its raw pointers refer to
+unsafe impl Send for SlabCache {}
+
+#[cfg(test)]
+mod tests {
+    use super::SlabCache;
+    use std::{collections::BTreeSet, vec};
+
+    const SLAB_SIZE: usize = 0x1000;
+
+    fn new_slab() -> usize {
+        let buf = vec![0u8; 2 * SLAB_SIZE].leak();
+        (buf.as_mut_ptr() as usize).next_multiple_of(SLAB_SIZE)
+    }
+
+    #[test]
+    fn empty_cache_needs_a_slab() {
+        let mut cache = SlabCache::new(64);
+        assert_eq!(cache.allocate(), None);
+        assert_eq!((cache.free_count(), cache.total_count()), (0, 0));
+    }
+
+    #[test]
+    fn slab_is_carved_into_aligned_objects() {
+        let mut cache = SlabCache::new(64);
+        let slab = new_slab();
+        unsafe { cache.add_slab(slab, SLAB_SIZE) };
+        assert_eq!(cache.total_count(), SLAB_SIZE / 64);
+        assert_eq!(cache.free_count(), SLAB_SIZE / 64);
+
+        let mut objects = BTreeSet::new();
+        while let Some(obj) = cache.allocate() {
+            let addr = obj.as_ptr() as usize;
+            assert!((slab..slab + SLAB_SIZE).contains(&addr));
+            assert_eq!(addr % 64, 0);
+            assert!(objects.insert(addr), "Object {:#x} handed out twice", addr);
+        }
+        assert_eq!(objects.len(), SLAB_SIZE / 64);
+        assert_eq!(cache.free_count(), 0);
+        assert_eq!(cache.total_count(), SLAB_SIZE / 64);
+    }
+
+    #[test]
+    fn freed_objects_are_reused() {
+        let mut cache = SlabCache::new(32);
+        unsafe { cache.add_slab(new_slab(), SLAB_SIZE) };
+        let first = cache.allocate().unwrap();
+        let second = cache.allocate().unwrap();
+        assert_ne!(first, second);
+
+        unsafe { cache.deallocate(first) };
+        assert_eq!(cache.free_count(), SLAB_SIZE / 32 - 1);
+        assert_eq!(cache.allocate(), Some(first));
+    }
+
+    #[test]
+    fn partial_objects_are_not_used() {
+        let mut cache = SlabCache::new(2048);
+        // Only one whole object fits
+        unsafe { cache.add_slab(new_slab(), 3000) };
+        assert_eq!(cache.total_count(), 1);
+        assert!(cache.allocate().is_some());
+        assert_eq!(cache.allocate(), None);
+    }
+}
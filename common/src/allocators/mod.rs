@@ -1,9 +1,12 @@
 use crate::util::error::AllocError;
 use core::{alloc::Layout, ptr::NonNull};
 
+pub mod heap;
 pub mod page_frame_allocator;
 pub mod static_box;
 pub mod static_bump;
+pub mod static_frame;
+pub mod static_vec;
 
 /// Allocates memory that will live for the rest of the program's lifetime.
 ///
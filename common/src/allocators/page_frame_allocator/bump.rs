@@ -1,4 +1,7 @@
-use super::FrameAllocator;
+use super::{
+    stats::{FrameOwner, FrameStats},
+    FrameAllocator,
+};
 use crate::{
     concurrency::single_threaded_lock::SingleThreadedLock, memory::PhysAddr,
     util::error::AllocError,
@@ -10,6 +13,7 @@ pub struct BumpPFA {
     end_frame: usize,
     next: usize,
     page_size: usize,
+    stats: FrameStats,
 }
 
 impl BumpPFA {
@@ -27,6 +31,7 @@ impl BumpPFA {
             end_frame,
             next: start_frame,
             page_size,
+            stats: FrameStats::new(page_size, (end_frame - start_frame) / page_size),
         })
     }
 
@@ -34,15 +39,25 @@ impl BumpPFA {
         (self.start_frame, self.next)
     }
 
-    pub fn allocate_contiguous_pages(&mut self, num_pages: usize) -> Result<PhysAddr, AllocError> {
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    pub fn allocate_contiguous_pages(
+        &mut self,
+        num_pages: usize,
+        owner: FrameOwner,
+    ) -> Result<PhysAddr, AllocError> {
         if num_pages == 0 {
             return Err(AllocError);
         }
         if self.next + ((num_pages - 1) * self.page_size) >= self.end_frame {
+            self.stats.record_failure();
             return Err(AllocError);
         }
         let page = self.next;
         self.next += self.page_size * num_pages;
+        self.stats.record_allocation(owner, num_pages);
         Ok(page)
     }
 }
@@ -57,22 +72,31 @@ impl SingleThreadedBumpPFA {
     pub fn allocated_range(&self) -> (usize, usize) {
         self.0.lock().allocated_range()
     }
+
+    pub fn stats(&self) -> FrameStats {
+        self.0.lock().stats()
+    }
 }
 
 unsafe impl<'a> FrameAllocator for &'a SingleThreadedBumpPFA {
-    fn allocate_pages(&self, num_contiguous_pages: usize) -> Result<PhysAddr, AllocError> {
+    fn allocate_pages(
+        &self,
+        num_contiguous_pages: usize,
+        owner: FrameOwner,
+    ) -> Result<PhysAddr, AllocError> {
         self.0
             .lock()
-            .allocate_contiguous_pages(num_contiguous_pages)
+            .allocate_contiguous_pages(num_contiguous_pages, owner)
     }
 
     fn allocate_zeroed_pages(
         &self,
         num_contiguous_pages: usize,
+        owner: FrameOwner,
         translation: fn(usize) -> usize,
     ) -> Result<PhysAddr, AllocError> {
         let mut inner = self.0.lock();
-        let start_phys = inner.allocate_contiguous_pages(num_contiguous_pages)?;
+        let start_phys = inner.allocate_contiguous_pages(num_contiguous_pages, owner)?;
         let start_virt = translation(start_phys);
 
         let slice = unsafe {
@@ -88,7 +112,53 @@ unsafe impl<'a> FrameAllocator for &'a SingleThreadedBumpPFA {
         Ok(start_phys)
     }
 
-    unsafe fn deallocate_pages(&self, _: PhysAddr, _: usize) {
+    unsafe fn deallocate_pages(&self, _: PhysAddr, _: usize, _: FrameOwner) {
         panic!("Attempted to free bump-allocated pages!");
     }
+
+    fn stats(&self) -> FrameStats {
+        (*self).stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BumpPFA;
+    use crate::allocators::page_frame_allocator::stats::FrameOwner;
+
+    const PAGE: usize = 0x1000;
+
+    #[test]
+    fn allocations_are_recorded() {
+        let mut pfa = unsafe { BumpPFA::new(0x10000, 0x10000 + 4 * PAGE, PAGE) }.unwrap();
+        assert_eq!(
+            pfa.allocate_contiguous_pages(1, FrameOwner::PageTable)
+                .unwrap(),
+            0x10000
+        );
+        assert_eq!(
+            pfa.allocate_contiguous_pages(2, FrameOwner::Heap).unwrap(),
+            0x10000 + PAGE
+        );
+
+        let stats = pfa.stats();
+        assert_eq!(stats.total_pages, 4);
+        assert_eq!(stats.used_pages, 3);
+        assert_eq!(stats.pages_owned_by(FrameOwner::PageTable), 1);
+        assert_eq!(stats.pages_owned_by(FrameOwner::Heap), 2);
+        assert_eq!(pfa.allocated_range(), (0x10000, 0x10000 + 3 * PAGE));
+    }
+
+    #[test]
+    fn running_out_is_recorded_as_a_failure() {
+        let mut pfa = unsafe { BumpPFA::new(0x10000, 0x10000 + 2 * PAGE, PAGE) }.unwrap();
+        assert!(pfa.allocate_contiguous_pages(3, FrameOwner::Heap).is_err());
+        assert!(pfa.allocate_contiguous_pages(2, FrameOwner::Heap).is_ok());
+        assert!(pfa.allocate_contiguous_pages(1, FrameOwner::Heap).is_err());
+
+        let stats = pfa.stats();
+        assert_eq!(stats.failed_allocations, 2);
+        assert_eq!(stats.used_pages, 2);
+        assert_eq!(stats.free_pages(), 0);
+    }
 }
@@ -17,32 +17,73 @@ impl FreelistPFA {
         self.free_count
     }
 
-    pub fn allocate_page(&mut self) -> *mut u8 {
+    pub fn is_empty(&self) -> bool {
+        self.free_count == 0
+    }
+
+    /// Takes a page off the freelist, or returns None if there are no free pages left
+    pub fn allocate_page(&mut self) -> Option<*mut u8> {
         // Get the next free page
-        if let Some(old_head_ptr) = self.head {
-            // Update the head with the next available frame in the freelist
-            // Safety: The only way a frame could have made its way onto this freelist is if it was added
-            // via a call to free(), and we ensure the start of a freed frame contains a valid FreelistEntry
-            self.head = unsafe { (*old_head_ptr).0 };
-
-            return old_head_ptr as *mut u8;
-        } else {
-            panic!("Physical page frame allocator ran out of memory to allocate!");
-        }
+        let old_head_ptr = self.head?;
+        // Update the head with the next available frame in the freelist
+        // Safety: The only way a frame could have made its way onto this freelist is if it was added
+        // via a call to free(), and we ensure the start of a freed frame contains a valid FreelistEntry
+        self.head = unsafe { (*old_head_ptr).0 };
+        self.free_count -= 1;
+
+        Some(old_head_ptr as *mut u8)
     }
 
     pub unsafe fn free_page(&mut self, frame: *mut u8) {
         self.free_count += 1;
 
+        // Safety: We rely on the caller to ensure that the passed frame_ptr is indeed a frame
+        // we can safely de-allocate, which means we can arbitrarily write to it. Additionally,
+        // we require the pointer to be at the start of the page frame, for easy access to the freelist
+        // bookkeeping nodes
         let new_head_ptr = frame as *mut FreelistEntry;
-        if let Some(old_head) = self.head {
-            // Safety: We rely on the caller to ensure that the passed frame_ptr is indeed a frame
-            // we can safely de-allocate, which means we can arbitrarily write to it. Additionally,
-            // we require the pointer to be at the start of the page frame, for easy access to the freelist
-            // bookkeeping nodes
-            (*new_head_ptr).0 = Some(old_head);
-        }
+        new_head_ptr.write(FreelistEntry(self.head));
 
         self.head = Some(new_head_ptr);
     }
 }
+
+// Safety: Pages on the freelist belong to the freelist alone, so nothing else can observe the memory its
+// raw pointers refer to
+unsafe impl Send for FreelistPFA {}
+
+#[cfg(test)]
+mod tests {
+    use super::FreelistPFA;
+    use std::vec;
+
+    const PAGE: usize = 0x1000;
+
+    fn new_pages(num_pages: usize) -> usize {
+        let buf = vec![0u8; (num_pages + 1) * PAGE].leak();
+        (buf.as_mut_ptr() as usize).next_multiple_of(PAGE)
+    }
+
+    #[test]
+    fn empty_freelist_has_no_pages() {
+        let mut freelist = FreelistPFA::new();
+        assert!(freelist.is_empty());
+        assert_eq!(freelist.allocate_page(), None);
+    }
+
+    #[test]
+    fn freed_pages_are_reused_last_in_first_out() {
+        let mut freelist = FreelistPFA::new();
+        let pages = new_pages(2);
+        unsafe {
+            freelist.free_page(pages as *mut u8);
+            freelist.free_page((pages + PAGE) as *mut u8);
+        }
+        assert_eq!(freelist.len(), 2);
+
+        assert_eq!(freelist.allocate_page(), Some((pages + PAGE) as *mut u8));
+        assert_eq!(freelist.allocate_page(), Some(pages as *mut u8));
+        assert_eq!(freelist.allocate_page(), None);
+        assert!(freelist.is_empty());
+    }
+}
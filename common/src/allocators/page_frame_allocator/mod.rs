@@ -1,14 +1,29 @@
+use self::stats::{FrameOwner, FrameStats};
 use crate::{memory::PhysAddr, util::error::AllocError};
 
 pub mod bump;
 pub mod freelist;
+pub mod stats;
 
 pub unsafe trait FrameAllocator {
-    fn allocate_pages(&self, num_contiguous_pages: usize) -> Result<PhysAddr, AllocError>;
+    fn allocate_pages(
+        &self,
+        num_contiguous_pages: usize,
+        owner: FrameOwner,
+    ) -> Result<PhysAddr, AllocError>;
     fn allocate_zeroed_pages(
         &self,
         num_contiguous_pages: usize,
+        owner: FrameOwner,
         translation: fn(usize) -> usize,
     ) -> Result<PhysAddr, AllocError>;
-    unsafe fn deallocate_pages(&self, addr: PhysAddr, num_contiguous_pages: usize);
+    /// Frees pages previously allocated with the same owner
+    unsafe fn deallocate_pages(
+        &self,
+        addr: PhysAddr,
+        num_contiguous_pages: usize,
+        owner: FrameOwner,
+    );
+    /// Returns a snapshot of this allocator's usage counters
+    fn stats(&self) -> FrameStats;
 }
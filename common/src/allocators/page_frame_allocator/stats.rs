@@ -0,0 +1,169 @@
+use crate::memory::memory_size::MemorySize;
+use core::fmt::Display;
+
+/// Identifies what a page frame was allocated for, so physical memory usage can be broken down by owner
+#[derive(Clone, Copy, PartialEq)]
+pub enum FrameOwner {
+    PageTable,
+    Heap,
+    Dma,
+    User,
+    Static,
+}
+
+impl FrameOwner {
+    pub const ALL: [FrameOwner; 5] = [
+        FrameOwner::PageTable,
+        FrameOwner::Heap,
+        FrameOwner::Dma,
+        FrameOwner::User,
+        FrameOwner::Static,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            FrameOwner::PageTable => "Page Tables",
+            FrameOwner::Heap => "Heap",
+            FrameOwner::Dma => "DMA",
+            FrameOwner::User => "User",
+            FrameOwner::Static => "Static",
+        }
+    }
+}
+
+/// Usage counters kept by a FrameAllocator
+#[derive(Clone, Copy)]
+pub struct FrameStats {
+    pub page_size: usize,
+    pub total_pages: usize,
+    pub used_pages: usize,
+    pub peak_used_pages: usize,
+    pub failed_allocations: usize,
+    pub owned_pages: [usize; FrameOwner::ALL.len()],
+}
+
+impl FrameStats {
+    pub const fn new(page_size: usize, total_pages: usize) -> Self {
+        Self {
+            page_size,
+            total_pages,
+            used_pages: 0,
+            peak_used_pages: 0,
+            failed_allocations: 0,
+            owned_pages: [0; FrameOwner::ALL.len()],
+        }
+    }
+
+    pub fn free_pages(&self) -> usize {
+        self.total_pages - self.used_pages
+    }
+
+    pub fn pages_owned_by(&self, owner: FrameOwner) -> usize {
+        self.owned_pages[owner as usize]
+    }
+
+    pub fn record_allocation(&mut self, owner: FrameOwner, num_pages: usize) {
+        self.used_pages += num_pages;
+        self.peak_used_pages = self.peak_used_pages.max(self.used_pages);
+        self.owned_pages[owner as usize] += num_pages;
+    }
+
+    pub fn record_deallocation(&mut self, owner: FrameOwner, num_pages: usize) {
+        self.used_pages -= num_pages;
+        self.owned_pages[owner as usize] -= num_pages;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.failed_allocations += 1;
+    }
+}
+
+impl Display for FrameStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "Page size:      {}\n\
+            Total Pages:    {}\n\
+            Free Pages:     {}\n\
+            Used Pages:     {}\n\
+            Peak Pages:     {}\n\
+            Failed Allocs:  {}",
+            MemorySize::new(self.page_size),
+            self.total_pages,
+            self.free_pages(),
+            self.used_pages,
+            self.peak_used_pages,
+            self.failed_allocations
+        )?;
+        for owner in FrameOwner::ALL {
+            let pages = self.pages_owned_by(owner);
+            if pages != 0 {
+                writeln!(
+                    f,
+                    "Owner: {:11} | {:>8} pages | {}",
+                    owner.as_str(),
+                    pages,
+                    MemorySize::new(pages * self.page_size)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameOwner, FrameStats};
+    use std::format;
+
+    #[test]
+    fn allocations_are_counted_per_owner() {
+        let mut stats = FrameStats::new(0x1000, 16);
+        stats.record_allocation(FrameOwner::Heap, 3);
+        stats.record_allocation(FrameOwner::PageTable, 2);
+        stats.record_allocation(FrameOwner::Heap, 1);
+
+        assert_eq!(stats.used_pages, 6);
+        assert_eq!(stats.free_pages(), 10);
+        assert_eq!(stats.pages_owned_by(FrameOwner::Heap), 4);
+        assert_eq!(stats.pages_owned_by(FrameOwner::PageTable), 2);
+        assert_eq!(stats.pages_owned_by(FrameOwner::Dma), 0);
+    }
+
+    #[test]
+    fn peak_survives_deallocation() {
+        let mut stats = FrameStats::new(0x1000, 16);
+        stats.record_allocation(FrameOwner::Heap, 5);
+        stats.record_deallocation(FrameOwner::Heap, 4);
+        stats.record_allocation(FrameOwner::Dma, 2);
+
+        assert_eq!(stats.used_pages, 3);
+        assert_eq!(stats.peak_used_pages, 5);
+        assert_eq!(stats.pages_owned_by(FrameOwner::Heap), 1);
+        assert_eq!(stats.pages_owned_by(FrameOwner::Dma), 2);
+    }
+
+    #[test]
+    fn failures_do_not_use_pages() {
+        let mut stats = FrameStats::new(0x1000, 16);
+        stats.record_failure();
+        stats.record_failure();
+
+        assert_eq!(stats.failed_allocations, 2);
+        assert_eq!(stats.used_pages, 0);
+        assert_eq!(stats.free_pages(), 16);
+    }
+
+    #[test]
+    fn display_lists_only_owners_with_pages() {
+        let mut stats = FrameStats::new(0x1000, 16);
+        stats.record_allocation(FrameOwner::PageTable, 2);
+        let output = format!("{stats}");
+
+        assert!(output.contains("Used Pages:     2\n"));
+        assert!(output.contains("Page Tables"));
+        assert!(!output.contains("Heap"));
+        assert!(output.ends_with('\n'));
+    }
+}
@@ -3,7 +3,7 @@ use core::{
     alloc::Layout,
     marker::Unsize,
     ops::{CoerceUnsized, Deref, DerefMut},
-    ptr::NonNull,
+    ptr::{copy_nonoverlapping, NonNull},
 };
 
 /// A smart pointer that holds exclusive ownership over data that will live statically (until the end of the program)
@@ -32,6 +32,64 @@ impl<T> StaticBox<T> {
     }
 }
 
+impl<T> StaticBox<[T]> {
+    /// Allocates a slice of len elements, initializing each element with the result of calling f with
+    /// its index
+    pub fn from_fn<A: StaticAlloc, F: FnMut(usize) -> T>(
+        len: usize,
+        mut f: F,
+        allocator: &mut A,
+    ) -> Result<Self, AllocError> {
+        let slice_ptr = allocate_slice::<T, A>(len, allocator)?;
+        for idx in 0..len {
+            unsafe {
+                // Safety: idx is within the bounds of the freshly allocated slice, which nothing else can
+                // access yet
+                slice_ptr.as_ptr().cast::<T>().add(idx).write(f(idx));
+            }
+        }
+
+        Ok(Self(slice_ptr))
+    }
+
+    /// Allocates a slice holding a copy of every element of src
+    pub fn from_slice<A: StaticAlloc>(src: &[T], allocator: &mut A) -> Result<Self, AllocError>
+    where
+        T: Clone,
+    {
+        Self::from_fn(src.len(), |idx| src[idx].clone(), allocator)
+    }
+}
+
+impl StaticBox<str> {
+    /// Allocates a copy of src
+    pub fn from_str<A: StaticAlloc>(src: &str, allocator: &mut A) -> Result<Self, AllocError> {
+        let bytes_ptr = allocate_slice::<u8, A>(src.len(), allocator)?;
+        unsafe {
+            // Safety: The allocation is exactly src.len() bytes long and cannot overlap src. The copied bytes
+            // come from a str, so they are valid UTF-8.
+            copy_nonoverlapping(src.as_ptr(), bytes_ptr.as_ptr().cast::<u8>(), src.len());
+            Ok(Self(NonNull::new_unchecked(bytes_ptr.as_ptr() as *mut str)))
+        }
+    }
+}
+
+/// Allocates uninitialized, suitably aligned memory for len values of type T
+fn allocate_slice<T, A: StaticAlloc>(
+    len: usize,
+    allocator: &mut A,
+) -> Result<NonNull<[T]>, AllocError> {
+    let layout = Layout::array::<T>(len).map_err(|_| AllocError)?;
+    let start = if layout.size() == 0 {
+        // Nothing to allocate, so don't waste the allocator's memory on it
+        NonNull::dangling()
+    } else {
+        allocator.allocate_bytes(layout)?.cast::<T>()
+    };
+
+    Ok(NonNull::slice_from_raw_parts(start, len))
+}
+
 impl<T: ?Sized> Deref for StaticBox<T> {
     type Target = T;
 
@@ -50,3 +108,57 @@ impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<StaticBox<U>> for StaticBox
 
 // Safety: StaticBox owns its data exclusively, so this type inherits T's Send status
 unsafe impl<T: ?Sized + Send> Send for StaticBox<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::StaticBox;
+    use crate::allocators::static_bump::StaticBumpAlloc;
+    use std::vec;
+
+    fn new_allocator(size: usize) -> StaticBumpAlloc {
+        let buf = vec![0u8; size].leak();
+        unsafe { StaticBumpAlloc::new(buf.as_mut_ptr() as usize, buf.len()) }
+    }
+
+    #[test]
+    fn from_fn_initializes_every_element() {
+        let mut allocator = new_allocator(64);
+        let slice = StaticBox::from_fn(4, |idx| idx as u32 * 10, &mut allocator).unwrap();
+        assert_eq!(*slice, [0, 10, 20, 30]);
+        assert_eq!(slice.as_ptr() as usize % align_of::<u32>(), 0);
+    }
+
+    #[test]
+    fn from_fn_fails_without_room() {
+        let mut allocator = new_allocator(8);
+        assert!(StaticBox::from_fn(4, |_| 0u64, &mut allocator).is_err());
+    }
+
+    #[test]
+    fn empty_slices_take_no_memory() {
+        let mut allocator = new_allocator(0);
+        let slice = StaticBox::from_slice(&[] as &[u64], &mut allocator).unwrap();
+        assert!(slice.is_empty());
+        let string = StaticBox::from_str("", &mut allocator).unwrap();
+        assert_eq!(&*string, "");
+        assert_eq!(allocator.used(), 0);
+    }
+
+    #[test]
+    fn from_slice_copies() {
+        let mut allocator = new_allocator(64);
+        let src = [1u16, 2, 3];
+        let mut slice = StaticBox::from_slice(&src, &mut allocator).unwrap();
+        slice[0] = 7;
+        assert_eq!(*slice, [7, 2, 3]);
+        assert_eq!(src, [1, 2, 3]);
+    }
+
+    #[test]
+    fn from_str_copies() {
+        let mut allocator = new_allocator(64);
+        let string = StaticBox::from_str("console=ttyS0 ünïcode", &mut allocator).unwrap();
+        assert_eq!(&*string, "console=ttyS0 ünïcode");
+        assert_eq!(allocator.used(), "console=ttyS0 ünïcode".len());
+    }
+}
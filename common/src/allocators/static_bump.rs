@@ -16,8 +16,13 @@ impl StaticBumpAlloc {
         }
     }
 
+    /// Bytes handed out so far, including any padding needed to satisfy alignment
+    pub fn used(&self) -> usize {
+        self.next - self.mem_start
+    }
+
     pub fn remaining(&self) -> usize {
-        self.mem_size - self.next
+        self.mem_start + self.mem_size - self.next
     }
 }
 
@@ -34,7 +39,7 @@ unsafe impl StaticAlloc for StaticBumpAlloc {
         self.next = new_next;
 
         Ok(NonNull::slice_from_raw_parts(
-            NonNull::new(start as *mut u8).unwrap(),
+            NonNull::new(start as *mut u8).ok_or(AllocError)?,
             layout.size(),
         ))
     }
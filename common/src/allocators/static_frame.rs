@@ -0,0 +1,277 @@
+use super::{
+    page_frame_allocator::{stats::FrameOwner, FrameAllocator},
+    static_bump::StaticBumpAlloc,
+    StaticAlloc,
+};
+use crate::{
+    memory::address_space::{AddressSpace, MemoryAttributes},
+    util::error::AllocError,
+};
+use core::{alloc::Layout, ptr::NonNull};
+
+/// A StaticAlloc that grows on demand by requesting more pages from a FrameAllocator
+///
+/// Memory is handed out from the most recently acquired run of pages, exactly like StaticBumpAlloc. Once that
+/// run is exhausted, a new run large enough for the failed request is taken from the frame allocator. Every
+/// run is also mapped into the next free part of a dedicated virtual window in address_space, so the
+/// allocations stay reachable once that address space is activated. Like all StaticAlloc implementations,
+/// nothing is ever given back, including the unused tail of a run that has been left behind.
+pub struct FrameStaticAlloc<A: FrameAllocator, S: AddressSpace> {
+    frame_allocator: A,
+    address_space: S,
+    translation: fn(usize) -> usize,
+    page_size: usize,
+    virt_start: usize,
+    virt_next: usize,
+    virt_end: usize,
+    current: StaticBumpAlloc,
+    used: usize,
+    capacity: usize,
+}
+
+impl<A: FrameAllocator, S: AddressSpace> FrameStaticAlloc<A, S> {
+    /// Constructs a new, empty allocator that maps its pages into the window at virt_start of virt_size bytes
+    ///
+    /// Allocations are returned at translation(phys), so that they are usable in the current environment
+    /// regardless of whether address_space is active yet.
+    ///
+    /// # Safety
+    /// The virtual window must be page aligned and reserved exclusively for this allocator, and translation
+    /// must correctly turn a physical address into one that is currently accessible.
+    pub unsafe fn new(
+        frame_allocator: A,
+        address_space: S,
+        translation: fn(usize) -> usize,
+        page_size: usize,
+        virt_start: usize,
+        virt_size: usize,
+    ) -> Result<Self, AllocError> {
+        if virt_start % page_size != 0 || virt_size % page_size != 0 {
+            return Err(AllocError);
+        }
+
+        Ok(Self {
+            frame_allocator,
+            address_space,
+            translation,
+            page_size,
+            virt_start,
+            virt_next: virt_start,
+            virt_end: virt_start + virt_size,
+            current: StaticBumpAlloc::new(0, 0),
+            used: 0,
+            capacity: 0,
+        })
+    }
+
+    /// Bytes handed out so far, including any padding needed to satisfy alignment
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Bytes taken from the frame allocator so far
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes that can still be allocated before the allocator has to grow
+    pub fn remaining(&self) -> usize {
+        self.current.remaining()
+    }
+
+    /// Returns the (exclusive) range of the virtual window that has been mapped so far
+    pub fn mapped_range(&self) -> (usize, usize) {
+        (self.virt_start, self.virt_next)
+    }
+
+    /// Returns the address at which memory handed out by this allocator can be found once address_space is
+    /// active, or None if addr was not handed out by this allocator
+    pub fn mapped_address(&mut self, addr: usize) -> Option<usize> {
+        let offset = addr % self.page_size;
+        let page = addr - offset;
+        (self.virt_start..self.virt_next)
+            .step_by(self.page_size)
+            .find(|&virt| {
+                self.address_space
+                    .translate(virt)
+                    .is_ok_and(|phys| (self.translation)(phys) == page)
+            })
+            .map(|virt| virt + offset)
+    }
+
+    /// Acquires and maps a new run of pages that is large enough to satisfy layout
+    fn grow(&mut self, layout: Layout) -> Result<(), AllocError> {
+        // Pages are always aligned to at least the page size, so only larger alignments need extra room
+        let padding = if layout.align() > self.page_size {
+            layout.align()
+        } else {
+            0
+        };
+        let num_pages = (layout.size() + padding).div_ceil(self.page_size).max(1);
+        let size = num_pages * self.page_size;
+        if self.virt_next + size > self.virt_end {
+            return Err(AllocError);
+        }
+
+        let phys_start = self
+            .frame_allocator
+            .allocate_pages(num_pages, FrameOwner::Static)?;
+        if !self.address_space.map_range(
+            self.virt_next,
+            phys_start,
+            size,
+            MemoryAttributes::NormalCacheable,
+        ) {
+            unsafe {
+                // Safety: These frames were allocated above and were never made reachable
+                self.frame_allocator
+                    .deallocate_pages(phys_start, num_pages, FrameOwner::Static);
+            }
+            return Err(AllocError);
+        }
+        self.virt_next += size;
+        self.capacity += size;
+
+        // Safety: The frames were just handed to us by the frame allocator, so we have exclusive use of them
+        self.current = unsafe { StaticBumpAlloc::new((self.translation)(phys_start), size) };
+        Ok(())
+    }
+}
+
+unsafe impl<A: FrameAllocator, S: AddressSpace> StaticAlloc for FrameStaticAlloc<A, S> {
+    fn allocate_bytes(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let used_before = self.current.used();
+        match self.current.allocate_bytes(layout) {
+            Ok(block) => {
+                self.used += self.current.used() - used_before;
+                Ok(block)
+            }
+            Err(_) => {
+                self.grow(layout)?;
+                let block = self.current.allocate_bytes(layout)?;
+                self.used += self.current.used();
+                Ok(block)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameStaticAlloc;
+    use crate::allocators::{
+        heap::tests::{Frames, Pages, PAGE, PHYS_BASE},
+        page_frame_allocator::stats::FrameOwner,
+        StaticAlloc,
+    };
+    use core::alloc::Layout;
+    use std::{boxed::Box, vec::Vec};
+
+    const VIRT_BASE: usize = 0x8000_0000;
+
+    type TestAlloc = FrameStaticAlloc<&'static Frames, &'static Pages>;
+
+    fn new_alloc(window_pages: usize) -> (TestAlloc, &'static Frames, &'static Pages) {
+        let frames: &'static Frames = Box::leak(Box::default());
+        let pages: &'static Pages = Box::leak(Box::default());
+        let alloc = unsafe {
+            FrameStaticAlloc::new(
+                frames,
+                pages,
+                |phys| phys,
+                PAGE,
+                VIRT_BASE,
+                window_pages * PAGE,
+            )
+        }
+        .unwrap();
+        (alloc, frames, pages)
+    }
+
+    fn allocated_runs(frames: &Frames) -> Vec<(usize, usize)> {
+        let allocated = frames.allocated.lock().unwrap();
+        assert!(allocated
+            .values()
+            .all(|&(_, owner)| owner == FrameOwner::Static));
+        allocated
+            .iter()
+            .map(|(&addr, &(pages, _))| (addr, pages))
+            .collect()
+    }
+
+    fn allocate(alloc: &mut TestAlloc, size: usize, align: usize) -> usize {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        alloc.allocate_bytes(layout).unwrap().as_ptr() as *mut u8 as usize
+    }
+
+    #[test]
+    fn grows_into_a_new_frame() {
+        let (mut alloc, frames, _) = new_alloc(4);
+        assert_eq!(alloc.capacity(), 0);
+
+        assert_eq!(allocate(&mut alloc, 100, 8), PHYS_BASE);
+        assert_eq!(allocated_runs(frames), [(PHYS_BASE, 1)]);
+        assert_eq!(alloc.mapped_range(), (VIRT_BASE, VIRT_BASE + PAGE));
+
+        // Doesn't fit in what's left of the first frame
+        assert_eq!(allocate(&mut alloc, PAGE - 50, 8), PHYS_BASE + PAGE);
+        assert_eq!(
+            allocated_runs(frames),
+            [(PHYS_BASE, 1), (PHYS_BASE + PAGE, 1)]
+        );
+        assert_eq!(alloc.mapped_range(), (VIRT_BASE, VIRT_BASE + 2 * PAGE));
+        assert_eq!(alloc.remaining(), 50);
+    }
+
+    #[test]
+    fn tracks_used_and_capacity() {
+        let (mut alloc, _, _) = new_alloc(8);
+        allocate(&mut alloc, 8, 8);
+        allocate(&mut alloc, 1, 1);
+        // Needs 3 bytes of padding
+        allocate(&mut alloc, 4, 4);
+        assert_eq!(alloc.used(), 16);
+        assert_eq!(alloc.capacity(), PAGE);
+
+        // Too large for a single page, so a run of three pages is taken
+        allocate(&mut alloc, 2 * PAGE + 1, 8);
+        assert_eq!(alloc.used(), 16 + 2 * PAGE + 1);
+        assert_eq!(alloc.capacity(), 4 * PAGE);
+        assert_eq!(alloc.remaining(), PAGE - 1);
+    }
+
+    #[test]
+    fn fails_once_the_window_is_used_up() {
+        let (mut alloc, frames, pages) = new_alloc(2);
+        allocate(&mut alloc, 2 * PAGE, 8);
+
+        let layout = Layout::new::<u8>();
+        assert!(alloc.allocate_bytes(layout).is_err());
+        assert_eq!(allocated_runs(frames), [(PHYS_BASE, 2)]);
+        assert_eq!(pages.mapped.lock().unwrap().len(), 2);
+        assert_eq!(alloc.capacity(), 2 * PAGE);
+    }
+
+    #[test]
+    fn failed_mappings_give_frames_back() {
+        let (mut alloc, frames, pages) = new_alloc(4);
+        *pages.map_limit.lock().unwrap() = Some(0);
+
+        assert!(alloc.allocate_bytes(Layout::new::<u64>()).is_err());
+        assert!(allocated_runs(frames).is_empty());
+        assert_eq!(alloc.capacity(), 0);
+        assert_eq!(alloc.mapped_range(), (VIRT_BASE, VIRT_BASE));
+    }
+
+    #[test]
+    fn finds_mapped_addresses() {
+        let (mut alloc, _, _) = new_alloc(4);
+        let first = allocate(&mut alloc, 16, 8);
+        let second = allocate(&mut alloc, PAGE, 8);
+
+        assert_eq!(alloc.mapped_address(first), Some(VIRT_BASE));
+        assert_eq!(alloc.mapped_address(first + 8), Some(VIRT_BASE + 8));
+        assert_eq!(alloc.mapped_address(second), Some(VIRT_BASE + PAGE));
+        assert_eq!(alloc.mapped_address(PHYS_BASE + 8 * PAGE), None);
+    }
+}
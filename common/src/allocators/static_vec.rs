@@ -0,0 +1,297 @@
+use super::{static_box::StaticBox, StaticAlloc};
+use crate::util::error::{AllocError, CapacityError};
+use core::{
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    ptr::{drop_in_place, slice_from_raw_parts_mut},
+    slice::{from_raw_parts, from_raw_parts_mut},
+};
+
+/// A vector with a fixed capacity that is chosen at runtime, backed by a StaticAlloc
+///
+/// Think of this as an ArrayVec whose capacity need not be known at compile time. The backing storage is
+/// allocated once, when the StaticVec is created, and can never grow or be freed, so pushing to a full
+/// StaticVec fails instead of reallocating.
+pub struct StaticVec<T> {
+    buf: StaticBox<[MaybeUninit<T>]>,
+    len: usize,
+}
+
+impl<T> StaticVec<T> {
+    /// Allocates storage for exactly capacity elements
+    pub fn with_capacity<A: StaticAlloc>(
+        capacity: usize,
+        allocator: &mut A,
+    ) -> Result<Self, AllocError> {
+        Ok(Self {
+            buf: StaticBox::from_fn(capacity, |_| MaybeUninit::uninit(), allocator)?,
+            len: 0,
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.capacity()
+    }
+
+    /// Appends val to the end of the vector, or returns a CapacityError if the vector is full
+    pub fn try_push(&mut self, val: T) -> Result<(), CapacityError> {
+        if self.is_full() {
+            return Err(CapacityError);
+        }
+        self.buf[self.len].write(val);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Removes and returns the last element, if there is one
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+
+        // Safety: Every element below the old len was initialized, and dropping len means it will never be
+        // read again
+        Some(unsafe { self.buf[self.len].assume_init_read() })
+    }
+
+    /// Inserts val at idx, shifting every element after it one place to the right
+    pub fn try_insert(&mut self, idx: usize, val: T) -> Result<(), CapacityError> {
+        assert!(idx <= self.len, "Insertion index out of bounds");
+        if self.is_full() {
+            return Err(CapacityError);
+        }
+
+        self.buf[idx..=self.len].rotate_right(1);
+        self.buf[idx].write(val);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Removes and returns the element at idx, shifting every element after it one place to the left
+    pub fn remove(&mut self, idx: usize) -> T {
+        assert!(idx < self.len, "Removal index out of bounds");
+
+        self.buf[idx..self.len].rotate_left(1);
+        self.pop().unwrap()
+    }
+
+    /// Keeps only the elements for which f returns true, preserving their order
+    ///
+    /// If f panics, the element it panicked on and every element after it are kept.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        /// Closes the gap left by removed elements once retain is done, or once f panics
+        struct Guard<'a, T> {
+            vec: &'a mut StaticVec<T>,
+            len: usize,
+            processed: usize,
+            kept: usize,
+        }
+
+        impl<T> Drop for Guard<'_, T> {
+            fn drop(&mut self) {
+                // Everything between kept and processed was either dropped or moved to the front
+                self.vec.buf[self.kept..self.len].rotate_left(self.processed - self.kept);
+                self.vec.len = self.kept + self.len - self.processed;
+            }
+        }
+
+        // Until the guard restores it, a panic must not leave dropped elements reachable
+        let len = self.len;
+        self.len = 0;
+        let mut guard = Guard {
+            vec: self,
+            len,
+            processed: 0,
+            kept: 0,
+        };
+        while guard.processed < guard.len {
+            let idx = guard.processed;
+            // Safety: Every element from processed up to the original len is initialized
+            let keep = f(unsafe { guard.vec.buf[idx].assume_init_ref() });
+            guard.processed += 1;
+            if keep {
+                guard.vec.buf.swap(guard.kept, idx);
+                guard.kept += 1;
+            } else {
+                // Safety: The element is initialized, and it is forgotten about once dropped since the guard
+                // moves it out of the kept range
+                unsafe { guard.vec.buf[idx].assume_init_drop() };
+            }
+        }
+    }
+
+    /// Appends a copy of every element of src, or returns a CapacityError without appending anything if
+    /// there is not enough room for all of them
+    pub fn try_extend_from_slice(&mut self, src: &[T]) -> Result<(), CapacityError>
+    where
+        T: Clone,
+    {
+        if self.capacity() - self.len < src.len() {
+            return Err(CapacityError);
+        }
+        for val in src {
+            self.try_push(val.clone())?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops every element, leaving the vector empty
+    pub fn clear(&mut self) {
+        let len = self.len;
+        self.len = 0;
+        unsafe {
+            // Safety: The first len elements were initialized, and can no longer be reached
+            drop_in_place(slice_from_raw_parts_mut(
+                self.buf.as_mut_ptr() as *mut T,
+                len,
+            ));
+        }
+    }
+}
+
+impl<T> Deref for StaticVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: Every element below len is initialized
+        unsafe { from_raw_parts(self.buf.as_ptr() as *const T, self.len) }
+    }
+}
+
+impl<T> DerefMut for StaticVec<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: Every element below len is initialized
+        unsafe { from_raw_parts_mut(self.buf.as_mut_ptr() as *mut T, self.len) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StaticVec;
+    use crate::allocators::static_bump::StaticBumpAlloc;
+    use std::{cell::Cell, panic::catch_unwind, panic::AssertUnwindSafe, rc::Rc, vec};
+
+    fn new_vec<T>(capacity: usize) -> StaticVec<T> {
+        let buf = vec![0u8; capacity * size_of::<T>() + 64].leak();
+        let mut allocator = unsafe { StaticBumpAlloc::new(buf.as_mut_ptr() as usize, buf.len()) };
+        StaticVec::with_capacity(capacity, &mut allocator).unwrap()
+    }
+
+    /// Counts how many times it has been dropped
+    struct Droppable(Rc<Cell<usize>>, usize);
+
+    impl Drop for Droppable {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn push_until_full() {
+        let mut vec = new_vec(3);
+        for val in 0..3 {
+            vec.try_push(val).unwrap();
+        }
+
+        assert!(vec.is_full());
+        assert!(vec.try_push(3).is_err());
+        assert_eq!(*vec, [0, 1, 2]);
+        assert_eq!(vec.pop(), Some(2));
+        assert_eq!(vec.capacity(), 3);
+    }
+
+    #[test]
+    fn pop_empty() {
+        let mut vec = new_vec::<u32>(2);
+        assert_eq!(vec.pop(), None);
+    }
+
+    #[test]
+    fn insert_and_remove_shift_elements() {
+        let mut vec = new_vec(4);
+        vec.try_extend_from_slice(&[1, 3]).unwrap();
+        vec.try_insert(1, 2).unwrap();
+        vec.try_insert(0, 0).unwrap();
+        assert_eq!(*vec, [0, 1, 2, 3]);
+        assert!(vec.try_insert(4, 4).is_err());
+
+        assert_eq!(vec.remove(1), 1);
+        assert_eq!(*vec, [0, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn insert_past_end() {
+        let mut vec = new_vec(4);
+        vec.try_insert(1, 0).unwrap();
+    }
+
+    #[test]
+    fn extend_is_all_or_nothing() {
+        let mut vec = new_vec(3);
+        vec.try_push(0).unwrap();
+        assert!(vec.try_extend_from_slice(&[1, 2, 3]).is_err());
+        assert_eq!(*vec, [0]);
+    }
+
+    #[test]
+    fn retain_preserves_order() {
+        let mut vec = new_vec(6);
+        vec.try_extend_from_slice(&[0, 1, 2, 3, 4, 5]).unwrap();
+        vec.retain(|val| val % 2 == 1);
+        assert_eq!(*vec, [1, 3, 5]);
+    }
+
+    #[test]
+    fn retain_and_clear_drop_elements_once() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = new_vec(4);
+        for idx in 0..4 {
+            vec.try_push(Droppable(drops.clone(), idx)).ok().unwrap();
+        }
+
+        vec.retain(|val| val.1 != 2);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(
+            vec.iter().map(|val| val.1).collect::<vec::Vec<_>>(),
+            [0, 1, 3]
+        );
+        vec.clear();
+        assert_eq!(drops.get(), 4);
+        assert!(vec.is_empty());
+    }
+
+    #[test]
+    fn retain_survives_panic() {
+        let drops = Rc::new(Cell::new(0));
+        let mut vec = new_vec(5);
+        for idx in 0..5 {
+            vec.try_push(Droppable(drops.clone(), idx)).ok().unwrap();
+        }
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            vec.retain(|val| match val.1 {
+                1 => false,
+                3 => panic!("Oops"),
+                _ => true,
+            })
+        }));
+        assert!(result.is_err());
+        // Everything from the panic on is kept
+        assert_eq!(drops.get(), 1);
+        assert_eq!(
+            vec.iter().map(|val| val.1).collect::<vec::Vec<_>>(),
+            [0, 2, 3, 4]
+        );
+        vec.clear();
+        assert_eq!(drops.get(), 5);
+    }
+}
@@ -1,4 +1,4 @@
-use common::memory::address_space::MemoryAttributes;
+use crate::memory::address_space::MemoryAttributes;
 
 pub fn translate_memory_attrib(attr: MemoryAttributes) -> u8 {
     match attr {
@@ -0,0 +1,2 @@
+pub mod memory_attribute;
+pub mod page_table;
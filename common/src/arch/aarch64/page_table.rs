@@ -1,6 +1,5 @@
-use bitfield::BitRange;
-use common::{
-    allocators::page_frame_allocator::FrameAllocator,
+use crate::{
+    allocators::page_frame_allocator::{stats::FrameOwner, FrameAllocator},
     memory::{
         address_space::{AddressSpace, MemoryAttributes},
         PhysAddr,
@@ -8,6 +7,7 @@ use common::{
     read_linker_var,
     util::{error::AddressSpaceError, linker_variables::__PG_SIZE},
 };
+use bitfield::BitRange;
 use core::slice::from_raw_parts_mut;
 use tock_registers::{
     interfaces::{ReadWriteable, Readable},
@@ -18,7 +18,6 @@ use tock_registers::{
 use super::memory_attribute::translate_memory_attrib;
 
 const SIZE_4KIB: u64 = 4096;
-const SIZE_2MIB: u64 = 2 * 1024 * 1024;
 const SIZE_1GIB: u64 = 1024 * 1024 * 1024;
 
 register_bitfields!(
@@ -79,8 +78,13 @@ type TableDescriptor = InMemoryRegister<u64, TABLE::Register>;
 type BlockDescriptor = InMemoryRegister<u64, BLOCK::Register>;
 type PageDescriptor = InMemoryRegister<u64, PAGEENTRY4KIB::Register>;
 
+/// A 4 level translation table, as walked by the MMU from TTBR0_EL1 or TTBR1_EL1
+///
+/// Every table is reached through address_translation, which turns the physical address of a table into
+/// one we can access. Before the MMU is on that is the identity, afterwards it is usually the linear map.
 pub struct PageTable<'a, A: FrameAllocator> {
     lvl0_table: &'a mut [u64],
+    lvl0_table_phys: PhysAddr,
     address_translation: fn(usize) -> usize,
     frame_allocator: A,
 }
@@ -99,20 +103,42 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
             return Err(AddressSpaceError);
         }
 
-        let lvl0_table_phys_ptr = frame_allocator
-            .allocate_zeroed_pages(1, address_translation)
-            .map_err(|_| AddressSpaceError)? as *mut u64;
-        let lvl0_table = from_raw_parts_mut(lvl0_table_phys_ptr, 4096 / 8);
+        let lvl0_table_phys = frame_allocator
+            .allocate_zeroed_pages(1, FrameOwner::PageTable, address_translation)
+            .map_err(|_| AddressSpaceError)?;
+
+        Ok(Self::from_raw(
+            lvl0_table_phys,
+            address_translation,
+            frame_allocator,
+        ))
+    }
+
+    /// Takes over an existing page table, such as the one the bootloader built for the kernel
+    ///
+    /// # Safety
+    /// lvl0_table_phys must be the physical address of a valid lvl0 table, which nothing else modifies from
+    /// now on. Any table created from here on is allocated from frame_allocator, and address_translation
+    /// must be correct for every table, old and new.
+    pub unsafe fn from_raw(
+        lvl0_table_phys: PhysAddr,
+        address_translation: fn(usize) -> usize,
+        frame_allocator: A,
+    ) -> Self {
+        let lvl0_table_ptr = address_translation(lvl0_table_phys) as *mut u64;
+        let lvl0_table = from_raw_parts_mut(lvl0_table_ptr, 4096 / 8);
 
-        Ok(Self {
+        Self {
             lvl0_table,
+            lvl0_table_phys,
             address_translation,
             frame_allocator,
-        })
+        }
     }
 
-    pub fn as_raw(&mut self) -> *mut u64 {
-        self.lvl0_table.as_mut_ptr()
+    /// Returns the physical address of the lvl0 table, to be loaded into a TTBR
+    pub fn root_phys(&self) -> PhysAddr {
+        self.lvl0_table_phys
     }
 
     // Unsafe because bad things will happen if the address translation function is not correct
@@ -128,7 +154,9 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
             return Err(AddressSpaceError);
         }
 
-        let lvl1_table_ptr = (lvl0_descriptor.read(TABLE::NEXT_ADDR) << 12) as *mut u64;
+        let lvl1_table_ptr =
+            (self.address_translation)((lvl0_descriptor.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
         // Safe because a valid lvl 0 descriptor guaruntees a valid lvl1 table
         let lvl1_idx: u64 = virt_addr.bit_range(38, 30);
         let lvl1_table = unsafe { from_raw_parts_mut(lvl1_table_ptr, 4096 / 8) };
@@ -143,8 +171,33 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
         }
 
         // Must be a table pointer to a lvl 2...
-        todo!();
-        return Err(AddressSpaceError);
+        let lvl1_entry = TableDescriptor::new(lvl1_entry.get());
+        let lvl2_table_ptr =
+            (self.address_translation)((lvl1_entry.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
+        let lvl2_idx: u64 = virt_addr.bit_range(29, 21);
+        let lvl2_table = unsafe { from_raw_parts_mut(lvl2_table_ptr, 4096 / 8) };
+        let lvl2_entry = TableDescriptor::new(lvl2_table[lvl2_idx as usize]);
+        if !lvl2_entry.is_set(TABLE::VALID) {
+            return Err(AddressSpaceError);
+        } else if !lvl2_entry.is_set(TABLE::TABLE) {
+            // TODO: We never create 2MiB page entries yet
+            return Err(AddressSpaceError);
+        }
+
+        // ...which must point to a lvl 3 table of 4KiB pages
+        let lvl3_table_ptr =
+            (self.address_translation)((lvl2_entry.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
+        let lvl3_idx: u64 = virt_addr.bit_range(20, 12);
+        let lvl3_table = unsafe { from_raw_parts_mut(lvl3_table_ptr, 4096 / 8) };
+        let lvl3_entry = PageDescriptor::new(lvl3_table[lvl3_idx as usize]);
+        if !lvl3_entry.is_set(PAGEENTRY4KIB::VALID) {
+            return Err(AddressSpaceError);
+        }
+        let page_phys_start = lvl3_entry.read(PAGEENTRY4KIB::OUT_ADDR) << 12;
+        let phys_lower_bits: u64 = virt_addr.bit_range(11, 0);
+        Ok((page_phys_start | phys_lower_bits).try_into().unwrap())
     }
 
     pub fn map_1gib_page(
@@ -163,7 +216,7 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
         if !lvl0_descriptor.is_set(TABLE::VALID) {
             let page_phys_addr = self
                 .frame_allocator
-                .allocate_zeroed_pages(1, self.address_translation)
+                .allocate_zeroed_pages(1, FrameOwner::PageTable, self.address_translation)
                 .unwrap() as u64;
             lvl0_descriptor.modify(TABLE::VALID::SET);
             lvl0_descriptor.modify(TABLE::TABLE::SET);
@@ -173,7 +226,9 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
             self.lvl0_table[lvl0_idx as usize] = lvl0_descriptor.get();
         }
 
-        let lvl1_table_ptr = (lvl0_descriptor.read(TABLE::NEXT_ADDR) << 12) as *mut u64;
+        let lvl1_table_ptr =
+            (self.address_translation)((lvl0_descriptor.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
         // Safe because a valid lvl 0 descriptor guaruntees a valid lvl1 table
         let lvl1_idx: u64 = virt_start.bit_range(38, 30);
         let lvl1_table = unsafe { from_raw_parts_mut(lvl1_table_ptr, 4096 / 8) };
@@ -192,10 +247,6 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
         true
     }
 
-    pub fn map_2mib_page(&mut self, virt_start: u64, phys_start: u64) -> bool {
-        todo!()
-    }
-
     pub fn map_4kib_page(
         &mut self,
         virt_start: u64,
@@ -212,7 +263,7 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
         if !lvl0_descriptor.is_set(TABLE::VALID) {
             let page_phys_addr = self
                 .frame_allocator
-                .allocate_zeroed_pages(1, self.address_translation)
+                .allocate_zeroed_pages(1, FrameOwner::PageTable, self.address_translation)
                 .unwrap() as u64;
             lvl0_descriptor.modify(TABLE::VALID::SET);
             lvl0_descriptor.modify(TABLE::TABLE::SET);
@@ -223,7 +274,9 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
         }
 
         // Get (or optionally create) the lvl1 descriptor entry
-        let lvl1_table_ptr = (lvl0_descriptor.read(TABLE::NEXT_ADDR) << 12) as *mut u64;
+        let lvl1_table_ptr =
+            (self.address_translation)((lvl0_descriptor.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
         // Safe because a valid lvl 0 descriptor guaruntees a valid lvl1 table
         let lvl1_idx: u64 = virt_start.bit_range(38, 30);
         let lvl1_table = unsafe { from_raw_parts_mut(lvl1_table_ptr, 4096 / 8) };
@@ -231,7 +284,7 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
         if !lvl1_entry.is_set(TABLE::VALID) {
             let page_phys_addr = self
                 .frame_allocator
-                .allocate_zeroed_pages(1, self.address_translation)
+                .allocate_zeroed_pages(1, FrameOwner::PageTable, self.address_translation)
                 .unwrap() as u64;
             lvl1_entry.modify(TABLE::VALID::SET);
             lvl1_entry.modify(TABLE::TABLE::SET);
@@ -244,14 +297,16 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
         }
 
         // Get (or optionally create) the lvl2 descriptor entry
-        let lvl2_table_ptr = (lvl1_entry.read(TABLE::NEXT_ADDR) << 12) as *mut u64;
+        let lvl2_table_ptr =
+            (self.address_translation)((lvl1_entry.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
         let lvl2_idx: u64 = virt_start.bit_range(29, 21);
         let lvl2_table = unsafe { from_raw_parts_mut(lvl2_table_ptr, 4096 / 8) };
         let lvl2_entry = TableDescriptor::new(lvl2_table[lvl2_idx as usize]);
         if !lvl2_entry.is_set(TABLE::VALID) {
             let page_phys_addr = self
                 .frame_allocator
-                .allocate_zeroed_pages(1, self.address_translation)
+                .allocate_zeroed_pages(1, FrameOwner::PageTable, self.address_translation)
                 .unwrap() as u64;
             lvl2_entry.modify(TABLE::VALID::SET);
             lvl2_entry.modify(TABLE::TABLE::SET);
@@ -264,7 +319,9 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
         }
 
         // Create the lvl3 descriptor entry
-        let lvl3_table_ptr = (lvl2_entry.read(TABLE::NEXT_ADDR) << 12) as *mut u64;
+        let lvl3_table_ptr =
+            (self.address_translation)((lvl2_entry.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
         let lvl3_idx: u64 = virt_start.bit_range(20, 12);
         let lvl3_table = unsafe { from_raw_parts_mut(lvl3_table_ptr, 4096 / 8) };
         let lvl3_entry = PageDescriptor::new(lvl3_table[lvl3_idx as usize]);
@@ -282,16 +339,56 @@ impl<'a, A: FrameAllocator> PageTable<'a, A> {
         true
     }
 
-    pub fn unmap_1gib_page(&mut self, virt_start: u64, phys_start: u64) -> bool {
-        todo!()
-    }
+    /// Unmaps a single 4KiB page, which must currently be mapped to phys_start
+    pub fn unmap_4kib_page(&mut self, virt_start: u64, phys_start: u64) -> bool {
+        if virt_start % SIZE_4KIB != 0 || phys_start % SIZE_4KIB != 0 {
+            return false;
+        }
 
-    pub fn unmap_2mib_page(&mut self, virt_start: u64, phys_start: u64) -> bool {
-        todo!()
-    }
+        let lvl0_idx: u64 = virt_start.bit_range(47, 39);
+        let lvl0_descriptor = TableDescriptor::new(self.lvl0_table[lvl0_idx as usize]);
+        if !lvl0_descriptor.is_set(TABLE::VALID) {
+            return false;
+        }
 
-    pub fn unmap_4kib_page(&mut self, virt_start: u64, phys_start: u64) -> bool {
-        todo!()
+        let lvl1_table_ptr =
+            (self.address_translation)((lvl0_descriptor.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
+        // Safe because a valid lvl 0 descriptor guaruntees a valid lvl1 table
+        let lvl1_idx: u64 = virt_start.bit_range(38, 30);
+        let lvl1_table = unsafe { from_raw_parts_mut(lvl1_table_ptr, 4096 / 8) };
+        let lvl1_entry = TableDescriptor::new(lvl1_table[lvl1_idx as usize]);
+        // A 1GiB page can't be unmapped one 4KiB page at a time
+        if !lvl1_entry.is_set(TABLE::VALID) || !lvl1_entry.is_set(TABLE::TABLE) {
+            return false;
+        }
+
+        let lvl2_table_ptr =
+            (self.address_translation)((lvl1_entry.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
+        let lvl2_idx: u64 = virt_start.bit_range(29, 21);
+        let lvl2_table = unsafe { from_raw_parts_mut(lvl2_table_ptr, 4096 / 8) };
+        let lvl2_entry = TableDescriptor::new(lvl2_table[lvl2_idx as usize]);
+        if !lvl2_entry.is_set(TABLE::VALID) || !lvl2_entry.is_set(TABLE::TABLE) {
+            return false;
+        }
+
+        let lvl3_table_ptr =
+            (self.address_translation)((lvl2_entry.read(TABLE::NEXT_ADDR) << 12) as usize)
+                as *mut u64;
+        let lvl3_idx: u64 = virt_start.bit_range(20, 12);
+        let lvl3_table = unsafe { from_raw_parts_mut(lvl3_table_ptr, 4096 / 8) };
+        let lvl3_entry = PageDescriptor::new(lvl3_table[lvl3_idx as usize]);
+        if !lvl3_entry.is_set(PAGEENTRY4KIB::VALID)
+            || lvl3_entry.read(PAGEENTRY4KIB::OUT_ADDR) << 12 != phys_start
+        {
+            return false;
+        }
+        // TODO: Free tables once nothing is mapped through them anymore
+        lvl3_table[lvl3_idx as usize] = 0;
+        invalidate_tlb_entry(virt_start);
+
+        true
     }
 }
 
@@ -307,14 +404,72 @@ impl<'a, A: FrameAllocator> AddressSpace for PageTable<'a, A> {
         size: usize,
         attr: MemoryAttributes,
     ) -> bool {
-        todo!()
+        // TODO: Use larger pages where the range allows it
+        let page_size = SIZE_4KIB as usize;
+        if virt_start % page_size != 0 || phys_start % page_size != 0 || size % page_size != 0 {
+            return false;
+        }
+
+        for offset in (0..size).step_by(page_size) {
+            if !self.map_4kib_page(
+                (virt_start + offset) as u64,
+                (phys_start + offset) as u64,
+                attr,
+            ) {
+                return false;
+            }
+        }
+        // The range may be used as soon as we return
+        sync_tables();
+
+        true
     }
 
     fn unmap_range(&mut self, virt_start: usize, phys_start: usize, size: usize) -> bool {
-        todo!()
+        let page_size = SIZE_4KIB as usize;
+        if virt_start % page_size != 0 || phys_start % page_size != 0 || size % page_size != 0 {
+            return false;
+        }
+
+        for offset in (0..size).step_by(page_size) {
+            if !self.unmap_4kib_page((virt_start + offset) as u64, (phys_start + offset) as u64) {
+                return false;
+            }
+        }
+
+        true
     }
 
     fn translate(&mut self, virt_addr: usize) -> Result<PhysAddr, AddressSpaceError> {
         self.virt_to_phys(virt_addr.try_into().unwrap())
     }
 }
+
+/// Waits for every write to the tables to become visible to the table walker
+fn sync_tables() {
+    #[cfg(target_arch = "aarch64")]
+    // Safety: Barriers have no effect beyond ordering memory accesses
+    unsafe {
+        core::arch::asm!("dsb ishst", "isb", options(nostack))
+    };
+}
+
+/// Drops any translation of the page at virt_addr the TLBs of any core may still hold, once the write that
+/// unmapped it is visible to the table walker
+fn invalidate_tlb_entry(virt_addr: u64) {
+    // The operand holds bits 55:12 of the address in its low 44 bits
+    #[cfg(target_arch = "aarch64")]
+    // Safety: Invalidating TLB entries never has an effect beyond translations being walked again
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) (virt_addr >> 12) & 0xFFF_FFFF_FFFF,
+            options(nostack)
+        )
+    };
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = virt_addr;
+}
@@ -3,6 +3,8 @@ use crate::{
     util::error::AddressSpaceError,
 };
 
+pub mod aarch64;
+
 pub trait Arch {
     unsafe fn new_address_space<A: FrameAllocator>(
         translation: fn(usize) -> usize,
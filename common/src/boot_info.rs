@@ -0,0 +1,226 @@
+//! The handoff structure the bootloader passes to the kernel
+//!
+//! The bootloader places a BootInfo, and everything it points to, in memory that stays mapped in the
+//! kernel's address space, and passes the virtual address of the BootInfo to the kernel entry point in x0.
+//! All pointers inside a BootInfo are kernel virtual addresses. Physical addresses are stored as plain
+//! usizes, and can be reached through the linear map at linear_map_base + phys.
+//!
+//! The layout is #[repr(C)] so that it only changes when we change it. Any change to the layout must bump
+//! BOOT_INFO_VERSION, so that a kernel never misreads a BootInfo from a mismatched bootloader.
+
+use crate::{
+    device_tree::cpus::Cpu, memory::memory_map::MemoryMapEntry, util::error::BootInfoError,
+};
+use core::{
+    mem::{align_of, size_of},
+    ptr::null,
+    slice::from_raw_parts,
+    str::from_utf8,
+};
+
+/// "LANTERNB" in ASCII
+pub const BOOT_INFO_MAGIC: u64 = 0x4C414E5445524E42;
+pub const BOOT_INFO_VERSION: u32 = 4;
+
+#[repr(C)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    /// Size in bytes of this structure, as built by the bootloader
+    pub size: u32,
+    pub memory_map: *const MemoryMapEntry,
+    pub memory_map_len: usize,
+    /// The kernel command line, which is UTF-8 but not null terminated
+    pub command_line: *const u8,
+    pub command_line_len: usize,
+    /// The stdout-path of the device tree's /chosen node, which is UTF-8 but not null terminated
+    pub stdout_path: *const u8,
+    pub stdout_path_len: usize,
+    /// Every enabled CPU, including the one the bootloader is running on
+    pub cpus: *const Cpu,
+    pub cpus_len: usize,
+    pub dtb_phys: usize,
+    pub dtb_size: usize,
+    /// Size in bytes of the memory set aside for the device tree blob, which leaves room for the kernel to
+    /// edit it in place (see FdtMut::open)
+    pub dtb_capacity: usize,
+    pub linear_map_base: usize,
+    /// Physical address of the lvl0 table of the kernel's page table, as loaded into TTBR1_EL1
+    pub page_table_phys: usize,
+    pub kernel_phys_start: usize,
+    pub kernel_phys_end: usize,
+    pub kernel_virt_start: usize,
+    pub kernel_virt_end: usize,
+    pub stack_virt_start: usize,
+    pub stack_virt_end: usize,
+}
+
+impl BootInfo {
+    /// Returns a BootInfo with a valid header and everything else empty, for the bootloader to fill in
+    pub const fn new() -> Self {
+        Self {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
+            size: size_of::<Self>() as u32,
+            memory_map: null(),
+            memory_map_len: 0,
+            command_line: null(),
+            command_line_len: 0,
+            stdout_path: null(),
+            stdout_path_len: 0,
+            cpus: null(),
+            cpus_len: 0,
+            dtb_phys: 0,
+            dtb_size: 0,
+            dtb_capacity: 0,
+            linear_map_base: 0,
+            page_table_phys: 0,
+            kernel_phys_start: 0,
+            kernel_phys_end: 0,
+            kernel_virt_start: 0,
+            kernel_virt_end: 0,
+            stack_virt_start: 0,
+            stack_virt_end: 0,
+        }
+    }
+
+    /// Checks that ptr points to a BootInfo this kernel understands, and returns a reference to it
+    ///
+    /// # Safety
+    /// ptr must either be null, or point to readable memory that is at least size_of::<BootInfo>() bytes
+    /// long and that is never modified again.
+    pub unsafe fn from_ptr(ptr: *const BootInfo) -> Result<&'static BootInfo, BootInfoError> {
+        if ptr.is_null() {
+            return Err(BootInfoError::Null);
+        }
+        if ptr as usize % align_of::<BootInfo>() != 0 {
+            return Err(BootInfoError::Misaligned);
+        }
+        let boot_info = &*ptr;
+        if boot_info.magic != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::BadMagic);
+        }
+        if boot_info.version != BOOT_INFO_VERSION || boot_info.size as usize != size_of::<Self>() {
+            return Err(BootInfoError::UnsupportedVersion);
+        }
+        if from_utf8(boot_info.command_line_bytes()).is_err() {
+            return Err(BootInfoError::InvalidCommandLine);
+        }
+        if from_utf8(boot_info.stdout_path_bytes()).is_err() {
+            return Err(BootInfoError::InvalidStdoutPath);
+        }
+
+        Ok(boot_info)
+    }
+
+    pub fn memory_map(&self) -> &[MemoryMapEntry] {
+        if self.memory_map_len == 0 {
+            return &[];
+        }
+        // Safety: The bootloader guarantees the pointer refers to memory_map_len entries that stay mapped
+        unsafe { from_raw_parts(self.memory_map, self.memory_map_len) }
+    }
+
+    pub fn cpus(&self) -> &[Cpu] {
+        if self.cpus_len == 0 {
+            return &[];
+        }
+        // Safety: The bootloader guarantees the pointer refers to cpus_len entries that stay mapped
+        unsafe { from_raw_parts(self.cpus, self.cpus_len) }
+    }
+
+    pub fn command_line(&self) -> &str {
+        from_utf8(self.command_line_bytes()).unwrap_or("")
+    }
+
+    /// The device the firmware suggests using as the console (eg "serial0:115200n8"), or "" if there is none
+    pub fn stdout_path(&self) -> &str {
+        from_utf8(self.stdout_path_bytes()).unwrap_or("")
+    }
+
+    fn command_line_bytes(&self) -> &[u8] {
+        if self.command_line_len == 0 {
+            return &[];
+        }
+        // Safety: The bootloader guarantees the pointer refers to command_line_len bytes that stay mapped
+        unsafe { from_raw_parts(self.command_line, self.command_line_len) }
+    }
+
+    fn stdout_path_bytes(&self) -> &[u8] {
+        if self.stdout_path_len == 0 {
+            return &[];
+        }
+        // Safety: The bootloader guarantees the pointer refers to stdout_path_len bytes that stay mapped
+        unsafe { from_raw_parts(self.stdout_path, self.stdout_path_len) }
+    }
+}
+
+impl Default for BootInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BootInfo;
+    use crate::util::error::BootInfoError;
+    use std::boxed::Box;
+
+    #[test]
+    fn accepts_valid_boot_info() {
+        let command_line = "console=serial0";
+        let stdout_path = "serial0:115200n8";
+        let mut boot_info = BootInfo::new();
+        boot_info.command_line = command_line.as_ptr();
+        boot_info.command_line_len = command_line.len();
+        boot_info.stdout_path = stdout_path.as_ptr();
+        boot_info.stdout_path_len = stdout_path.len();
+
+        let validated = unsafe { BootInfo::from_ptr(Box::leak(Box::new(boot_info))) }.unwrap();
+        assert_eq!(validated.command_line(), command_line);
+        assert_eq!(validated.stdout_path(), stdout_path);
+        assert_eq!(BootInfo::new().stdout_path(), "");
+        assert!(validated.memory_map().is_empty());
+        assert!(validated.cpus().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_boot_info() {
+        assert_eq!(
+            unsafe { BootInfo::from_ptr(core::ptr::null()) }.err(),
+            Some(BootInfoError::Null)
+        );
+
+        let mut bad_magic = BootInfo::new();
+        bad_magic.magic = 0;
+        assert_eq!(
+            unsafe { BootInfo::from_ptr(&bad_magic) }.err(),
+            Some(BootInfoError::BadMagic)
+        );
+
+        let mut bad_version = BootInfo::new();
+        bad_version.version += 1;
+        assert_eq!(
+            unsafe { BootInfo::from_ptr(&bad_version) }.err(),
+            Some(BootInfoError::UnsupportedVersion)
+        );
+
+        let command_line = [0xFF, 0xFE];
+        let mut bad_command_line = BootInfo::new();
+        bad_command_line.command_line = command_line.as_ptr();
+        bad_command_line.command_line_len = command_line.len();
+        assert_eq!(
+            unsafe { BootInfo::from_ptr(&bad_command_line) }.err(),
+            Some(BootInfoError::InvalidCommandLine)
+        );
+
+        let mut bad_stdout_path = BootInfo::new();
+        bad_stdout_path.stdout_path = command_line.as_ptr();
+        bad_stdout_path.stdout_path_len = command_line.len();
+        assert_eq!(
+            unsafe { BootInfo::from_ptr(&bad_stdout_path) }.err(),
+            Some(BootInfoError::InvalidStdoutPath)
+        );
+    }
+}
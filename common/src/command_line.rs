@@ -0,0 +1,366 @@
+//! Parsing of the kernel command line
+//!
+//! A command line is a whitespace separated list of arguments, each of which is either a flag ("quiet") or a
+//! key=value pair ("loglevel=debug"). Every subsystem declares the parameters it understands in a ParamTable
+//! of its own, which hands out a typed Param for each name, and the command line is parsed against the tables
+//! of all subsystems at once. Arguments no table declares, and values that don't parse as the declared type,
+//! are reported through a warning callback rather than failing the boot.
+
+use core::fmt::Display;
+
+use crate::memory::memory_size::{GIB_SIZE, KIB_SIZE, MIB_SIZE};
+
+/// A type that a command line argument can be parsed into
+pub trait ParamValue<'a>: Sized {
+    /// Parses the value of an argument, which is None if the argument was given as a bare flag
+    fn parse(value: Option<&'a str>) -> Option<Self>;
+}
+
+/// Flags are true when present without a value, otherwise the usual spellings of yes and no are accepted
+impl<'a> ParamValue<'a> for bool {
+    fn parse(value: Option<&'a str>) -> Option<Self> {
+        match value {
+            None | Some("1" | "y" | "yes" | "on" | "true") => Some(true),
+            Some("0" | "n" | "no" | "off" | "false") => Some(false),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Numbers are decimal, or hexadecimal with a 0x prefix, optionally followed by a K, M or G size suffix
+impl<'a> ParamValue<'a> for usize {
+    fn parse(value: Option<&'a str>) -> Option<Self> {
+        let value = value?;
+        let (digits, multiplier) = match value.as_bytes().last()? {
+            b'K' | b'k' => (&value[..value.len() - 1], KIB_SIZE),
+            b'M' | b'm' => (&value[..value.len() - 1], MIB_SIZE),
+            b'G' | b'g' => (&value[..value.len() - 1], GIB_SIZE),
+            _ => (value, 1),
+        };
+        let number = match digits.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+            None => digits.parse().ok()?,
+        };
+        number.checked_mul(multiplier)
+    }
+}
+
+impl<'a> ParamValue<'a> for &'a str {
+    fn parse(value: Option<&'a str>) -> Option<Self> {
+        value.filter(|value| !value.is_empty())
+    }
+}
+
+/// Somewhere a parsed argument can be stored, regardless of its type
+trait ParamSlot<'a> {
+    fn set(&mut self, value: Option<&'a str>) -> bool;
+}
+
+impl<'a, T: ParamValue<'a>> ParamSlot<'a> for Option<T> {
+    fn set(&mut self, value: Option<&'a str>) -> bool {
+        match T::parse(value) {
+            Some(parsed) => {
+                *self = Some(parsed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'a, T: ParamValue<'a>> ParamSlot<'a> for T {
+    fn set(&mut self, value: Option<&'a str>) -> bool {
+        match T::parse(value) {
+            Some(parsed) => {
+                *self = parsed;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A named parameter, filled in with the parsed value of the argument of the same name if there is one
+pub struct Param<'p, 'a> {
+    name: &'static str,
+    slot: &'p mut dyn ParamSlot<'a>,
+}
+
+impl<'p, 'a> Param<'p, 'a> {
+    pub fn new<T: ParamValue<'a>>(name: &'static str, slot: &'p mut Option<T>) -> Self {
+        Self { name, slot }
+    }
+
+    /// A parameter with a default, which is left as it is unless the argument is given
+    pub fn with_default<T: ParamValue<'a>>(name: &'static str, slot: &'p mut T) -> Self {
+        Self { name, slot }
+    }
+}
+
+/// The parameters one subsystem understands
+///
+/// Each subsystem implements this for whatever holds its settings, so adding a parameter only touches the
+/// subsystem it belongs to.
+pub trait ParamTable<'a> {
+    /// Returns the parameter called name, if this table declares one
+    fn param(&mut self, name: &str) -> Option<Param<'_, 'a>>;
+}
+
+/// A plain list of params is a table too
+impl<'p, 'a, const N: usize> ParamTable<'a> for [Param<'p, 'a>; N] {
+    fn param(&mut self, name: &str) -> Option<Param<'_, 'a>> {
+        let param = self.iter_mut().find(|param| param.name == name)?;
+        Some(Param {
+            name: param.name,
+            slot: &mut *param.slot,
+        })
+    }
+}
+
+/// A problem with a single argument, which was otherwise skipped
+#[derive(Debug, PartialEq)]
+pub enum ParamWarning<'a> {
+    Unknown(&'a str),
+    InvalidValue {
+        name: &'a str,
+        value: Option<&'a str>,
+    },
+}
+
+impl<'a> Display for ParamWarning<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParamWarning::Unknown(name) => write!(f, "Unknown command line parameter \"{}\"", name),
+            ParamWarning::InvalidValue {
+                name,
+                value: Some(value),
+            } => write!(
+                f,
+                "Invalid value \"{}\" for command line parameter \"{}\"",
+                value, name
+            ),
+            ParamWarning::InvalidValue { name, value: None } => {
+                write!(f, "Command line parameter \"{}\" requires a value", name)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct CommandLine<'a> {
+    line: &'a str,
+}
+
+impl<'a> CommandLine<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self { line }
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.line
+    }
+
+    /// Iterates over every argument as a (name, value) pair, where the value is None for flags
+    pub fn args(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> + 'a {
+        self.line
+            .split_whitespace()
+            .map(|arg| match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg, None),
+            })
+    }
+
+    /// Fills in the params of every table from the argument with the same name, reporting any argument that
+    /// none of the tables declare or that doesn't parse through warn. Later arguments override earlier ones
+    /// with the same name, and if several tables declare the same name, the first one gets the value.
+    pub fn parse<F: FnMut(ParamWarning<'a>)>(
+        &self,
+        tables: &mut [&mut dyn ParamTable<'a>],
+        mut warn: F,
+    ) {
+        for (name, value) in self.args() {
+            match tables.iter_mut().find_map(|table| table.param(name)) {
+                Some(param) => {
+                    if !param.slot.set(value) {
+                        warn(ParamWarning::InvalidValue { name, value });
+                    }
+                }
+                None => warn(ParamWarning::Unknown(name)),
+            }
+        }
+    }
+
+    /// Like parse, but without reporting anything, for a command line that has already been checked (eg by
+    /// the bootloader, before the kernel has a console to warn on)
+    pub fn parse_quietly(&self, tables: &mut [&mut dyn ParamTable<'a>]) {
+        self.parse(tables, |_| ());
+    }
+}
+
+/// How much detail to print while booting
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl<'a> ParamValue<'a> for LogLevel {
+    fn parse(value: Option<&'a str>) -> Option<Self> {
+        match value? {
+            "error" | "0" => Some(LogLevel::Error),
+            "warn" | "1" => Some(LogLevel::Warn),
+            "info" | "2" => Some(LogLevel::Info),
+            "debug" | "3" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+/// The parameters shared by the bootloader and the kernel
+#[derive(Default)]
+pub struct BootParams<'a> {
+    pub log_level: LogLevel,
+    /// Memory above this physical address is left unused
+    pub mem_limit: Option<usize>,
+    /// The device to use as the console, overriding the stdout-path of the device tree
+    pub console: Option<&'a str>,
+}
+
+impl<'a> ParamTable<'a> for BootParams<'a> {
+    fn param(&mut self, name: &str) -> Option<Param<'_, 'a>> {
+        match name {
+            "loglevel" => Some(Param::with_default("loglevel", &mut self.log_level)),
+            "mem" => Some(Param::new("mem", &mut self.mem_limit)),
+            "console" => Some(Param::new("console", &mut self.console)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BootParams, CommandLine, LogLevel, Param, ParamTable, ParamValue, ParamWarning};
+
+    #[test]
+    fn parses_typed_values() {
+        assert_eq!(bool::parse(None), Some(true));
+        assert_eq!(bool::parse(Some("off")), Some(false));
+        assert_eq!(bool::parse(Some("maybe")), None);
+
+        assert_eq!(usize::parse(Some("1234")), Some(1234));
+        assert_eq!(usize::parse(Some("0x1000")), Some(0x1000));
+        assert_eq!(usize::parse(Some("512M")), Some(512 * 1024 * 1024));
+        assert_eq!(usize::parse(Some("0x10k")), Some(0x4000));
+        assert_eq!(usize::parse(Some("")), None);
+        assert_eq!(usize::parse(Some("G")), None);
+        assert_eq!(usize::parse(None), None);
+
+        assert_eq!(<&str>::parse(Some("serial0")), Some("serial0"));
+        assert_eq!(<&str>::parse(Some("")), None);
+
+        assert_eq!(LogLevel::parse(Some("debug")), Some(LogLevel::Debug));
+        assert_eq!(LogLevel::parse(Some("0")), Some(LogLevel::Error));
+        assert_eq!(LogLevel::parse(Some("loud")), None);
+    }
+
+    #[test]
+    fn fills_params_and_warns_about_the_rest() {
+        let command_line =
+            CommandLine::new("  quiet verbose=no mem=1G mem=2G bogus count=x  count ");
+        let mut quiet: Option<bool> = None;
+        let mut verbose: Option<bool> = None;
+        let mut mem: Option<usize> = None;
+        let mut count: Option<usize> = None;
+        let mut warnings = Vec::new();
+        command_line.parse(
+            &mut [&mut [
+                Param::new("quiet", &mut quiet),
+                Param::new("verbose", &mut verbose),
+                Param::new("mem", &mut mem),
+                Param::new("count", &mut count),
+            ]],
+            |warning| warnings.push(warning),
+        );
+
+        assert_eq!(quiet, Some(true));
+        assert_eq!(verbose, Some(false));
+        assert_eq!(mem, Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(count, None);
+        assert_eq!(
+            warnings,
+            [
+                ParamWarning::Unknown("bogus"),
+                ParamWarning::InvalidValue {
+                    name: "count",
+                    value: Some("x")
+                },
+                ParamWarning::InvalidValue {
+                    name: "count",
+                    value: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_boot_params() {
+        // The command line the Raspberry Pi firmware passes by default
+        let firmware = CommandLine::new("coherent_pool=1M 8250.nr_uarts=1 console=serial0,115200");
+        let mut unknown = 0;
+        let mut params = BootParams::default();
+        firmware.parse(&mut [&mut params], |_| unknown += 1);
+        assert_eq!(unknown, 2);
+        assert_eq!(params.log_level, LogLevel::Info);
+        assert_eq!(params.mem_limit, None);
+        assert_eq!(params.console, Some("serial0,115200"));
+
+        let mut params = BootParams::default();
+        CommandLine::new("loglevel=warn mem=0x40000000 loglevel=loud")
+            .parse(&mut [&mut params], |warning| {
+                assert!(matches!(warning, ParamWarning::InvalidValue { .. }))
+            });
+        assert_eq!(params.log_level, LogLevel::Warn);
+        assert_eq!(params.mem_limit, Some(0x40000000));
+        assert_eq!(params.console, None);
+    }
+
+    /// A subsystem with parameters of its own
+    #[derive(Default)]
+    struct Scheduler {
+        timeslice: Option<usize>,
+        nosmp: Option<bool>,
+    }
+
+    impl<'a> ParamTable<'a> for Scheduler {
+        fn param(&mut self, name: &str) -> Option<Param<'_, 'a>> {
+            match name {
+                "timeslice" => Some(Param::new("timeslice", &mut self.timeslice)),
+                "nosmp" => Some(Param::new("nosmp", &mut self.nosmp)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn parses_every_registered_table() {
+        let command_line = CommandLine::new("nosmp loglevel=debug timeslice=10 coherent_pool=1M");
+        let mut boot = BootParams::default();
+        let mut scheduler = Scheduler::default();
+        let mut warnings = Vec::new();
+        command_line.parse(&mut [&mut boot, &mut scheduler], |warning| {
+            warnings.push(warning)
+        });
+
+        assert_eq!(boot.log_level, LogLevel::Debug);
+        assert_eq!(scheduler.timeslice, Some(10));
+        assert_eq!(scheduler.nosmp, Some(true));
+        assert_eq!(warnings, [ParamWarning::Unknown("coherent_pool")]);
+
+        let mut scheduler = Scheduler::default();
+        command_line.parse_quietly(&mut [&mut scheduler]);
+        assert_eq!(scheduler.timeslice, Some(10));
+    }
+}
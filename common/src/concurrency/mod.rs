@@ -1,4 +1,5 @@
 pub mod single_threaded_lock;
+pub mod single_threaded_writer_mutex;
 
 /// Essentially a carbon copy of lock_api's RawMutex, but without the const associated variable. We needed
 /// to strip that so that we can make it object safe for dyn.
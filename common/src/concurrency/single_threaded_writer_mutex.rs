@@ -1,4 +1,4 @@
-use common::concurrency::RawWriterMutex;
+use super::RawWriterMutex;
 
 pub struct SingleThreadedRawWriterMutex;
 
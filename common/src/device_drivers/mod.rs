@@ -1 +1,2 @@
 pub mod character_device;
+pub mod registry;
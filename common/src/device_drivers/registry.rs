@@ -0,0 +1,338 @@
+//! Binding drivers to the devices described by a device tree
+//!
+//! Every driver declares the compatible strings it supports along with a probe function. The registry walks
+//! the device tree and hands every enabled, matching node to the probe function of its driver, which is
+//! expected to construct the driver and store it in the caller supplied context. Nodes are bound to at
+//! most one driver, and drivers are probed in the order they are registered, so a driver that depends on
+//! another only needs to be registered after it.
+
+use core::fmt::Display;
+
+use crate::{
+    device_tree::{fdt::FdtNode, interrupts::Interrupt, DeviceTree, Specifier},
+    util::error::{CapacityError, DeviceError},
+};
+
+/// Everything a driver needs to know about the device it is being bound to
+pub struct DeviceResources<'a> {
+    dt: DeviceTree<'a>,
+    node: FdtNode<'a>,
+}
+
+impl<'a> DeviceResources<'a> {
+    pub fn node(&self) -> &FdtNode<'a> {
+        &self.node
+    }
+
+    /// Whether path (or an alias, as accepted by DeviceTree::find_node) refers to this device
+    pub fn is_node(&self, path: &str) -> bool {
+        self.dt
+            .find_node(path)
+            .is_some_and(|node| node.offset() == self.node.offset())
+    }
+
+    /// The device's register regions, as (CPU physical address, size) pairs
+    pub fn regs(&self) -> impl Iterator<Item = (usize, usize)> + 'a {
+        self.dt
+            .reg(&self.node)
+            .map(|(address, size)| (address as usize, size as usize))
+    }
+
+    /// The device's interrupts, resolved to their controllers
+    pub fn interrupts(&self) -> impl Iterator<Item = Interrupt<'a>> + 'a {
+        self.dt.interrupts(&self.node)
+    }
+
+    /// The device's clock inputs, as specifiers of their clock providers
+    pub fn clocks(&self) -> impl Iterator<Item = Specifier<'a>> + 'a {
+        self.dt.clocks(&self.node)
+    }
+}
+
+/// A driver that can be bound to devices through the registry
+///
+/// The probe function receives the resources of a matching device, and stores whatever it constructs in
+/// the context C. Returning DeviceError::Unsupported passes on the device without it counting as a failure,
+/// for drivers that only handle some of the devices they are compatible with.
+pub struct Driver<C> {
+    pub name: &'static str,
+    pub compatible: &'static [&'static str],
+    pub probe: fn(&DeviceResources, &mut C) -> Result<(), DeviceError>,
+}
+
+impl<C> Driver<C> {
+    fn matches(&self, node: &FdtNode) -> bool {
+        self.compatible
+            .iter()
+            .any(|compatible| node.is_compatible(compatible))
+    }
+}
+
+/// A device that a driver was successfully bound to
+#[derive(Clone, Copy)]
+pub struct BoundDevice<'a> {
+    pub driver: &'static str,
+    pub node: &'a str,
+    /// Address of the device's first register region, if it has any
+    pub base: Option<usize>,
+}
+
+/// The outcome of probing a device tree, holding up to N bound devices
+pub struct DeviceList<'a, const N: usize> {
+    devices: [Option<BoundDevice<'a>>; N],
+    len: usize,
+    failed: usize,
+}
+
+impl<'a, const N: usize> DeviceList<'a, N> {
+    pub const fn new() -> Self {
+        Self {
+            devices: [None; N],
+            len: 0,
+            failed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of matching devices whose probe function returned an error
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BoundDevice<'a>> {
+        self.devices[..self.len].iter().flatten()
+    }
+
+    fn push(&mut self, device: BoundDevice<'a>) -> Result<(), CapacityError> {
+        let slot = self.devices.get_mut(self.len).ok_or(CapacityError)?;
+        *slot = Some(device);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn is_bound(&self, node: &FdtNode) -> bool {
+        // Node names are slices of the blob, so two nodes are the same exactly when their names are
+        self.iter()
+            .any(|device| core::ptr::eq(device.node, node.name()))
+    }
+}
+
+impl<'a, const N: usize> Default for DeviceList<'a, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const N: usize> Display for DeviceList<'a, N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for device in self.iter() {
+            match device.base {
+                Some(base) => write!(f, "Driver: {:16} | {:#018x} | ", device.driver, base)?,
+                None => write!(f, "Driver: {:16} | {:18} | ", device.driver, "-")?,
+            }
+            writeln!(f, "{}", device.node)?;
+        }
+        if self.failed != 0 {
+            writeln!(f, "{} matching device(s) failed to probe", self.failed)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A set of drivers to bind against a device tree
+pub struct DriverRegistry<'r, C> {
+    drivers: &'r [Driver<C>],
+}
+
+impl<'r, C> DriverRegistry<'r, C> {
+    pub const fn new(drivers: &'r [Driver<C>]) -> Self {
+        Self { drivers }
+    }
+
+    /// Probes every enabled device in dt that one of our drivers is compatible with, in registration order
+    ///
+    /// Devices whose probe fails are counted (unless the driver declined them as unsupported), but left
+    /// unbound so that a later driver may still claim them.
+    /// Returns CapacityError once more than N devices have been bound, after which nothing else is probed.
+    pub fn probe<'a, const N: usize>(
+        &self,
+        dt: &DeviceTree<'a>,
+        context: &mut C,
+    ) -> Result<DeviceList<'a, N>, CapacityError> {
+        let mut devices = DeviceList::new();
+        for driver in self.drivers {
+            let nodes = dt
+                .fdt()
+                .nodes()
+                .filter(|node| node.is_enabled() && driver.matches(node));
+            for node in nodes {
+                if devices.is_bound(&node) {
+                    continue;
+                }
+
+                let resources = DeviceResources { dt: *dt, node };
+                match (driver.probe)(&resources, context) {
+                    Ok(()) => devices.push(BoundDevice {
+                        driver: driver.name,
+                        node: node.name(),
+                        base: resources.regs().next().map(|(base, _)| base),
+                    })?,
+                    Err(DeviceError::Unsupported) => {}
+                    Err(_) => devices.failed += 1,
+                }
+            }
+        }
+
+        Ok(devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeviceResources, Driver, DriverRegistry};
+    use crate::{
+        device_tree::{fdt::Fdt, DeviceTree},
+        util::error::{CapacityError, DeviceError},
+    };
+
+    const RPI3_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2710-rpi-3-b.dtb");
+
+    #[derive(Default)]
+    struct Context {
+        uart: Option<usize>,
+        mailbox: Option<usize>,
+        irqs: usize,
+    }
+
+    fn probe_uart(resources: &DeviceResources, context: &mut Context) -> Result<(), DeviceError> {
+        // Depends on the mailbox, so that registration order can be checked
+        context.mailbox.ok_or(DeviceError::MissingDependency)?;
+        context.uart = Some(resources.regs().next().ok_or(DeviceError::BadOperand)?.0);
+        context.irqs += resources.interrupts().count();
+        Ok(())
+    }
+
+    fn probe_mailbox(
+        resources: &DeviceResources,
+        context: &mut Context,
+    ) -> Result<(), DeviceError> {
+        context.mailbox = Some(resources.regs().next().ok_or(DeviceError::BadOperand)?.0);
+        Ok(())
+    }
+
+    fn probe_failing(_: &DeviceResources, _: &mut Context) -> Result<(), DeviceError> {
+        Err(DeviceError::Other)
+    }
+
+    fn probe_uart0(resources: &DeviceResources, context: &mut Context) -> Result<(), DeviceError> {
+        if !resources.is_node("uart0") {
+            return Err(DeviceError::Unsupported);
+        }
+        context.uart = Some(resources.regs().next().ok_or(DeviceError::BadOperand)?.0);
+        Ok(())
+    }
+
+    const UART: Driver<Context> = Driver {
+        name: "pl011",
+        compatible: &["arm,pl011"],
+        probe: probe_uart,
+    };
+    const MAILBOX: Driver<Context> = Driver {
+        name: "bcm2835-mbox",
+        compatible: &["brcm,bcm2835-mbox"],
+        probe: probe_mailbox,
+    };
+
+    fn dt() -> DeviceTree<'static> {
+        DeviceTree::new(Fdt::new(RPI3_DTB).unwrap())
+    }
+
+    #[test]
+    fn binds_drivers_in_registration_order() {
+        let mut context = Context::default();
+        let drivers = [MAILBOX, UART];
+        let devices = DriverRegistry::new(&drivers)
+            .probe::<4>(&dt(), &mut context)
+            .unwrap();
+
+        assert_eq!(context.mailbox, Some(0x3F00B880));
+        assert_eq!(context.uart, Some(0x3F201000));
+        assert_eq!(context.irqs, 1);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices.failed(), 0);
+        let bound: Vec<_> = devices.iter().map(|device| device.node).collect();
+        assert_eq!(bound, ["mailbox@7e00b880", "serial@7e201000"]);
+
+        let printed = format!("{}", devices);
+        assert!(printed.contains("pl011"));
+        assert!(printed.contains("0x000000003f201000"));
+
+        // The other way around, the uart's dependency is not there yet when it is probed
+        let mut context = Context::default();
+        let drivers = [UART, MAILBOX];
+        let devices = DriverRegistry::new(&drivers)
+            .probe::<4>(&dt(), &mut context)
+            .unwrap();
+        assert_eq!(context.uart, None);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices.failed(), 1);
+    }
+
+    #[test]
+    fn failed_devices_remain_available_to_later_drivers() {
+        let failing = Driver {
+            name: "failing",
+            compatible: &["brcm,bcm2835-mbox"],
+            probe: probe_failing,
+        };
+        let mut context = Context::default();
+        let drivers = [failing, MAILBOX];
+        let devices = DriverRegistry::new(&drivers)
+            .probe::<4>(&dt(), &mut context)
+            .unwrap();
+        assert_eq!(devices.failed(), 1);
+        assert_eq!(devices.iter().next().unwrap().driver, "bcm2835-mbox");
+
+        // Binding a device twice is not possible, even through a second matching driver
+        let mut context = Context::default();
+        let drivers = [MAILBOX, MAILBOX];
+        let devices = DriverRegistry::new(&drivers)
+            .probe::<4>(&dt(), &mut context)
+            .unwrap();
+        assert_eq!(devices.len(), 1);
+    }
+
+    #[test]
+    fn unsupported_devices_are_not_failures() {
+        let uart0 = Driver {
+            name: "uart0",
+            compatible: &["arm,pl011", "brcm,bcm2835-aux-uart"],
+            probe: probe_uart0,
+        };
+        let mut context = Context::default();
+        let devices = DriverRegistry::new(&[uart0])
+            .probe::<4>(&dt(), &mut context)
+            .unwrap();
+        assert_eq!(context.uart, Some(0x3F201000));
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices.failed(), 0);
+    }
+
+    #[test]
+    fn reports_running_out_of_capacity() {
+        let mut context = Context::default();
+        let drivers = [MAILBOX, UART];
+        assert!(matches!(
+            DriverRegistry::new(&drivers).probe::<1>(&dt(), &mut context),
+            Err(CapacityError)
+        ));
+    }
+}
@@ -0,0 +1,82 @@
+//! Builds minimal blobs for tests, for the cases the vendored blobs don't cover
+
+use super::fdt::{
+    FDT_BEGIN_NODE, FDT_END, FDT_END_NODE, FDT_MAGIC, FDT_PROP, FDT_VERSION, HEADER_SIZE,
+};
+
+#[derive(Default)]
+pub struct BlobBuilder {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl BlobBuilder {
+    pub fn begin(&mut self, name: &str) -> &mut Self {
+        self.structs.extend(FDT_BEGIN_NODE.to_be_bytes());
+        self.structs.extend(name.as_bytes());
+        self.structs.push(0);
+        self.structs
+            .resize(self.structs.len().next_multiple_of(4), 0);
+        self
+    }
+
+    pub fn prop(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.prop_bytes(name, &value)
+    }
+
+    /// Adds a property holding a list of null terminated strings
+    pub fn prop_strs(&mut self, name: &str, strings: &[&str]) -> &mut Self {
+        let value: Vec<u8> = strings
+            .iter()
+            .flat_map(|string| string.bytes().chain([0]))
+            .collect();
+        self.prop_bytes(name, &value)
+    }
+
+    pub fn prop_bytes(&mut self, name: &str, value: &[u8]) -> &mut Self {
+        let name_offset = self.strings.len() as u32;
+        self.strings.extend(name.as_bytes());
+        self.strings.push(0);
+        self.structs.extend(FDT_PROP.to_be_bytes());
+        self.structs.extend((value.len() as u32).to_be_bytes());
+        self.structs.extend(name_offset.to_be_bytes());
+        self.structs.extend(value);
+        self.structs
+            .resize(self.structs.len().next_multiple_of(4), 0);
+        self
+    }
+
+    pub fn end(&mut self) -> &mut Self {
+        self.structs.extend(FDT_END_NODE.to_be_bytes());
+        self
+    }
+
+    pub fn build(&mut self) -> Vec<u8> {
+        self.structs.extend(FDT_END.to_be_bytes());
+        // Header, then an empty memory reservation block, then the structure and strings blocks
+        let structs_offset = HEADER_SIZE + 16;
+        let strings_offset = structs_offset + self.structs.len();
+        let total_size = strings_offset + self.strings.len();
+        let header = [
+            FDT_MAGIC as usize,
+            total_size,
+            structs_offset,
+            strings_offset,
+            HEADER_SIZE,
+            FDT_VERSION as usize,
+            16,
+            0,
+            self.strings.len(),
+            self.structs.len(),
+        ];
+        let mut blob: Vec<u8> = header
+            .iter()
+            .flat_map(|&field| (field as u32).to_be_bytes())
+            .collect();
+        blob.extend([0; 16]);
+        blob.extend(&self.structs);
+        blob.extend(&self.strings);
+        blob
+    }
+}
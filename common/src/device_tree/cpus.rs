@@ -0,0 +1,135 @@
+//! The CPU topology described by the /cpus node
+
+use super::{fdt::FdtNode, DeviceTree};
+
+/// How a secondary CPU is brought out of the firmware's parking loop
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnableMethod {
+    /// The CPU spins until an entry point is written to its release address and an event is sent
+    SpinTable = 1,
+    /// The CPU is started through PSCI CPU_ON calls to the firmware
+    Psci = 2,
+    /// No enable method, or one we don't know how to use
+    Unknown = 0,
+}
+
+/// A single CPU, as handed to the kernel so it can bring up the other cores
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cpu {
+    /// The affinity fields of the CPU's MPIDR_EL1, as found in its reg property
+    pub mpidr: u64,
+    pub enable_method: EnableMethod,
+    /// Physical address the CPU polls for its entry point, for spin-table CPUs (0 otherwise)
+    pub release_addr: u64,
+}
+
+impl<'a> DeviceTree<'a> {
+    /// Iterates over every enabled CPU described by /cpus
+    pub fn cpus(&self) -> impl Iterator<Item = Cpu> + 'a {
+        let cpus = self.find_node("/cpus");
+        cpus.into_iter().flat_map(|cpus| {
+            cpus.children()
+                .filter(|node| is_cpu(node) && node.is_enabled())
+                .filter_map(move |node| parse_cpu(&cpus, &node))
+        })
+    }
+}
+
+/// Returns the smallest page aligned range covering the release address of every spin-table CPU, which is
+/// memory the parked CPUs keep reading until they are released
+pub fn spin_table_region<I: Iterator<Item = Cpu>>(
+    cpus: I,
+    page_size: usize,
+) -> Option<(usize, usize)> {
+    cpus.filter(|cpu| cpu.enable_method == EnableMethod::SpinTable)
+        .map(|cpu| cpu.release_addr as usize)
+        .fold(None, |region, addr| {
+            let start = addr - addr % page_size;
+            let end = (addr + size_of::<u64>()).next_multiple_of(page_size);
+            Some(match region {
+                Some((region_start, region_end)) => (start.min(region_start), end.max(region_end)),
+                None => (start, end),
+            })
+        })
+}
+
+fn is_cpu(node: &FdtNode) -> bool {
+    match node.property("device_type") {
+        Some(device_type) => device_type.as_str() == Some("cpu"),
+        // Old trees leave out device_type, so fall back on the node name
+        None => node.base_name() == "cpu",
+    }
+}
+
+fn parse_cpu(cpus: &FdtNode, node: &FdtNode) -> Option<Cpu> {
+    let mpidr = node.property("reg")?.cells(0, cpus.address_cells())?;
+    // A CPU without an enable method of its own uses the one given for all CPUs
+    let enable_method = node
+        .property("enable-method")
+        .or_else(|| cpus.property("enable-method"))
+        .and_then(|prop| prop.as_str());
+    let release_addr = node
+        .property("cpu-release-addr")
+        .and_then(|prop| prop.cells(0, (prop.value.len() / 4) as u32));
+
+    let (enable_method, release_addr) = match (enable_method, release_addr) {
+        (Some("spin-table"), Some(release_addr)) => (EnableMethod::SpinTable, release_addr),
+        (Some("psci"), _) => (EnableMethod::Psci, 0),
+        _ => (EnableMethod::Unknown, 0),
+    };
+
+    Some(Cpu {
+        mpidr,
+        enable_method,
+        release_addr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spin_table_region, Cpu, EnableMethod};
+    use crate::device_tree::{fdt::Fdt, DeviceTree};
+
+    const RPI3_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2710-rpi-3-b.dtb");
+    const RPI4_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2711-rpi-4-b.dtb");
+
+    #[test]
+    fn enumerates_spin_table_cpus() {
+        for blob in [RPI3_DTB, RPI4_DTB] {
+            let dt = DeviceTree::new(Fdt::new(blob).unwrap());
+            let cpus: Vec<Cpu> = dt.cpus().collect();
+            assert_eq!(cpus.len(), 4);
+            for (idx, cpu) in cpus.iter().enumerate() {
+                assert_eq!(cpu.mpidr, idx as u64);
+                assert_eq!(cpu.enable_method, EnableMethod::SpinTable);
+                assert_eq!(cpu.release_addr, 0xD8 + 8 * idx as u64);
+            }
+
+            assert_eq!(spin_table_region(dt.cpus(), 0x1000), Some((0, 0x1000)));
+        }
+    }
+
+    #[test]
+    fn spin_table_region_covers_every_release_addr() {
+        let cpu = |release_addr, enable_method| Cpu {
+            mpidr: 0,
+            enable_method,
+            release_addr,
+        };
+        assert_eq!(spin_table_region([].into_iter(), 0x1000), None);
+        assert_eq!(
+            spin_table_region(
+                [
+                    cpu(0x2FFC, EnableMethod::SpinTable),
+                    cpu(0x1000, EnableMethod::SpinTable),
+                    cpu(0x9000, EnableMethod::Psci),
+                ]
+                .into_iter(),
+                0x1000
+            ),
+            Some((0x1000, 0x4000))
+        );
+    }
+}
@@ -0,0 +1,551 @@
+use crate::util::error::FdtError;
+use core::slice::from_raw_parts;
+
+pub(super) const FDT_MAGIC: u32 = 0xD00DFEED;
+pub(super) const FDT_BEGIN_NODE: u32 = 0x1;
+pub(super) const FDT_END_NODE: u32 = 0x2;
+pub(super) const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+pub(super) const FDT_END: u32 = 0x9;
+
+pub(super) const HEADER_SIZE: usize = 40;
+/// Deepest nesting of nodes we support when looking up the ancestors of a node
+pub const MAX_DEPTH: usize = 16;
+/// The newest version of the format we know how to read
+pub(super) const FDT_VERSION: u32 = 17;
+
+/// A flattened device tree blob, as handed to us by the firmware
+///
+/// This is a zero-copy view of the blob: nodes and properties are decoded straight out of it on demand.
+/// Malformed data is never trusted, so a corrupt structure block simply ends iteration early rather than
+/// reading out of bounds.
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    mem_rsvmap_offset: usize,
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(blob: &'a [u8]) -> Result<Self, FdtError> {
+        if blob.len() < HEADER_SIZE || read_u32(blob, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let header = |idx: usize| read_u32(blob, idx * 4).unwrap() as usize;
+        let total_size = header(1);
+        if total_size < HEADER_SIZE || total_size > blob.len() {
+            return Err(FdtError::Truncated);
+        }
+        // Blobs newer than we understand are still fine as long as they remain compatible with our version
+        let last_comp_version = header(6) as u32;
+        if last_comp_version > FDT_VERSION {
+            return Err(FdtError::UnsupportedVersion);
+        }
+
+        let blob = &blob[..total_size];
+        let structs = blob
+            .get(header(2)..header(2) + header(9))
+            .ok_or(FdtError::Truncated)?;
+        let strings = blob
+            .get(header(3)..header(3) + header(8))
+            .ok_or(FdtError::Truncated)?;
+        Ok(Self {
+            blob,
+            structs,
+            strings,
+            mem_rsvmap_offset: header(4),
+        })
+    }
+
+    /// Constructs an Fdt from the address the firmware passed us
+    ///
+    /// # Safety
+    /// ptr must point to readable memory that is at least as large as the header claims the blob is, and
+    /// that memory must not be modified for as long as the Fdt lives.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
+        if ptr.is_null() {
+            return Err(FdtError::BadMagic);
+        }
+        let header = from_raw_parts(ptr, HEADER_SIZE);
+        if read_u32(header, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = read_u32(header, 4).unwrap() as usize;
+
+        Self::new(from_raw_parts(ptr, total_size.max(HEADER_SIZE)))
+    }
+
+    /// Size in bytes of the entire blob, as reported by its header
+    pub fn total_size(&self) -> usize {
+        self.blob.len()
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.blob
+    }
+
+    /// Iterates over the (address, size) pairs of the memory reservation block (/memreserve/ in source form)
+    pub fn reserved_entries(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let blob = self.blob;
+        let mut offset = self.mem_rsvmap_offset;
+        core::iter::from_fn(move || {
+            let address = read_u64(blob, offset)?;
+            let size = read_u64(blob, offset + 8)?;
+            offset += 16;
+            // The block is terminated by an all zero entry
+            (address != 0 || size != 0).then_some((address, size))
+        })
+    }
+
+    pub fn root(&self) -> Option<FdtNode<'a>> {
+        let mut cursor = Cursor::new(*self, 0);
+        match cursor.next_token()? {
+            Token::BeginNode(name) => Some(FdtNode {
+                fdt: *self,
+                name,
+                offset: cursor.offset,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the node whose FDT_BEGIN_NODE token is at offset in the structure block, as given by
+    /// FdtNode::offset
+    pub fn node_at(&self, offset: usize) -> Option<FdtNode<'a>> {
+        let mut cursor = Cursor::new(*self, offset);
+        match cursor.next_token()? {
+            Token::BeginNode(name) => Some(FdtNode {
+                fdt: *self,
+                name,
+                offset: cursor.offset,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Iterates over every node in the tree, depth first, starting with the root
+    pub fn nodes(&self) -> impl Iterator<Item = FdtNode<'a>> + 'a {
+        let fdt = *self;
+        let mut cursor = Cursor::new(fdt, 0);
+        core::iter::from_fn(move || loop {
+            match cursor.next_token()? {
+                Token::BeginNode(name) => {
+                    return Some(FdtNode {
+                        fdt,
+                        name,
+                        offset: cursor.offset,
+                    })
+                }
+                Token::EndNode | Token::Prop(_) => continue,
+                Token::End => return None,
+            }
+        })
+    }
+
+    /// Returns every ancestor of node, or None if node is nested deeper than MAX_DEPTH
+    ///
+    /// Nodes only know their own position in the blob, so this walks the tree from the root to find them.
+    pub fn ancestors(&self, node: &FdtNode<'a>) -> Option<Ancestors<'a>> {
+        let mut ancestors = Ancestors {
+            nodes: [None; MAX_DEPTH],
+            len: 0,
+        };
+        let mut cursor = Cursor::new(*self, 0);
+        loop {
+            match cursor.next_token()? {
+                Token::BeginNode(name) => {
+                    if cursor.offset == node.offset {
+                        return Some(ancestors);
+                    }
+                    *ancestors.nodes.get_mut(ancestors.len)? = Some(FdtNode {
+                        fdt: *self,
+                        name,
+                        offset: cursor.offset,
+                    });
+                    ancestors.len += 1;
+                }
+                Token::EndNode => ancestors.len = ancestors.len.checked_sub(1)?,
+                Token::Prop(_) => (),
+                Token::End => return None,
+            }
+        }
+    }
+
+    fn string(&self, offset: usize) -> Option<&'a str> {
+        read_str(self.strings.get(offset..)?)
+    }
+}
+
+/// The chain of nodes leading from the root down to (but excluding) some node
+pub struct Ancestors<'a> {
+    nodes: [Option<FdtNode<'a>>; MAX_DEPTH],
+    len: usize,
+}
+
+impl<'a> Ancestors<'a> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true for the root node, which has no ancestors
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the ancestor at the given depth, where 0 is the root
+    pub fn get(&self, depth: usize) -> Option<FdtNode<'a>> {
+        *self.nodes[..self.len].get(depth)?
+    }
+
+    pub fn parent(&self) -> Option<FdtNode<'a>> {
+        self.get(self.len.checked_sub(1)?)
+    }
+}
+
+/// A single node of a device tree
+#[derive(Clone, Copy)]
+pub struct FdtNode<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    // Offset of the first token after this node's name in the structure block
+    offset: usize,
+}
+
+impl<'a> FdtNode<'a> {
+    /// The full name of this node, including its unit address (eg "memory@0")
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Offset of this node's FDT_BEGIN_NODE token in the structure block, which identifies the node for as
+    /// long as the blob isn't modified
+    pub fn offset(&self) -> usize {
+        self.offset - (self.name.len() + 1).next_multiple_of(4) - 4
+    }
+
+    /// The name of this node, without its unit address
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    pub fn props(&self) -> impl Iterator<Item = FdtProp<'a>> + 'a {
+        let mut cursor = Cursor::new(self.fdt, self.offset);
+        core::iter::from_fn(move || match cursor.next_token()? {
+            Token::Prop(prop) => Some(prop),
+            _ => None,
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<FdtProp<'a>> {
+        self.props().find(|prop| prop.name == name)
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = FdtNode<'a>> + 'a {
+        let fdt = self.fdt;
+        let mut cursor = Cursor::new(fdt, self.offset);
+        core::iter::from_fn(move || loop {
+            match cursor.next_token()? {
+                Token::Prop(_) => continue,
+                Token::BeginNode(name) => {
+                    let child = FdtNode {
+                        fdt,
+                        name,
+                        offset: cursor.offset,
+                    };
+                    // Leave the cursor just past the child's subtree, ready for its next sibling
+                    cursor.skip_subtree()?;
+                    return Some(child);
+                }
+                Token::EndNode | Token::End => return None,
+            }
+        })
+    }
+
+    /// Finds a direct child by name. A name without a unit address matches any unit address.
+    pub fn child(&self, name: &str) -> Option<FdtNode<'a>> {
+        self.children()
+            .find(|child| child.name == name || child.base_name() == name)
+    }
+
+    /// Number of cells used to encode addresses in the reg properties of this node's children
+    pub fn address_cells(&self) -> u32 {
+        self.property("#address-cells")
+            .and_then(|prop| prop.u32(0))
+            .unwrap_or(2)
+    }
+
+    /// Number of cells used to encode sizes in the reg properties of this node's children
+    pub fn size_cells(&self) -> u32 {
+        self.property("#size-cells")
+            .and_then(|prop| prop.u32(0))
+            .unwrap_or(1)
+    }
+
+    pub fn phandle(&self) -> Option<u32> {
+        self.property("phandle")
+            .or_else(|| self.property("linux,phandle"))
+            .and_then(|prop| prop.u32(0))
+    }
+
+    /// Returns true if any of the entries of this node's compatible property is compatible
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property("compatible")
+            .is_some_and(|prop| prop.strings().any(|entry| entry == compatible))
+    }
+
+    /// Returns false if this node has been disabled through its status property
+    pub fn is_enabled(&self) -> bool {
+        match self.property("status").and_then(|prop| prop.as_str()) {
+            Some(status) => status == "okay" || status == "ok",
+            None => true,
+        }
+    }
+}
+
+/// A single property of a device tree node
+#[derive(Clone, Copy)]
+pub struct FdtProp<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> FdtProp<'a> {
+    /// Reads the idx'th 32 bit cell of the value
+    pub fn u32(&self, idx: usize) -> Option<u32> {
+        read_u32(self.value, idx * 4)
+    }
+
+    /// Reads a number made of num_cells consecutive cells, starting at cell idx. Numbers wider than 64 bits
+    /// cannot be represented and return None.
+    pub fn cells(&self, idx: usize, num_cells: u32) -> Option<u64> {
+        if num_cells > 2 {
+            return None;
+        }
+        (0..num_cells as usize).try_fold(0u64, |value, cell| {
+            Some((value << 32) | self.u32(idx + cell)? as u64)
+        })
+    }
+
+    /// Decodes the value as a list of (address, size) pairs, as found in reg properties
+    pub fn reg_entries(
+        &self,
+        address_cells: u32,
+        size_cells: u32,
+    ) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let prop = *self;
+        let entry_cells = (address_cells + size_cells) as usize;
+        let num_entries = match entry_cells {
+            0 => 0,
+            _ => self.value.len() / (entry_cells * 4),
+        };
+        (0..num_entries).map_while(move |entry| {
+            let idx = entry * entry_cells;
+            Some((
+                prop.cells(idx, address_cells)?,
+                prop.cells(idx + address_cells as usize, size_cells)?,
+            ))
+        })
+    }
+
+    /// Decodes the value as a list of (child address, parent address, size) triples, as found in ranges
+    /// properties. The child address and size cells come from the node owning the property, while the
+    /// parent address cells come from its parent.
+    pub fn range_entries(
+        &self,
+        child_address_cells: u32,
+        parent_address_cells: u32,
+        size_cells: u32,
+    ) -> impl Iterator<Item = (u64, u64, u64)> + 'a {
+        let prop = *self;
+        let entry_cells = (child_address_cells + parent_address_cells + size_cells) as usize;
+        let num_entries = match entry_cells {
+            0 => 0,
+            _ => self.value.len() / (entry_cells * 4),
+        };
+        (0..num_entries).map_while(move |entry| {
+            let idx = entry * entry_cells;
+            let parent_idx = idx + child_address_cells as usize;
+            Some((
+                prop.cells(idx, child_address_cells)?,
+                prop.cells(parent_idx, parent_address_cells)?,
+                prop.cells(parent_idx + parent_address_cells as usize, size_cells)?,
+            ))
+        })
+    }
+
+    /// Interprets the value as a single null terminated string
+    pub fn as_str(&self) -> Option<&'a str> {
+        read_str(self.value)
+    }
+
+    /// Iterates over the value as a list of null terminated strings
+    pub fn strings(&self) -> impl Iterator<Item = &'a str> + 'a {
+        self.value
+            .split(|&byte| byte == 0)
+            .filter(|bytes| !bytes.is_empty())
+            .filter_map(|bytes| core::str::from_utf8(bytes).ok())
+    }
+}
+
+pub(super) enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(FdtProp<'a>),
+    End,
+}
+
+/// Walks the tokens of the structure block
+pub(super) struct Cursor<'a> {
+    fdt: Fdt<'a>,
+    pub(super) offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(super) fn new(fdt: Fdt<'a>, offset: usize) -> Self {
+        Self { fdt, offset }
+    }
+
+    /// Returns the next token, or None if the structure block is malformed
+    pub(super) fn next_token(&mut self) -> Option<Token<'a>> {
+        let structs = self.fdt.structs;
+        loop {
+            let token = read_u32(structs, self.offset)?;
+            self.offset += 4;
+            match token {
+                FDT_NOP => continue,
+                FDT_BEGIN_NODE => {
+                    let name = read_str(structs.get(self.offset..)?)?;
+                    self.offset = (self.offset + name.len() + 1).next_multiple_of(4);
+                    return Some(Token::BeginNode(name));
+                }
+                FDT_END_NODE => return Some(Token::EndNode),
+                FDT_PROP => {
+                    let len = read_u32(structs, self.offset)? as usize;
+                    let name = self
+                        .fdt
+                        .string(read_u32(structs, self.offset + 4)? as usize)?;
+                    let value = structs.get(self.offset + 8..self.offset + 8 + len)?;
+                    self.offset = (self.offset + 8 + len).next_multiple_of(4);
+                    return Some(Token::Prop(FdtProp { name, value }));
+                }
+                FDT_END => return Some(Token::End),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Skips to just past the end of the node whose name was the last token read
+    pub(super) fn skip_subtree(&mut self) -> Option<()> {
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next_token()? {
+                Token::BeginNode(_) => depth += 1,
+                Token::EndNode => depth -= 1,
+                Token::Prop(_) => (),
+                Token::End => return None,
+            }
+        }
+
+        Some(())
+    }
+}
+
+pub(super) fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn read_str(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fdt;
+    use crate::util::error::FdtError;
+
+    const RPI3_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2710-rpi-3-b.dtb");
+    const RPI4_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2711-rpi-4-b.dtb");
+
+    #[test]
+    fn parses_header() {
+        let fdt = Fdt::new(RPI3_DTB).unwrap();
+        assert_eq!(fdt.total_size(), RPI3_DTB.len());
+        assert!(fdt.reserved_entries().eq([(0, 0x1000)]));
+
+        assert_eq!(Fdt::new(&RPI3_DTB[..64]).err(), Some(FdtError::Truncated));
+        assert_eq!(Fdt::new(&RPI3_DTB[4..]).err(), Some(FdtError::BadMagic));
+    }
+
+    #[test]
+    fn checks_last_compatible_version() {
+        let mut blob = RPI3_DTB.to_vec();
+        // A newer version that is still compatible with version 16
+        blob[20..24].copy_from_slice(&18u32.to_be_bytes());
+        blob[24..28].copy_from_slice(&16u32.to_be_bytes());
+        assert!(Fdt::new(&blob).is_ok());
+
+        blob[24..28].copy_from_slice(&18u32.to_be_bytes());
+        assert_eq!(Fdt::new(&blob).err(), Some(FdtError::UnsupportedVersion));
+    }
+
+    #[test]
+    fn walks_nodes_and_properties() {
+        let fdt = Fdt::new(RPI4_DTB).unwrap();
+        let root = fdt.root().unwrap();
+        assert_eq!(root.name(), "");
+        assert_eq!(root.address_cells(), 2);
+        assert_eq!(root.size_cells(), 1);
+
+        let reserved = root.child("reserved-memory").unwrap();
+        assert_eq!(reserved.address_cells(), 2);
+        assert_eq!(reserved.size_cells(), 1);
+        let cma = reserved.child("linux,cma").unwrap();
+        assert_eq!(cma.property("size").unwrap().u32(0), Some(0x4000000));
+        assert_eq!(
+            cma.property("compatible").unwrap().as_str(),
+            Some("shared-dma-pool")
+        );
+        assert!(cma.children().next().is_none());
+
+        // Siblings that follow a node with children must still be found
+        let chosen = root.child("chosen").unwrap();
+        assert!(chosen.property("bootargs").is_some());
+    }
+
+    #[test]
+    fn decodes_reg() {
+        let fdt = Fdt::new(RPI3_DTB).unwrap();
+        let root = fdt.root().unwrap();
+        let memory = root.child("memory").unwrap();
+        assert_eq!(memory.name(), "memory@0");
+        let reg = memory.property("reg").unwrap();
+        assert!(reg
+            .reg_entries(root.address_cells(), root.size_cells())
+            .eq([(0, 0)]));
+    }
+
+    #[test]
+    fn decodes_ranges() {
+        let fdt = Fdt::new(RPI4_DTB).unwrap();
+        let root = fdt.root().unwrap();
+        let soc = root.child("soc").unwrap();
+        let ranges = soc.property("ranges").unwrap();
+        assert!(ranges
+            .range_entries(soc.address_cells(), root.address_cells(), soc.size_cells())
+            .eq([
+                (0x7E000000, 0xFE000000, 0x1800000),
+                (0x7C000000, 0xFC000000, 0x2000000),
+                (0x40000000, 0xFF800000, 0x800000),
+            ]));
+    }
+}
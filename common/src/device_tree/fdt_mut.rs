@@ -0,0 +1,457 @@
+//! A writable copy of a flattened device tree
+//!
+//! FdtMut keeps a blob packed at the start of a caller supplied buffer, in the usual order of header, memory
+//! reservation block, structure block and strings block. The rest of the buffer is room for the tree to
+//! grow into. Every edit leaves a valid blob behind, so the tree can be read back through as_fdt at any
+//! point, and an edit that doesn't fit fails without changing anything.
+//!
+//! Nodes are referred to by the offset of their FDT_BEGIN_NODE token, as returned by FdtNode::offset. An
+//! edit moves everything that comes after it in the structure block, so only the offsets of the edited node
+//! and its ancestors remain valid across it. Anything else must be looked up again.
+
+use super::{
+    fdt::{
+        read_u32, Cursor, Fdt, FdtNode, Token, FDT_BEGIN_NODE, FDT_END_NODE, FDT_PROP, FDT_VERSION,
+        HEADER_SIZE,
+    },
+    DeviceTree,
+};
+use crate::util::error::FdtError;
+
+/// The oldest version of the format that our blobs remain readable by
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+// Indices of the header fields we maintain
+const TOTAL_SIZE: usize = 1;
+const OFF_DT_STRUCT: usize = 2;
+const OFF_DT_STRINGS: usize = 3;
+const OFF_MEM_RSVMAP: usize = 4;
+const VERSION: usize = 5;
+const LAST_COMP_VERSION: usize = 6;
+const SIZE_DT_STRINGS: usize = 8;
+const SIZE_DT_STRUCT: usize = 9;
+
+/// Where a property of a node is, or would go if the node doesn't have it yet
+enum PropertySlot {
+    /// Offset of the value in the structure block, and its length in bytes
+    Existing { value: usize, len: usize },
+    /// Offset in the structure block a new property would be inserted at
+    Missing { offset: usize },
+}
+
+pub struct FdtMut<'a> {
+    buf: &'a mut [u8],
+}
+
+impl<'a> FdtMut<'a> {
+    /// Copies blob into buf, which must be at least as large as the blob to be of any use
+    pub fn new(buf: &'a mut [u8], blob: &[u8]) -> Result<Self, FdtError> {
+        let size = Fdt::new(blob)?.total_size();
+        buf.get_mut(..size)
+            .ok_or(FdtError::NoSpace)?
+            .copy_from_slice(&blob[..size]);
+        Self::open(buf)
+    }
+
+    /// Opens the blob at the start of buf for editing in place, making the rest of buf available to it
+    ///
+    /// Any gaps between the blocks of the blob are squeezed out first. Blobs whose blocks aren't in the
+    /// usual order are rejected with BadLayout.
+    pub fn open(buf: &'a mut [u8]) -> Result<Self, FdtError> {
+        let fdt = Fdt::new(buf)?;
+        let header = |field: usize| read_u32(buf, field * 4).unwrap() as usize;
+        // The reservation block is terminated by an all zero entry, which is kept
+        let rsvmap_size = (fdt.reserved_entries().count() + 1) * 16;
+        let (rsvmap, structs, strings) = (
+            header(OFF_MEM_RSVMAP),
+            header(OFF_DT_STRUCT),
+            header(OFF_DT_STRINGS),
+        );
+        let (structs_size, strings_size) = (header(SIZE_DT_STRUCT), header(SIZE_DT_STRINGS));
+        if rsvmap < HEADER_SIZE
+            || rsvmap % 8 != 0
+            || rsvmap + rsvmap_size > structs
+            || structs + structs_size > strings
+        {
+            return Err(FdtError::BadLayout);
+        }
+
+        // Moving every block towards the start of the buffer in order can't overwrite a block that has yet
+        // to be moved
+        let new_structs = HEADER_SIZE + rsvmap_size;
+        let new_strings = new_structs + structs_size;
+        buf.copy_within(rsvmap..rsvmap + rsvmap_size, HEADER_SIZE);
+        buf.copy_within(structs..structs + structs_size, new_structs);
+        buf.copy_within(strings..strings + strings_size, new_strings);
+
+        let mut fdt = Self { buf };
+        fdt.set_header(OFF_MEM_RSVMAP, HEADER_SIZE);
+        fdt.set_header(OFF_DT_STRUCT, new_structs);
+        fdt.set_header(OFF_DT_STRINGS, new_strings);
+        fdt.set_header(TOTAL_SIZE, new_strings + strings_size);
+        fdt.set_header(VERSION, FDT_VERSION as usize);
+        fdt.set_header(LAST_COMP_VERSION, FDT_LAST_COMP_VERSION as usize);
+        Ok(fdt)
+    }
+
+    pub fn as_fdt(&self) -> Fdt<'_> {
+        // Every edit leaves a valid blob behind
+        Fdt::new(self.buf).unwrap()
+    }
+
+    pub fn device_tree(&self) -> DeviceTree<'_> {
+        DeviceTree::new(self.as_fdt())
+    }
+
+    /// The blob as it currently stands
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.total_size()]
+    }
+
+    /// Gives up editing, returning the blob as it currently stands
+    pub fn into_bytes(self) -> &'a [u8] {
+        let size = self.total_size();
+        let buf: &'a [u8] = self.buf;
+        &buf[..size]
+    }
+
+    /// Size in bytes of the blob
+    pub fn total_size(&self) -> usize {
+        self.header(TOTAL_SIZE)
+    }
+
+    /// Size in bytes of the buffer the blob can grow into
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Finds a node by path or alias, in the same way as DeviceTree::find_node
+    pub fn find_node(&self, path: &str) -> Option<usize> {
+        self.device_tree().find_node(path).map(|node| node.offset())
+    }
+
+    pub fn node(&self, node: usize) -> Option<FdtNode<'_>> {
+        self.as_fdt().node_at(node)
+    }
+
+    /// Sets a property of node, replacing its value if the node already has it
+    pub fn set_property(&mut self, node: usize, name: &str, value: &[u8]) -> Result<(), FdtError> {
+        self.reserve_property(node, name, value.len())?
+            .copy_from_slice(value);
+        Ok(())
+    }
+
+    /// Sets a property of node to a single null terminated string
+    pub fn set_property_str(
+        &mut self,
+        node: usize,
+        name: &str,
+        value: &str,
+    ) -> Result<(), FdtError> {
+        // The terminator is already there, as the reserved value starts out zeroed
+        self.reserve_property(node, name, value.len() + 1)?[..value.len()]
+            .copy_from_slice(value.as_bytes());
+        Ok(())
+    }
+
+    pub fn set_property_u32(
+        &mut self,
+        node: usize,
+        name: &str,
+        value: u32,
+    ) -> Result<(), FdtError> {
+        self.set_property(node, name, &value.to_be_bytes())
+    }
+
+    /// Removes a property from node, returning false if the node didn't have it
+    pub fn delete_property(&mut self, node: usize, name: &str) -> Result<bool, FdtError> {
+        match self.property_slot(node, name)? {
+            PropertySlot::Existing { value, len } => {
+                // Take the token, length and name offset along with the value
+                self.resize_structs(value - 12, 12 + len.next_multiple_of(4), 0)?;
+                Ok(true)
+            }
+            PropertySlot::Missing { .. } => Ok(false),
+        }
+    }
+
+    /// Returns the child of parent with exactly the given name, adding an empty one if there is none
+    pub fn add_subnode(&mut self, parent: usize, name: &str) -> Result<usize, FdtError> {
+        if name.is_empty() || name.contains(['/', '\0']) {
+            return Err(FdtError::InvalidName);
+        }
+
+        let mut cursor = self.cursor_at(parent)?;
+        loop {
+            let offset = cursor.offset;
+            match cursor.next_token().ok_or(FdtError::Truncated)? {
+                Token::Prop(_) => (),
+                Token::BeginNode(child) => {
+                    let child_offset = cursor.offset - (child.len() + 1).next_multiple_of(4) - 4;
+                    if child == name {
+                        return Ok(child_offset);
+                    }
+                    cursor.skip_subtree().ok_or(FdtError::Truncated)?;
+                }
+                // New children go last, right before the parent's FDT_END_NODE
+                Token::EndNode => {
+                    let size = 4 + (name.len() + 1).next_multiple_of(4) + 4;
+                    let start = self.resize_structs(offset, 0, size)?;
+                    self.write_u32(start, FDT_BEGIN_NODE);
+                    self.buf[start + 4..start + 4 + name.len()].copy_from_slice(name.as_bytes());
+                    self.write_u32(start + size - 4, FDT_END_NODE);
+                    return Ok(offset);
+                }
+                Token::End => return Err(FdtError::Truncated),
+            }
+        }
+    }
+
+    /// Replaces the 32 bit cell at byte offset of an existing property with f applied to it
+    pub(super) fn update_cell<F: FnOnce(u32) -> u32>(
+        &mut self,
+        node: usize,
+        name: &str,
+        offset: usize,
+        f: F,
+    ) -> Result<(), FdtError> {
+        let PropertySlot::Existing { value, len } = self.property_slot(node, name)? else {
+            return Err(FdtError::NotFound);
+        };
+        if offset + 4 > len {
+            return Err(FdtError::NotFound);
+        }
+        let pos = self.header(OFF_DT_STRUCT) + value + offset;
+        let cell = read_u32(self.buf, pos).unwrap();
+        self.write_u32(pos, f(cell));
+        Ok(())
+    }
+
+    /// Makes room for a len byte value of the property name of node, and returns the zeroed value
+    fn reserve_property(
+        &mut self,
+        node: usize,
+        name: &str,
+        len: usize,
+    ) -> Result<&mut [u8], FdtError> {
+        let start = match self.property_slot(node, name)? {
+            PropertySlot::Existing {
+                value,
+                len: old_len,
+            } => {
+                let start = self.resize_structs(
+                    value,
+                    old_len.next_multiple_of(4),
+                    len.next_multiple_of(4),
+                )?;
+                self.write_u32(start - 8, len as u32);
+                start
+            }
+            PropertySlot::Missing { offset } => {
+                // Check for room up front, so that we don't add the name only to fail on the property
+                let size = 12 + len.next_multiple_of(4);
+                if self.total_size() + size + name.len() + 1 > self.buf.len() {
+                    return Err(FdtError::NoSpace);
+                }
+                let name_offset = self.string_offset(name)?;
+                let start = self.resize_structs(offset, 0, size)?;
+                self.write_u32(start, FDT_PROP);
+                self.write_u32(start + 4, len as u32);
+                self.write_u32(start + 8, name_offset);
+                start + 12
+            }
+        };
+
+        Ok(&mut self.buf[start..start + len])
+    }
+
+    fn property_slot(&self, node: usize, name: &str) -> Result<PropertySlot, FdtError> {
+        let mut cursor = self.cursor_at(node)?;
+        loop {
+            let offset = cursor.offset;
+            match cursor.next_token().ok_or(FdtError::Truncated)? {
+                Token::Prop(prop) if prop.name == name => {
+                    let len = prop.value.len();
+                    return Ok(PropertySlot::Existing {
+                        value: cursor.offset - len.next_multiple_of(4),
+                        len,
+                    });
+                }
+                Token::Prop(_) => (),
+                // Properties must come before any children
+                _ => return Ok(PropertySlot::Missing { offset }),
+            }
+        }
+    }
+
+    /// Returns a cursor positioned just past the name of node
+    fn cursor_at(&self, node: usize) -> Result<Cursor<'_>, FdtError> {
+        let mut cursor = Cursor::new(self.as_fdt(), node);
+        match cursor.next_token() {
+            Some(Token::BeginNode(_)) => Ok(cursor),
+            _ => Err(FdtError::NotFound),
+        }
+    }
+
+    /// Returns the offset of name in the strings block, appending it if it isn't there yet
+    fn string_offset(&mut self, name: &str) -> Result<u32, FdtError> {
+        let strings_start = self.header(OFF_DT_STRINGS);
+        let strings = &self.buf[strings_start..self.total_size()];
+        // Names may also share the tail of a longer string
+        let existing = (0..strings.len()).find(|&offset| {
+            strings[offset..].starts_with(name.as_bytes())
+                && strings.get(offset + name.len()) == Some(&0)
+        });
+        if let Some(offset) = existing {
+            return Ok(offset as u32);
+        }
+
+        let end = self.total_size();
+        let new_end = end + name.len() + 1;
+        if new_end > self.buf.len() {
+            return Err(FdtError::NoSpace);
+        }
+        self.buf[end..new_end - 1].copy_from_slice(name.as_bytes());
+        self.buf[new_end - 1] = 0;
+        let strings_size = self.header(SIZE_DT_STRINGS);
+        self.set_header(SIZE_DT_STRINGS, strings_size + name.len() + 1);
+        self.set_header(TOTAL_SIZE, new_end);
+        Ok(strings_size as u32)
+    }
+
+    /// Replaces remove bytes at offset in the structure block with insert zeroed bytes, moving everything
+    /// after them along. Returns the position of the inserted bytes in the buffer.
+    fn resize_structs(
+        &mut self,
+        offset: usize,
+        remove: usize,
+        insert: usize,
+    ) -> Result<usize, FdtError> {
+        let total_size = self.total_size();
+        let new_total_size = total_size + insert - remove;
+        if new_total_size > self.buf.len() {
+            return Err(FdtError::NoSpace);
+        }
+
+        let start = self.header(OFF_DT_STRUCT) + offset;
+        self.buf
+            .copy_within(start + remove..total_size, start + insert);
+        self.buf[start..start + insert].fill(0);
+        let structs_size = self.header(SIZE_DT_STRUCT);
+        let strings = self.header(OFF_DT_STRINGS);
+        self.set_header(SIZE_DT_STRUCT, structs_size + insert - remove);
+        self.set_header(OFF_DT_STRINGS, strings + insert - remove);
+        self.set_header(TOTAL_SIZE, new_total_size);
+        Ok(start)
+    }
+
+    fn header(&self, field: usize) -> usize {
+        read_u32(self.buf, field * 4).unwrap() as usize
+    }
+
+    fn set_header(&mut self, field: usize, value: usize) {
+        self.write_u32(field * 4, value as u32);
+    }
+
+    fn write_u32(&mut self, pos: usize, value: u32) {
+        self.buf[pos..pos + 4].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FdtMut;
+    use crate::{device_tree::fdt::Fdt, util::error::FdtError};
+
+    const RPI4_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2711-rpi-4-b.dtb");
+
+    #[test]
+    fn copies_blob() {
+        let mut buf = vec![0; RPI4_DTB.len() + 0x100];
+        let fdt = FdtMut::new(&mut buf, RPI4_DTB).unwrap();
+        let original = Fdt::new(RPI4_DTB).unwrap();
+        assert!(fdt
+            .as_fdt()
+            .reserved_entries()
+            .eq(original.reserved_entries()));
+        assert!(fdt
+            .as_fdt()
+            .nodes()
+            .map(|node| node.name())
+            .eq(original.nodes().map(|node| node.name())));
+        assert_eq!(fdt.capacity(), RPI4_DTB.len() + 0x100);
+
+        let mut small = vec![0; 0x100];
+        assert_eq!(
+            FdtMut::new(&mut small, RPI4_DTB).err(),
+            Some(FdtError::NoSpace)
+        );
+    }
+
+    #[test]
+    fn edits_properties_and_nodes() {
+        let mut buf = vec![0; RPI4_DTB.len() + 0x1000];
+        let mut fdt = FdtMut::new(&mut buf, RPI4_DTB).unwrap();
+        let chosen = fdt.find_node("/chosen").unwrap();
+
+        // Replacing a value with a longer, then a shorter one
+        fdt.set_property_str(chosen, "bootargs", "console=serial0 loglevel=debug")
+            .unwrap();
+        fdt.set_property_str(chosen, "bootargs", "quiet").unwrap();
+        fdt.set_property_u32(chosen, "lantern,test", 0x1234)
+            .unwrap();
+        let node = fdt.add_subnode(chosen, "lantern").unwrap();
+        fdt.set_property_str(node, "status", "okay").unwrap();
+        assert_eq!(fdt.add_subnode(chosen, "lantern"), Ok(node));
+        assert_eq!(fdt.add_subnode(chosen, "a/b"), Err(FdtError::InvalidName));
+
+        let dt = fdt.device_tree();
+        let chosen = dt.find_node("/chosen").unwrap();
+        assert_eq!(chosen.property("bootargs").unwrap().as_str(), Some("quiet"));
+        assert_eq!(
+            chosen.property("lantern,test").unwrap().u32(0),
+            Some(0x1234)
+        );
+        let node = dt.find_node("/chosen/lantern").unwrap();
+        assert_eq!(node.property("status").unwrap().as_str(), Some("okay"));
+        // Nodes after the edits must be unaffected
+        assert_eq!(dt.cpus().count(), 4);
+        assert!(dt.find_node("/soc/serial@7e201000").is_some());
+
+        let chosen = fdt.find_node("/chosen").unwrap();
+        assert_eq!(fdt.delete_property(chosen, "lantern,test"), Ok(true));
+        assert_eq!(fdt.delete_property(chosen, "lantern,test"), Ok(false));
+        assert!(fdt
+            .device_tree()
+            .find_node("/chosen")
+            .unwrap()
+            .property("lantern,test")
+            .is_none());
+    }
+
+    #[test]
+    fn failed_edits_leave_the_tree_intact() {
+        let mut buf = RPI4_DTB.to_vec();
+        let mut fdt = FdtMut::new(&mut buf, RPI4_DTB).unwrap();
+        let chosen = fdt.find_node("/chosen").unwrap();
+        assert_eq!(
+            fdt.set_property(chosen, "bootargs", &[1; 0x1000]),
+            Err(FdtError::NoSpace)
+        );
+        assert_eq!(fdt.as_bytes(), RPI4_DTB);
+    }
+
+    #[test]
+    fn reopens_edited_blob_in_place() {
+        let mut buf = vec![0; RPI4_DTB.len() + 0x1000];
+        let size = {
+            let mut fdt = FdtMut::new(&mut buf, RPI4_DTB).unwrap();
+            let root = fdt.find_node("/").unwrap();
+            fdt.add_subnode(root, "extra@0").unwrap();
+            fdt.total_size()
+        };
+        let fdt = FdtMut::open(&mut buf).unwrap();
+        assert_eq!(fdt.total_size(), size);
+        assert!(fdt.find_node("/extra@0").is_some());
+    }
+}
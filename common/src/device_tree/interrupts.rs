@@ -0,0 +1,263 @@
+//! Decoding of interrupt specifiers
+//!
+//! A specifier is only meaningful to the controller it belongs to, so decoding is driven by the compatible
+//! string of that controller. The controllers found on the Raspberry Pi 3 (BCM2835 ARM control chained
+//! behind the BCM2836 per-core controller) and Raspberry Pi 4 (GIC-400) are understood. Specifiers of any
+//! other controller are still resolved to their controller, but left undecoded.
+
+use super::{fdt::FdtNode, Specifier};
+
+/// Interrupt IDs of the first private and shared peripheral interrupts of a GIC
+const GIC_PPI_BASE: u32 = 16;
+const GIC_SPI_BASE: u32 = 32;
+
+/// How an interrupt line signals, encoded as in the IRQ_TYPE_* flags of the device tree bindings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    /// The binding doesn't say, so the controller's default applies
+    Unspecified,
+    EdgeRising,
+    EdgeFalling,
+    EdgeBoth,
+    LevelHigh,
+    LevelLow,
+}
+
+impl Trigger {
+    fn from_flags(flags: u32) -> Self {
+        match flags & 0xF {
+            1 => Trigger::EdgeRising,
+            2 => Trigger::EdgeFalling,
+            3 => Trigger::EdgeBoth,
+            4 => Trigger::LevelHigh,
+            8 => Trigger::LevelLow,
+            _ => Trigger::Unspecified,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GicInterruptType {
+    /// Shared peripheral interrupt, which can be routed to any core
+    Spi,
+    /// Private peripheral interrupt, of which every core has its own copy
+    Ppi,
+}
+
+/// An interrupt specifier, decoded according to the controller it belongs to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptLine {
+    /// An interrupt of the BCM2835 ARM control block. Bank 0 holds the ARM specific interrupts of the basic
+    /// pending register, while banks 1 and 2 hold GPU interrupts 0-31 and 32-63.
+    Bcm2835 { bank: u32, irq: u32 },
+    /// One of the BCM2836 per-core interrupt sources (eg the timers, mailboxes, or 8 for the GPU)
+    Bcm2836Local { irq: u32, trigger: Trigger },
+    /// An interrupt of a GIC, where intid is the ID the GIC knows it by. cpu_mask is only set for private
+    /// interrupts, and selects the cores the interrupt is wired to.
+    Gic {
+        kind: GicInterruptType,
+        number: u32,
+        intid: u32,
+        trigger: Trigger,
+        cpu_mask: u8,
+    },
+    /// A specifier of a controller we don't know how to decode
+    Unknown,
+}
+
+/// A single interrupt of a device, along with the controller it is delivered to
+#[derive(Clone, Copy)]
+pub struct Interrupt<'a> {
+    pub specifier: Specifier<'a>,
+    pub line: InterruptLine,
+}
+
+impl<'a> Interrupt<'a> {
+    /// Decodes specifier according to the format of the controller it belongs to
+    pub fn decode(specifier: Specifier<'a>) -> Self {
+        let controller = specifier.provider;
+        let line = match *specifier.cells() {
+            [bank, irq]
+                if controller.is_compatible("brcm,bcm2835-armctrl-ic")
+                    || controller.is_compatible("brcm,bcm2836-armctrl-ic") =>
+            {
+                InterruptLine::Bcm2835 { bank, irq }
+            }
+            [irq, flags] if controller.is_compatible("brcm,bcm2836-l1-intc") => {
+                InterruptLine::Bcm2836Local {
+                    irq,
+                    trigger: Trigger::from_flags(flags),
+                }
+            }
+            [kind, number, flags] if is_gic(&controller) => {
+                let (kind, intid) = match kind {
+                    0 => (GicInterruptType::Spi, GIC_SPI_BASE + number),
+                    1 => (GicInterruptType::Ppi, GIC_PPI_BASE + number),
+                    _ => return Self::undecoded(specifier),
+                };
+                InterruptLine::Gic {
+                    kind,
+                    number,
+                    intid,
+                    trigger: Trigger::from_flags(flags),
+                    cpu_mask: (flags >> 8) as u8,
+                }
+            }
+            _ => InterruptLine::Unknown,
+        };
+
+        Self { specifier, line }
+    }
+
+    /// The interrupt controller this interrupt is delivered to
+    pub fn controller(&self) -> FdtNode<'a> {
+        self.specifier.provider
+    }
+
+    fn undecoded(specifier: Specifier<'a>) -> Self {
+        Self {
+            specifier,
+            line: InterruptLine::Unknown,
+        }
+    }
+}
+
+fn is_gic(controller: &FdtNode) -> bool {
+    ["arm,gic-400", "arm,cortex-a15-gic", "arm,cortex-a7-gic"]
+        .iter()
+        .any(|compatible| controller.is_compatible(compatible))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GicInterruptType, InterruptLine, Trigger};
+    use crate::device_tree::{blob_builder::BlobBuilder, fdt::Fdt, DeviceTree};
+
+    const RPI3_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2710-rpi-3-b.dtb");
+    const RPI4_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2711-rpi-4-b.dtb");
+
+    #[test]
+    fn decodes_bcm2835_chained_to_bcm2836_local() {
+        let dt = DeviceTree::new(Fdt::new(RPI3_DTB).unwrap());
+        let uart = dt.find_node("/soc/serial@7e201000").unwrap();
+        let interrupt = dt.interrupts(&uart).next().unwrap();
+        assert_eq!(
+            interrupt.controller().name(),
+            "interrupt-controller@7e00b200"
+        );
+        assert_eq!(interrupt.line, InterruptLine::Bcm2835 { bank: 2, irq: 25 });
+
+        // The ARM control block is itself wired to the GPU interrupt of the per-core controller, which is
+        // the root of the chain
+        let cascade = dt.cascade(&interrupt.controller()).unwrap();
+        assert_eq!(cascade.controller().name(), "local_intc@40000000");
+        assert_eq!(
+            cascade.line,
+            InterruptLine::Bcm2836Local {
+                irq: 8,
+                trigger: Trigger::LevelHigh
+            }
+        );
+        assert!(dt.cascade(&cascade.controller()).is_none());
+
+        // The architected timer sits directly on the per-core controller
+        let timer = dt.find_node("/timer").unwrap();
+        let lines: Vec<_> = dt.interrupts(&timer).map(|irq| irq.line).collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[1],
+            InterruptLine::Bcm2836Local {
+                irq: 1,
+                trigger: Trigger::LevelHigh
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_gic() {
+        let dt = DeviceTree::new(Fdt::new(RPI4_DTB).unwrap());
+        let uart = dt.find_node("/soc/serial@7e201000").unwrap();
+        let interrupt = dt.interrupts(&uart).next().unwrap();
+        assert_eq!(
+            interrupt.controller().name(),
+            "interrupt-controller@40041000"
+        );
+        assert_eq!(
+            interrupt.line,
+            InterruptLine::Gic {
+                kind: GicInterruptType::Spi,
+                number: 0x79,
+                intid: 153,
+                trigger: Trigger::LevelHigh,
+                cpu_mask: 0
+            }
+        );
+
+        let timer = dt.find_node("/timer").unwrap();
+        let physical = dt.interrupts(&timer).nth(1).unwrap();
+        assert_eq!(
+            physical.line,
+            InterruptLine::Gic {
+                kind: GicInterruptType::Ppi,
+                number: 14,
+                intid: 30,
+                trigger: Trigger::LevelLow,
+                cpu_mask: 0xF
+            }
+        );
+        // The GIC is the root controller, its own interrupt is delivered to itself
+        assert!(dt.cascade(&physical.controller()).is_none());
+    }
+
+    #[test]
+    fn resolves_interrupts_extended_and_inherited_parents() {
+        let blob = BlobBuilder::default()
+            .begin("")
+            .prop("interrupt-parent", &[1])
+            .begin("gic")
+            .prop("compatible", &[])
+            .prop("interrupt-controller", &[])
+            .prop("#interrupt-cells", &[3])
+            .prop("phandle", &[1])
+            .end()
+            .begin("gpio")
+            .prop("interrupt-controller", &[])
+            .prop("#interrupt-cells", &[2])
+            .prop("interrupts", &[0, 5, 4])
+            .prop("phandle", &[2])
+            .end()
+            .begin("bus")
+            .begin("device")
+            .prop("interrupts-extended", &[1, 0, 7, 1, 2, 17, 2])
+            .prop("interrupts", &[0, 1, 4])
+            .end()
+            .begin("inherits")
+            .prop("interrupts", &[0, 3, 4, 0, 4, 4])
+            .end()
+            .end()
+            .end()
+            .build();
+        let dt = DeviceTree::new(Fdt::new(&blob).unwrap());
+
+        // interrupts-extended takes precedence, and every entry names its own controller
+        let device = dt.find_node("/bus/device").unwrap();
+        let interrupts: Vec<_> = dt.interrupts(&device).collect();
+        assert_eq!(interrupts.len(), 2);
+        assert_eq!(interrupts[0].controller().name(), "gic");
+        assert_eq!(interrupts[0].specifier.cells(), [0, 7, 1]);
+        assert_eq!(interrupts[1].controller().name(), "gpio");
+        assert_eq!(interrupts[1].specifier.cells(), [17, 2]);
+        assert_eq!(interrupts[1].line, InterruptLine::Unknown);
+
+        // Without an interrupt-parent of its own, a node uses its closest ancestor's
+        let inherits = dt.find_node("/bus/inherits").unwrap();
+        assert_eq!(dt.interrupt_parent(&inherits).unwrap().name(), "gic");
+        assert!(dt
+            .interrupts(&inherits)
+            .map(|irq| irq.specifier.cells().to_vec())
+            .eq([vec![0, 3, 4], vec![0, 4, 4]]));
+
+        let gpio = dt.find_node("/gpio").unwrap();
+        assert_eq!(dt.cascade(&gpio).unwrap().controller().name(), "gic");
+    }
+}
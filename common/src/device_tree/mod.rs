@@ -0,0 +1,375 @@
+//! Queries over a flattened device tree
+//!
+//! The fdt module only knows how to walk the raw blob. DeviceTree builds on it to answer the questions
+//! drivers and boot code actually ask: where is a node, which nodes are compatible with a driver, and at
+//! which CPU physical address do a device's registers live.
+
+use self::{
+    fdt::{Ancestors, Fdt, FdtNode, FdtProp},
+    interrupts::Interrupt,
+};
+
+#[cfg(test)]
+mod blob_builder;
+pub mod cpus;
+pub mod fdt;
+pub mod fdt_mut;
+pub mod interrupts;
+pub mod overlay;
+
+/// The most cells a single specifier (eg an interrupt or clock) may consist of
+pub const MAX_SPECIFIER_CELLS: usize = 4;
+
+#[derive(Clone, Copy)]
+pub struct DeviceTree<'a> {
+    fdt: Fdt<'a>,
+}
+
+impl<'a> DeviceTree<'a> {
+    pub fn new(fdt: Fdt<'a>) -> Self {
+        Self { fdt }
+    }
+
+    pub fn fdt(&self) -> Fdt<'a> {
+        self.fdt
+    }
+
+    pub fn root(&self) -> Option<FdtNode<'a>> {
+        self.fdt.root()
+    }
+
+    /// Finds a node by its full path (eg "/soc/serial@7e201000"), or by a path that starts with one of the
+    /// aliases defined in /aliases (eg "serial0"). Components without a unit address match any unit address.
+    pub fn find_node(&self, path: &str) -> Option<FdtNode<'a>> {
+        let (mut node, rest) = match path.strip_prefix('/') {
+            Some(rest) => (self.root()?, rest),
+            None => {
+                let (alias, rest) = path.split_once('/').unwrap_or((path, ""));
+                let target = self.root()?.child("aliases")?.property(alias)?.as_str()?;
+                // Aliases must hold full paths, which also stops us from recursing forever
+                if !target.starts_with('/') {
+                    return None;
+                }
+                (self.find_node(target)?, rest)
+            }
+        };
+
+        for component in rest.split('/').filter(|component| !component.is_empty()) {
+            node = node.child(component)?;
+        }
+        Some(node)
+    }
+
+    /// Iterates over every enabled node that is compatible with the given compatible string
+    pub fn find_compatible<'b>(&self, compatible: &'b str) -> impl Iterator<Item = FdtNode<'a>> + 'b
+    where
+        'a: 'b,
+    {
+        self.fdt
+            .nodes()
+            .filter(move |node| node.is_compatible(compatible) && node.is_enabled())
+    }
+
+    pub fn find_phandle(&self, phandle: u32) -> Option<FdtNode<'a>> {
+        self.fdt
+            .nodes()
+            .find(|node| node.phandle() == Some(phandle))
+    }
+
+    /// Decodes the reg property of node into (address, size) pairs, without any translation. The addresses
+    /// are in the address space of the bus node sits on, and are decoded with that bus' cell sizes.
+    pub fn raw_reg(&self, node: &FdtNode<'a>) -> Option<impl Iterator<Item = (u64, u64)> + 'a> {
+        let parent = self.fdt.ancestors(node)?.parent()?;
+        let reg = node.property("reg")?;
+        Some(reg.reg_entries(parent.address_cells(), parent.size_cells()))
+    }
+
+    /// Decodes the reg property of node into (address, size) pairs, with every address translated to a CPU
+    /// physical address. Entries that are not visible to the CPU are skipped.
+    pub fn reg(&self, node: &FdtNode<'a>) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let ancestors = self.fdt.ancestors(node);
+        let entries = ancestors.as_ref().and_then(|ancestors| {
+            let parent = ancestors.parent()?;
+            let reg = node.property("reg")?;
+            Some(reg.reg_entries(parent.address_cells(), parent.size_cells()))
+        });
+
+        entries
+            .into_iter()
+            .flatten()
+            .filter_map(move |(address, size)| {
+                Some((translate(ancestors.as_ref()?, address, size)?, size))
+            })
+    }
+
+    /// Translates an address from the reg property of node to a CPU physical address
+    pub fn translate(&self, node: &FdtNode<'a>, address: u64) -> Option<u64> {
+        translate(&self.fdt.ancestors(node)?, address, 1)
+    }
+
+    /// Finds the controller that node's interrupts are delivered to, which is named by the interrupt-parent
+    /// property of the node itself or of its closest ancestor that has one
+    pub fn interrupt_parent(&self, node: &FdtNode<'a>) -> Option<FdtNode<'a>> {
+        let ancestors = self.fdt.ancestors(node)?;
+        let phandle = core::iter::once(*node)
+            .chain(
+                (0..ancestors.len())
+                    .rev()
+                    .filter_map(|depth| ancestors.get(depth)),
+            )
+            .find_map(|node| node.property("interrupt-parent"))?
+            .u32(0)?;
+        self.find_phandle(phandle)
+    }
+
+    /// Resolves every interrupt of node to the controller it is delivered to, and decodes it according to
+    /// that controller's format
+    ///
+    /// Interrupts come from interrupts-extended if node has it, where every entry names its own controller.
+    /// Otherwise, the interrupts property is split using the #interrupt-cells of node's interrupt parent.
+    pub fn interrupts(&self, node: &FdtNode<'a>) -> impl Iterator<Item = Interrupt<'a>> + 'a {
+        let extended = node
+            .property("interrupts-extended")
+            .map(|_| self.phandle_specifiers(node, "interrupts-extended", "#interrupt-cells"));
+
+        let (parent, interrupts, num_cells) = match extended {
+            Some(_) => (None, None, 0),
+            None => {
+                let parent = self.interrupt_parent(node);
+                let num_cells = parent
+                    .and_then(|parent| parent.property("#interrupt-cells")?.u32(0))
+                    .unwrap_or(0) as usize;
+                (
+                    parent,
+                    node.property("interrupts").filter(|_| num_cells != 0),
+                    num_cells,
+                )
+            }
+        };
+        let mut idx = 0;
+        let plain = core::iter::from_fn(move || {
+            let specifier = Specifier::read(parent?, &interrupts?, idx, num_cells)?;
+            idx += num_cells;
+            Some(specifier)
+        });
+
+        extended
+            .into_iter()
+            .flatten()
+            .chain(plain)
+            .map(Interrupt::decode)
+    }
+
+    /// Returns the interrupt through which controller forwards its own interrupts to its parent controller,
+    /// or None if controller is the root of its interrupt tree
+    pub fn cascade(&self, controller: &FdtNode<'a>) -> Option<Interrupt<'a>> {
+        let interrupt = self.interrupts(controller).next()?;
+        // The root controller may name itself as its interrupt parent
+        if interrupt.controller().name().as_ptr() == controller.name().as_ptr() {
+            return None;
+        }
+        Some(interrupt)
+    }
+
+    /// Decodes the clocks property of node into one specifier per clock input
+    pub fn clocks(&self, node: &FdtNode<'a>) -> impl Iterator<Item = Specifier<'a>> + 'a {
+        self.phandle_specifiers(node, "clocks", "#clock-cells")
+    }
+
+    /// Decodes a list of (phandle, args...) specifiers, such as clocks or dmas, where the number of args of
+    /// each entry is given by the cells_name property of the node the phandle refers to
+    pub fn phandle_specifiers(
+        &self,
+        node: &FdtNode<'a>,
+        list_name: &str,
+        cells_name: &'a str,
+    ) -> impl Iterator<Item = Specifier<'a>> + 'a {
+        let dt = *self;
+        let list = node.property(list_name);
+
+        let mut idx = 0;
+        core::iter::from_fn(move || {
+            let list = list?;
+            let provider = dt.find_phandle(list.u32(idx)?)?;
+            let num_cells = match provider.property(cells_name) {
+                Some(prop) => prop.u32(0)? as usize,
+                None => 0,
+            };
+            let specifier = Specifier::read(provider, &list, idx + 1, num_cells)?;
+            idx += 1 + num_cells;
+            Some(specifier)
+        })
+    }
+}
+
+/// A reference to a resource provided by another node (eg an interrupt line or clock), along with the
+/// provider specific cells that select the resource
+#[derive(Clone, Copy)]
+pub struct Specifier<'a> {
+    pub provider: FdtNode<'a>,
+    cells: [u32; MAX_SPECIFIER_CELLS],
+    len: usize,
+}
+
+impl<'a> Specifier<'a> {
+    pub fn cells(&self) -> &[u32] {
+        &self.cells[..self.len]
+    }
+
+    /// Reads num_cells cells of prop, starting at cell idx
+    fn read(
+        provider: FdtNode<'a>,
+        prop: &FdtProp<'a>,
+        idx: usize,
+        num_cells: usize,
+    ) -> Option<Self> {
+        if num_cells > MAX_SPECIFIER_CELLS {
+            return None;
+        }
+        let mut cells = [0; MAX_SPECIFIER_CELLS];
+        for (cell, value) in cells.iter_mut().take(num_cells).enumerate() {
+            *value = prop.u32(idx + cell)?;
+        }
+
+        Some(Self {
+            provider,
+            cells,
+            len: num_cells,
+        })
+    }
+}
+
+/// Translates a region at address on the bus formed by the last of ancestors, all the way up to the root
+/// through the ranges property of every bus in between
+fn translate(ancestors: &Ancestors, mut address: u64, size: u64) -> Option<u64> {
+    // The root's address space is the CPU's, so only the buses below it need translating
+    for depth in (1..ancestors.len()).rev() {
+        let bus = ancestors.get(depth)?;
+        let parent = ancestors.get(depth - 1)?;
+        // A bus without ranges is not memory mapped at all, while an empty ranges is an identity mapping
+        let ranges = bus.property("ranges")?;
+        if ranges.value.is_empty() {
+            continue;
+        }
+
+        let (child_base, parent_base, _) = ranges
+            .range_entries(
+                bus.address_cells(),
+                parent.address_cells(),
+                bus.size_cells(),
+            )
+            .find(|&(child_base, _, range_size)| {
+                address >= child_base && address - child_base + size <= range_size
+            })?;
+        address = parent_base + (address - child_base);
+    }
+
+    Some(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fdt::Fdt, DeviceTree};
+
+    const RPI3_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2710-rpi-3-b.dtb");
+    const RPI4_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2711-rpi-4-b.dtb");
+
+    fn rpi3() -> DeviceTree<'static> {
+        DeviceTree::new(Fdt::new(RPI3_DTB).unwrap())
+    }
+
+    fn rpi4() -> DeviceTree<'static> {
+        DeviceTree::new(Fdt::new(RPI4_DTB).unwrap())
+    }
+
+    #[test]
+    fn finds_nodes_by_path_and_alias() {
+        let dt = rpi3();
+        assert_eq!(dt.find_node("/").unwrap().name(), "");
+        assert_eq!(
+            dt.find_node("/soc/serial@7e201000").unwrap().name(),
+            "serial@7e201000"
+        );
+        assert_eq!(dt.find_node("/chosen").unwrap().name(), "chosen");
+        assert_eq!(dt.find_node("serial0").unwrap().name(), "serial@7e215040");
+        assert_eq!(
+            dt.find_node("soc/serial@7e201000").unwrap().name(),
+            "serial@7e201000"
+        );
+        assert!(dt.find_node("/soc/serial@0").is_none());
+        assert!(dt.find_node("not-an-alias").is_none());
+    }
+
+    #[test]
+    fn finds_nodes_by_compatible_and_phandle() {
+        let dt = rpi4();
+        let mut mailboxes = dt.find_compatible("brcm,bcm2835-mbox");
+        assert_eq!(mailboxes.next().unwrap().name(), "mailbox@7e00b880");
+        assert!(mailboxes.next().is_none());
+
+        let phandle = dt
+            .root()
+            .unwrap()
+            .property("interrupt-parent")
+            .unwrap()
+            .u32(0)
+            .unwrap();
+        assert_eq!(
+            dt.find_phandle(phandle).unwrap().name(),
+            "interrupt-controller@40041000"
+        );
+        assert!(dt.find_phandle(0xFFFFFFFF).is_none());
+    }
+
+    #[test]
+    fn translates_reg_through_ranges() {
+        let dt = rpi3();
+        let uart = dt.find_node("/soc/serial@7e201000").unwrap();
+        assert!(dt.raw_reg(&uart).unwrap().eq([(0x7E201000, 0x200)]));
+        assert!(dt.reg(&uart).eq([(0x3F201000, 0x200)]));
+
+        let dt = rpi4();
+        let uart = dt.find_node("/soc/serial@7e201000").unwrap();
+        assert!(dt.reg(&uart).eq([(0xFE201000, 0x200)]));
+        // A bus with two address cells
+        let emmc = dt.find_node("/emmc2bus/mmc@7e340000").unwrap();
+        assert!(dt.reg(&emmc).eq([(0xFE340000, 0x100)]));
+        // A bus with two address and two size cells
+        let pcie = dt.find_node("/scb/pcie@7d500000").unwrap();
+        assert!(dt.raw_reg(&pcie).unwrap().eq([(0x7D500000, 0x9310)]));
+        assert!(dt.reg(&pcie).eq([(0xFD500000, 0x9310)]));
+        assert_eq!(dt.translate(&pcie, 0x7D500010), Some(0xFD500010));
+    }
+
+    #[test]
+    fn decodes_interrupts_and_clocks() {
+        let dt = rpi3();
+        let uart = dt.find_node("/soc/serial@7e201000").unwrap();
+        assert_eq!(
+            dt.interrupt_parent(&uart).unwrap().name(),
+            "interrupt-controller@7e00b200"
+        );
+        let mut interrupts = dt.interrupts(&uart);
+        assert_eq!(interrupts.next().unwrap().specifier.cells(), [2, 25]);
+        assert!(interrupts.next().is_none());
+
+        let clocks: Vec<_> = dt.clocks(&uart).collect();
+        assert_eq!(clocks.len(), 2);
+        assert_eq!(clocks[0].provider.name(), "cprman@7e101000");
+        assert_eq!(clocks[0].cells(), [0x13]);
+        assert_eq!(clocks[1].cells(), [0x14]);
+
+        // Nodes without interrupts or clocks have no specifiers
+        let chosen = dt.find_node("/chosen").unwrap();
+        assert!(dt.interrupts(&chosen).next().is_none());
+        assert!(dt.clocks(&chosen).next().is_none());
+    }
+
+    #[test]
+    fn decodes_memory_with_root_cells() {
+        let dt = rpi4();
+        let memory = dt.find_node("/memory").unwrap();
+        // The firmware fills in the real size at boot, so the vendored blob describes no memory
+        assert!(dt.reg(&memory).eq([(0, 0)]));
+    }
+}
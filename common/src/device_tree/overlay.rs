@@ -0,0 +1,490 @@
+//! Merging device tree overlays into a writable tree
+//!
+//! An overlay (a .dtbo compiled with dtc -@) is a list of fragments. Each one names a target node of the base
+//! tree, and holds the properties and subnodes to merge into it under __overlay__. Overlays are compiled
+//! without the base tree at hand, so their phandle references need fixing up once merged:
+//! - __fixups__ lists every cell that refers to a label of the base tree, by "path:property:offset". Labels
+//!   are resolved through the __symbols__ of the base tree.
+//! - __local_fixups__ mirrors the overlay, listing every cell that refers to a node of the overlay itself.
+//!   The overlay's own phandles are renumbered to follow those of the base tree, and these cells along with
+//!   them. A node merged into one of ours that already has a phandle keeps ours instead.
+//!
+//! The labels the overlay defines end up in the base tree's __symbols__, so that later overlays can refer
+//! to them in turn.
+
+use super::{
+    fdt::{read_u32, Fdt, FdtNode},
+    fdt_mut::FdtMut,
+    DeviceTree,
+};
+use crate::util::error::FdtError;
+
+/// Longest path a label of an overlay may resolve to
+const MAX_PATH_LEN: usize = 256;
+
+/// A single cell of an overlay that refers to a label of the base tree
+struct Fixup<'o> {
+    label: &'o str,
+    path: &'o str,
+    property: &'o str,
+    offset: usize,
+}
+
+struct Overlay<'o> {
+    dt: DeviceTree<'o>,
+    fixups: Option<FdtNode<'o>>,
+    /// Added to every phandle of the overlay, to move them past those of the base tree
+    phandle_delta: u32,
+}
+
+impl<'o> Overlay<'o> {
+    fn fixups(&self) -> impl Iterator<Item = Result<Fixup<'o>, FdtError>> + 'o {
+        self.fixups.into_iter().flat_map(|fixups| {
+            fixups.props().flat_map(|prop| {
+                prop.strings().map(move |entry| {
+                    let (path, rest) = entry.split_once(':').ok_or(FdtError::BadOverlay)?;
+                    let (property, offset) = rest.split_once(':').ok_or(FdtError::BadOverlay)?;
+                    Ok(Fixup {
+                        label: prop.name,
+                        path,
+                        property,
+                        offset: offset.parse().map_err(|_| FdtError::BadOverlay)?,
+                    })
+                })
+            })
+        })
+    }
+
+    /// Iterates over the (label, offset) pairs of the cells of property of node that refer to the base tree
+    fn fixups_of<'a>(
+        &'a self,
+        node: &'a FdtNode<'o>,
+        property: &'a str,
+    ) -> impl Iterator<Item = Result<(&'o str, usize), FdtError>> + 'a {
+        self.fixups().filter_map(move |fixup| match fixup {
+            Ok(fixup) if fixup.property != property => None,
+            Ok(fixup) => {
+                let fixed = self.dt.find_node(fixup.path)?;
+                (fixed.offset() == node.offset()).then_some(Ok((fixup.label, fixup.offset)))
+            }
+            Err(err) => Some(Err(err)),
+        })
+    }
+}
+
+impl<'a> FdtMut<'a> {
+    /// Merges overlay into this tree
+    ///
+    /// On error, the tree is left valid but with only part of the overlay applied.
+    pub fn apply_overlay(&mut self, overlay: Fdt) -> Result<(), FdtError> {
+        let root = overlay.root().ok_or(FdtError::BadOverlay)?;
+        let base_max = max_phandle(self.as_fdt());
+        let overlay = Overlay {
+            dt: DeviceTree::new(overlay),
+            fixups: exact_child(&root, "__fixups__"),
+            phandle_delta: base_max,
+        };
+
+        // Every label the overlay refers to needs a phandle before merging starts, since adding one moves
+        // the nodes we are merging into
+        let mut next_phandle = base_max + max_phandle(overlay.dt.fdt()) + 1;
+        for fixup in overlay.fixups() {
+            let node = self.symbol(fixup?.label)?;
+            if self.node(node).and_then(|node| node.phandle()).is_none() {
+                self.set_property_u32(node, "phandle", next_phandle)?;
+                next_phandle += 1;
+            }
+        }
+
+        let local_fixups = exact_child(&root, "__local_fixups__");
+        for fragment in root.children() {
+            // Anything without an __overlay__ is bookkeeping, such as __fixups__ itself
+            let Some(content) = exact_child(&fragment, "__overlay__") else {
+                continue;
+            };
+            let target = self.fragment_target(&overlay, &fragment)?;
+            let local_fixups = local_fixups
+                .and_then(|local_fixups| exact_child(&local_fixups, fragment.name()))
+                .and_then(|fragment| exact_child(&fragment, "__overlay__"));
+            self.merge_node(&overlay, &content, local_fixups, target)?;
+        }
+
+        if let Some(symbols) = exact_child(&root, "__symbols__") {
+            self.merge_symbols(&overlay, &symbols)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the properties and subnodes of source into target, fixing up the phandles they refer to
+    fn merge_node<'o>(
+        &mut self,
+        overlay: &Overlay<'o>,
+        source: &FdtNode<'o>,
+        local_fixups: Option<FdtNode<'o>>,
+        target: usize,
+    ) -> Result<(), FdtError> {
+        let delta = overlay.phandle_delta;
+        // The rest of our tree may refer to target by its phandle, so it must not change
+        let has_phandle = self.node(target).and_then(|node| node.phandle()).is_some();
+        for prop in source.props() {
+            if prop.name == "phandle" || prop.name == "linux,phandle" {
+                if !has_phandle {
+                    self.set_property(target, prop.name, prop.value)?;
+                    self.update_cell(target, prop.name, 0, |phandle| phandle + delta)?;
+                }
+                continue;
+            }
+            self.set_property(target, prop.name, prop.value)?;
+            if let Some(offsets) = local_fixups.and_then(|fixups| fixups.property(prop.name)) {
+                for idx in 0..offsets.value.len() / 4 {
+                    let offset = offsets.u32(idx).unwrap() as usize;
+                    let phandle = read_u32(prop.value, offset).ok_or(FdtError::BadOverlay)?;
+                    let phandle = self.local_phandle(overlay, phandle)?;
+                    self.update_cell(target, prop.name, offset, |_| phandle)?;
+                }
+            }
+            for fixup in overlay.fixups_of(source, prop.name) {
+                let (label, offset) = fixup?;
+                let phandle = self.symbol_phandle(label)?;
+                self.update_cell(target, prop.name, offset, |_| phandle)?;
+            }
+        }
+
+        for child in source.children() {
+            let child_target = self.add_subnode(target, child.name())?;
+            let child_fixups = local_fixups.and_then(|fixups| exact_child(&fixups, child.name()));
+            self.merge_node(overlay, &child, child_fixups, child_target)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the labels the overlay defines to our __symbols__, pointing them at where their nodes ended up
+    fn merge_symbols(&mut self, overlay: &Overlay, symbols: &FdtNode) -> Result<(), FdtError> {
+        let root = overlay.dt.root().ok_or(FdtError::BadOverlay)?;
+        for symbol in symbols.props() {
+            let path = symbol.as_str().ok_or(FdtError::BadOverlay)?;
+            // Labels inside an overlay look like /fragment@0/__overlay__/node, and labels of anything else
+            // (like the fragments themselves) don't survive merging
+            let mut components = path.trim_start_matches('/').splitn(3, '/');
+            let (Some(fragment), Some("__overlay__")) = (components.next(), components.next())
+            else {
+                continue;
+            };
+            let rest = components.next().unwrap_or("");
+            let fragment = exact_child(&root, fragment).ok_or(FdtError::BadOverlay)?;
+            let target = self.fragment_target(overlay, &fragment)?;
+
+            let mut buf = [0; MAX_PATH_LEN];
+            let mut len = self.node_path(target, &mut buf)?;
+            if !rest.is_empty() {
+                if len > 1 {
+                    len = append(&mut buf, len, "/")?;
+                }
+                len = append(&mut buf, len, rest)?;
+            }
+            let value = buf.get(..len + 1).ok_or(FdtError::NoSpace)?;
+
+            let our_root = self.find_node("/").ok_or(FdtError::NotFound)?;
+            let our_symbols = self.add_subnode(our_root, "__symbols__")?;
+            self.set_property(our_symbols, symbol.name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the node of ours that a fragment of overlay applies to, which it gives either by path or by
+    /// phandle. A phandle usually refers to a label of ours, and is then listed in __fixups__.
+    fn fragment_target(&self, overlay: &Overlay, fragment: &FdtNode) -> Result<usize, FdtError> {
+        if let Some(path) = fragment.property("target-path") {
+            let path = path.as_str().ok_or(FdtError::BadOverlay)?;
+            return self.find_node(path).ok_or(FdtError::NotFound);
+        }
+
+        let target = fragment.property("target").ok_or(FdtError::BadOverlay)?;
+        let fixup = overlay
+            .fixups_of(fragment, "target")
+            .find(|fixup| fixup.as_ref().map_or(true, |&(_, offset)| offset == 0))
+            .transpose()?;
+        let phandle = match fixup {
+            Some((label, _)) => self.symbol_phandle(label)?,
+            None => target.u32(0).ok_or(FdtError::BadOverlay)?,
+        };
+        self.device_tree()
+            .find_phandle(phandle)
+            .map(|node| node.offset())
+            .ok_or(FdtError::NotFound)
+    }
+
+    /// Returns the phandle a node of overlay ends up with once merged: that of the node of ours it merges
+    /// into if there is one, otherwise its own moved past ours
+    fn local_phandle(&self, overlay: &Overlay, phandle: u32) -> Result<u32, FdtError> {
+        let moved = phandle + overlay.phandle_delta;
+        let Some(node) = overlay.dt.find_phandle(phandle) else {
+            return Ok(moved);
+        };
+        let ancestors = overlay
+            .dt
+            .fdt()
+            .ancestors(&node)
+            .ok_or(FdtError::BadOverlay)?;
+        // Merged nodes live under /fragment@n/__overlay__, with the same path below it as they will have
+        // below the fragment's target
+        let mut path = (1..ancestors.len())
+            .filter_map(|depth| ancestors.get(depth))
+            .chain([node]);
+        let (Some(fragment), Some(content)) = (path.next(), path.next()) else {
+            return Ok(moved);
+        };
+        if content.name() != "__overlay__" {
+            return Ok(moved);
+        }
+
+        let mut ours = self.node(self.fragment_target(overlay, &fragment)?);
+        for node in path {
+            ours = ours.and_then(|ours| exact_child(&ours, node.name()));
+        }
+        Ok(ours.and_then(|ours| ours.phandle()).unwrap_or(moved))
+    }
+
+    /// Returns the node a label of our __symbols__ refers to
+    fn symbol(&self, label: &str) -> Result<usize, FdtError> {
+        let dt = self.device_tree();
+        let path = dt
+            .find_node("/__symbols__")
+            .and_then(|symbols| symbols.property(label))
+            .and_then(|path| path.as_str())
+            .ok_or(FdtError::NotFound)?;
+        dt.find_node(path)
+            .map(|node| node.offset())
+            .ok_or(FdtError::NotFound)
+    }
+
+    fn symbol_phandle(&self, label: &str) -> Result<u32, FdtError> {
+        self.node(self.symbol(label)?)
+            .and_then(|node| node.phandle())
+            .ok_or(FdtError::NotFound)
+    }
+
+    /// Writes the full path of node into buf, returning its length. The path is followed by a null byte.
+    fn node_path(&self, node: usize, buf: &mut [u8]) -> Result<usize, FdtError> {
+        let fdt = self.as_fdt();
+        let node = fdt.node_at(node).ok_or(FdtError::NotFound)?;
+        let ancestors = fdt.ancestors(&node).ok_or(FdtError::NotFound)?;
+        let mut len = append(buf, 0, "/")?;
+        // The root is the first ancestor, and has an empty name
+        for name in (1..ancestors.len())
+            .filter_map(|depth| ancestors.get(depth))
+            .chain((!ancestors.is_empty()).then_some(node))
+            .map(|node| node.name())
+        {
+            if len > 1 {
+                len = append(buf, len, "/")?;
+            }
+            len = append(buf, len, name)?;
+        }
+        Ok(len)
+    }
+}
+
+/// Copies string into buf at len followed by a null byte, and returns the new length excluding the null
+fn append(buf: &mut [u8], len: usize, string: &str) -> Result<usize, FdtError> {
+    let new_len = len + string.len();
+    let dest = buf.get_mut(len..new_len + 1).ok_or(FdtError::NoSpace)?;
+    dest[..string.len()].copy_from_slice(string.as_bytes());
+    dest[string.len()] = 0;
+    Ok(new_len)
+}
+
+fn exact_child<'o>(node: &FdtNode<'o>, name: &str) -> Option<FdtNode<'o>> {
+    node.children().find(|child| child.name() == name)
+}
+
+fn max_phandle(fdt: Fdt) -> u32 {
+    fdt.nodes()
+        .filter_map(|node| node.phandle())
+        .filter(|&phandle| phandle != u32::MAX)
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::max_phandle;
+    use crate::{
+        device_tree::{blob_builder::BlobBuilder, fdt::Fdt, fdt_mut::FdtMut},
+        util::error::FdtError,
+    };
+
+    const RPI3_DTB: &[u8] = include_bytes!("../../../bootloaders/raspi/vendor/bcm2710-rpi-3-b.dtb");
+
+    /// An overlay as dtc -@ would compile it from:
+    ///
+    /// &uart0 {
+    ///     status = "disabled";
+    ///     test,clock = <&test_clk>;
+    ///     test,gpios = <&gpio 14 0>;
+    ///     test_clk: clk { compatible = "fixed-clock"; };
+    /// };
+    /// &{/chosen} {
+    ///     bootargs = "quiet";
+    ///     lantern { };
+    /// };
+    fn overlay(gpio_label: &str) -> Vec<u8> {
+        BlobBuilder::default()
+            .begin("")
+            .begin("fragment@0")
+            .prop("target", &[0xFFFFFFFF])
+            .begin("__overlay__")
+            .prop_strs("status", &["disabled"])
+            .prop("test,clock", &[1])
+            .prop("test,gpios", &[0xFFFFFFFF, 14, 0])
+            .begin("clk")
+            .prop_strs("compatible", &["fixed-clock"])
+            .prop("phandle", &[1])
+            .end()
+            .end()
+            .end()
+            .begin("fragment@1")
+            .prop_strs("target-path", &["/chosen"])
+            .begin("__overlay__")
+            .prop_strs("bootargs", &["quiet"])
+            .begin("lantern")
+            .end()
+            .end()
+            .end()
+            .begin("__symbols__")
+            .prop_strs("test_clk", &["/fragment@0/__overlay__/clk"])
+            .end()
+            .begin("__fixups__")
+            .prop_strs("uart0", &["/fragment@0:target:0"])
+            .prop_strs(gpio_label, &["/fragment@0/__overlay__:test,gpios:0"])
+            .end()
+            .begin("__local_fixups__")
+            .begin("fragment@0")
+            .begin("__overlay__")
+            .prop("test,clock", &[0])
+            .end()
+            .end()
+            .end()
+            .end()
+            .build()
+    }
+
+    #[test]
+    fn applies_overlay() {
+        let overlay = overlay("gpio");
+        let mut buf = vec![0; RPI3_DTB.len() + 0x1000];
+        let mut fdt = FdtMut::new(&mut buf, RPI3_DTB).unwrap();
+        let base_max = max_phandle(fdt.as_fdt());
+        fdt.apply_overlay(Fdt::new(&overlay).unwrap()).unwrap();
+
+        let fdt = Fdt::new(fdt.as_bytes()).unwrap();
+        let dt = crate::device_tree::DeviceTree::new(fdt);
+        let uart = dt.find_node("/soc/serial@7e201000").unwrap();
+        assert!(!uart.is_enabled());
+        // The overlay's own phandle moves past the base tree's, along with the reference to it
+        let clk = dt.find_node("/soc/serial@7e201000/clk").unwrap();
+        assert_eq!(clk.phandle(), Some(base_max + 1));
+        assert_eq!(
+            uart.property("test,clock").unwrap().u32(0),
+            Some(base_max + 1)
+        );
+        // References to the base tree point at the labelled node
+        let gpio = dt.find_node("/soc/gpio@7e200000").unwrap();
+        let gpios = uart.property("test,gpios").unwrap();
+        assert_eq!(gpios.u32(0), gpio.phandle());
+        assert_eq!(gpios.u32(1), Some(14));
+
+        let chosen = dt.find_node("/chosen").unwrap();
+        assert_eq!(chosen.property("bootargs").unwrap().as_str(), Some("quiet"));
+        assert!(chosen.child("lantern").is_some());
+
+        // The overlay's label now refers to where its node ended up
+        let symbols = dt.find_node("/__symbols__").unwrap();
+        assert_eq!(
+            symbols.property("test_clk").unwrap().as_str(),
+            Some("/soc/serial@7e201000/clk")
+        );
+        // Everything else is still where it was
+        assert_eq!(dt.cpus().count(), 4);
+        assert!(dt.find_node("uart1").is_some());
+    }
+
+    /// An overlay that adds to a node of ours with a phandle, and refers to it through a label of its own:
+    ///
+    /// &{/soc} {
+    ///     my_gpio: gpio@7e200000 { test,extra; };
+    ///     test { test,gpio = <&my_gpio>; };
+    /// };
+    #[test]
+    fn merged_nodes_keep_their_phandle() {
+        let overlay = BlobBuilder::default()
+            .begin("")
+            .begin("fragment@0")
+            .prop_strs("target-path", &["/soc"])
+            .begin("__overlay__")
+            .begin("gpio@7e200000")
+            .prop("test,extra", &[])
+            .prop("phandle", &[1])
+            .end()
+            .begin("test")
+            .prop("test,gpio", &[1])
+            .end()
+            .end()
+            .end()
+            .begin("__symbols__")
+            .prop_strs("my_gpio", &["/fragment@0/__overlay__/gpio@7e200000"])
+            .end()
+            .begin("__local_fixups__")
+            .begin("fragment@0")
+            .begin("__overlay__")
+            .begin("test")
+            .prop("test,gpio", &[0])
+            .end()
+            .end()
+            .end()
+            .end()
+            .end()
+            .build();
+        let base = Fdt::new(RPI3_DTB).unwrap();
+        let gpio_phandle = crate::device_tree::DeviceTree::new(base)
+            .find_node("/soc/gpio@7e200000")
+            .unwrap()
+            .phandle()
+            .unwrap();
+        let mut buf = vec![0; RPI3_DTB.len() + 0x1000];
+        let mut fdt = FdtMut::new(&mut buf, RPI3_DTB).unwrap();
+        fdt.apply_overlay(Fdt::new(&overlay).unwrap()).unwrap();
+
+        let fdt = Fdt::new(fdt.as_bytes()).unwrap();
+        let dt = crate::device_tree::DeviceTree::new(fdt);
+        let gpio = dt.find_node("/soc/gpio@7e200000").unwrap();
+        assert!(gpio.property("test,extra").is_some());
+        assert_eq!(gpio.phandle(), Some(gpio_phandle));
+        let test = dt.find_node("/soc/test").unwrap();
+        assert_eq!(
+            test.property("test,gpio").unwrap().u32(0),
+            Some(gpio_phandle)
+        );
+        assert_eq!(
+            dt.find_node("/__symbols__")
+                .unwrap()
+                .property("my_gpio")
+                .unwrap()
+                .as_str(),
+            Some("/soc/gpio@7e200000")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_labels() {
+        let overlay = overlay("no_such_label");
+        let mut buf = vec![0; RPI3_DTB.len() + 0x1000];
+        let mut fdt = FdtMut::new(&mut buf, RPI3_DTB).unwrap();
+        assert_eq!(
+            fdt.apply_overlay(Fdt::new(&overlay).unwrap()),
+            Err(FdtError::NotFound)
+        );
+        // Labels are checked before anything is merged
+        assert_eq!(fdt.as_bytes(), RPI3_DTB);
+    }
+}
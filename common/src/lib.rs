@@ -1,10 +1,13 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(coerce_unsized)]
 #![feature(unsize)]
 
 pub mod allocators;
 pub mod arch;
+pub mod boot_info;
+pub mod command_line;
 pub mod concurrency;
 pub mod device_drivers;
+pub mod device_tree;
 pub mod memory;
 pub mod util;
@@ -1,6 +1,7 @@
 use super::PhysAddr;
 use crate::util::error::AddressSpaceError;
 
+#[derive(Clone, Copy)]
 pub enum MemoryAttributes {
     DeviceStronglyOrdered,
     NormalCacheable,
@@ -18,3 +19,27 @@ pub trait AddressSpace {
     fn unmap_range(&mut self, virt_start: usize, phys_start: usize, size: usize) -> bool;
     fn translate(&mut self, virt_addr: usize) -> Result<PhysAddr, AddressSpaceError>;
 }
+
+impl<T: AddressSpace + ?Sized> AddressSpace for &mut T {
+    fn set_active(&mut self) -> bool {
+        (**self).set_active()
+    }
+
+    fn map_range(
+        &mut self,
+        virt_start: usize,
+        phys_start: usize,
+        size: usize,
+        attr: MemoryAttributes,
+    ) -> bool {
+        (**self).map_range(virt_start, phys_start, size, attr)
+    }
+
+    fn unmap_range(&mut self, virt_start: usize, phys_start: usize, size: usize) -> bool {
+        (**self).unmap_range(virt_start, phys_start, size)
+    }
+
+    fn translate(&mut self, virt_addr: usize) -> Result<PhysAddr, AddressSpaceError> {
+        (**self).translate(virt_addr)
+    }
+}
@@ -1,12 +1,12 @@
 use super::memory_size::MemorySize;
 use crate::{
-    allocators::{static_box::StaticBox, StaticAlloc},
-    util::error::AllocError,
+    allocators::{static_vec::StaticVec, StaticAlloc},
+    util::error::{AllocError, MemoryMapError},
 };
-use arrayvec::ArrayVec;
 use core::{fmt::Display, ops::Deref};
 
-#[derive(Default, Clone, Copy, PartialEq)]
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+#[repr(u32)]
 pub enum MemoryMapType {
     #[default]
     FREE,
@@ -15,9 +15,12 @@ pub enum MemoryMapType {
     KERNEL,
     STACK,
     MMIO,
+    /// The device tree blob, which the kernel needs to keep mapped for as long as it parses it
+    DTB,
 }
 
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+#[repr(C)]
 pub struct MemoryMapEntry {
     pub base_addr: usize,
     pub end_addr: usize,
@@ -37,49 +40,32 @@ impl MemoryMapEntry {
         MemorySize::new(self.end_addr - self.base_addr)
     }
 
-    fn contains(&self, other: &Self) -> bool {
-        let range = self.base_addr..self.end_addr;
-        range.contains(&other.base_addr) || range.contains(&other.end_addr)
+    fn is_empty(&self) -> bool {
+        self.base_addr >= self.end_addr
     }
-    fn fully_contains(&self, other: &Self) -> bool {
-        let range = self.base_addr..=self.end_addr;
-        range.contains(&other.base_addr) && range.contains(&other.end_addr)
-    }
-
-    fn reduce(&mut self, other: &Self) -> Option<MemoryMapEntry> {
-        let mut new_block = None;
-        if self.contains(other) {
-            if other.base_addr <= self.base_addr {
-                self.base_addr = other.end_addr;
-            } else if other.end_addr >= self.end_addr {
-                self.end_addr = other.base_addr;
-            } else {
-                let old_end = self.end_addr;
-                // Truncate original
-                self.end_addr = other.base_addr;
-
-                // Add new free after reserved
-                let end = old_end;
-                new_block = Some(MemoryMapEntry {
-                    base_addr: other.end_addr,
-                    end_addr: end,
-                    mem_type: MemoryMapType::FREE,
-                });
-            }
-        }
 
-        new_block
+    fn overlaps(&self, other: &Self) -> bool {
+        self.base_addr < other.end_addr && other.base_addr < self.end_addr
     }
 }
 
-pub struct MemoryMap<const CAP: usize> {
-    entries: StaticBox<ArrayVec<MemoryMapEntry, CAP>>,
+/// A map of the physical address space, describing what every known region of memory is used for
+///
+/// The entries of a MemoryMap are always sorted by address, never overlap, and adjacent entries of the
+/// same type are always merged into one. See add_entry for how overlapping regions are resolved.
+pub struct MemoryMap {
+    entries: StaticVec<MemoryMapEntry>,
+    // Entries are rebuilt into here on every insertion, so that a failed insertion leaves the map untouched
+    scratch: StaticVec<MemoryMapEntry>,
 }
 
-impl<const CAP: usize> MemoryMap<CAP> {
-    pub fn new_in<A: StaticAlloc>(allocator: &mut A) -> Result<Self, AllocError> {
-        let boxed = StaticBox::new(ArrayVec::new(), allocator)?;
-        Ok(Self { entries: boxed })
+impl MemoryMap {
+    /// Constructs an empty memory map with room for at most capacity entries
+    pub fn new_in<A: StaticAlloc>(capacity: usize, allocator: &mut A) -> Result<Self, AllocError> {
+        Ok(Self {
+            entries: StaticVec::with_capacity(capacity, allocator)?,
+            scratch: StaticVec::with_capacity(capacity, allocator)?,
+        })
     }
 
     pub fn get_free_mem(&self) -> usize {
@@ -94,66 +80,177 @@ impl<const CAP: usize> MemoryMap<CAP> {
         bytes
     }
 
+    /// Returns the number of bytes of RAM the map covers. MMIO windows are not memory, so they don't count.
     pub fn get_total_mem(&self) -> usize {
         let mut bytes = 0;
         for entry in self.entries.deref() {
-            bytes += entry.size().as_bytes();
+            if entry.mem_type != MemoryMapType::MMIO {
+                bytes += entry.size().as_bytes();
+            }
         }
 
         bytes
     }
 
-    pub fn get_entries(&self) -> &ArrayVec<MemoryMapEntry, CAP> {
+    pub fn get_entries(&self) -> &[MemoryMapEntry] {
         &self.entries
     }
 
-    pub fn add_entry(&mut self, mut entry: MemoryMapEntry) -> bool {
-        // Merge adjacent entries of same type
-        if let Some(old_entry) = self
-            .entries
-            .iter()
-            .find(|x| x.end_addr == entry.base_addr && x.mem_type == entry.mem_type)
-        {
-            entry.base_addr = old_entry.base_addr;
+    /// Returns the lowest address of a FREE region of size bytes that is aligned to align and ends at or
+    /// below limit, if there is one
+    pub fn find_free_region(
+        &self,
+        size: usize,
+        align: usize,
+        limit: Option<usize>,
+    ) -> Option<usize> {
+        if size == 0 || !align.is_power_of_two() {
+            return None;
         }
-        if let Some(old_entry) = self
-            .entries
+        let limit = limit.unwrap_or(usize::MAX);
+
+        self.entries
             .iter()
-            .find(|x| x.base_addr == entry.end_addr && x.mem_type == entry.mem_type)
-        {
-            entry.end_addr = old_entry.end_addr;
+            .filter(|entry| entry.mem_type == MemoryMapType::FREE)
+            .find_map(|entry| {
+                let base = entry.base_addr.checked_next_multiple_of(align)?;
+                let end = base.checked_add(size)?;
+                (end <= entry.end_addr && end <= limit).then_some(base)
+            })
+    }
+
+    /// Finds a free region exactly like find_free_region, and marks it as mem_type in the same step
+    ///
+    /// Returns the base address of the region.
+    pub fn allocate_region(
+        &mut self,
+        size: usize,
+        align: usize,
+        limit: Option<usize>,
+        mem_type: MemoryMapType,
+    ) -> Result<usize, MemoryMapError> {
+        if size == 0 || !align.is_power_of_two() || mem_type == MemoryMapType::FREE {
+            return Err(MemoryMapError::InvalidEntry);
         }
+        let base = self
+            .find_free_region(size, align, limit)
+            .ok_or(MemoryMapError::OutOfMemory)?;
+        self.add_entry(MemoryMapEntry::new(base, base + size, mem_type))?;
 
-        // Remove free entries if they are completely consumed by a reserved entry
-        self.entries.retain(|x| !entry.fully_contains(x));
+        Ok(base)
+    }
 
-        // Reduce our free space
-        let mut new_entries: ArrayVec<MemoryMapEntry, 4> = ArrayVec::new();
-        for existing in self.entries.as_mut_slice() {
-            if let Some(additional_entry) = existing.reduce(&entry) {
-                new_entries.push(additional_entry);
-            }
-        }
+    /// Iterates over the address of every whole page frame that lies within a FREE region, in ascending order
+    pub fn free_frames(&self, page_size: usize) -> impl Iterator<Item = usize> + '_ {
+        self.entries
+            .iter()
+            .filter(|entry| entry.mem_type == MemoryMapType::FREE)
+            .flat_map(move |entry| {
+                let first = entry.base_addr.next_multiple_of(page_size);
+                let end = entry.end_addr - entry.end_addr % page_size;
+                (first..end).step_by(page_size)
+            })
+    }
 
-        // Add any newly created entries to accomodate the reserved entry
-        if self.entries.try_extend_from_slice(&new_entries).is_err() {
-            return false;
+    /// Inserts a region into the map, resolving any overlap with existing entries
+    ///
+    /// Wherever the new entry overlaps an existing one, the entry with the higher precedence wins: reserved
+    /// types (anything but FREE or RECLAIM) take precedence over RECLAIM, which takes precedence over FREE.
+    /// Between entries of equal precedence, the newer entry wins. This means marking a region as used always
+    /// takes effect, while adding free memory never clobbers a region that has already been reserved. Parts
+    /// of an existing entry that are not covered by the winner keep their original type.
+    ///
+    /// If the result would not fit in the map, an error is returned and the map is left unmodified.
+    pub fn add_entry(&mut self, entry: MemoryMapEntry) -> Result<(), MemoryMapError> {
+        if entry.base_addr > entry.end_addr {
+            return Err(MemoryMapError::InvalidEntry);
+        }
+        if entry.is_empty() {
+            return Ok(());
         }
 
-        // Add the reserved entry and return
-        if self.entries.try_push(entry).is_err() {
-            return false;
+        self.scratch.clear();
+        // Start of the part of the new entry that has not been emitted yet
+        let mut cursor = entry.base_addr;
+        for existing in self.entries.iter() {
+            if !existing.overlaps(&entry) {
+                if existing.base_addr >= entry.end_addr && cursor < entry.end_addr {
+                    // We've moved past the new entry, so whatever is left of it goes before this entry
+                    push_merged(
+                        &mut self.scratch,
+                        MemoryMapEntry::new(cursor, entry.end_addr, entry.mem_type),
+                    )?;
+                    cursor = entry.end_addr;
+                }
+                push_merged(&mut self.scratch, *existing)?;
+            } else if entry.mem_type.precedence() >= existing.mem_type.precedence() {
+                // The new entry wins, so only the parts of the existing entry outside of it survive
+                push_merged(
+                    &mut self.scratch,
+                    MemoryMapEntry::new(existing.base_addr, entry.base_addr, existing.mem_type),
+                )?;
+                if existing.end_addr > entry.end_addr {
+                    push_merged(
+                        &mut self.scratch,
+                        MemoryMapEntry::new(cursor, entry.end_addr, entry.mem_type),
+                    )?;
+                    cursor = entry.end_addr;
+                    push_merged(
+                        &mut self.scratch,
+                        MemoryMapEntry::new(entry.end_addr, existing.end_addr, existing.mem_type),
+                    )?;
+                }
+            } else {
+                // The existing entry wins, so the new entry can only fill the space around it
+                push_merged(
+                    &mut self.scratch,
+                    MemoryMapEntry::new(cursor, existing.base_addr, entry.mem_type),
+                )?;
+                push_merged(&mut self.scratch, *existing)?;
+                cursor = cursor.max(existing.end_addr);
+            }
         }
+        push_merged(
+            &mut self.scratch,
+            MemoryMapEntry::new(cursor, entry.end_addr, entry.mem_type),
+        )?;
 
-        // Sort the map from 0 to max addr
-        self.entries
-            .sort_unstable_by(|a, b| a.base_addr.cmp(&b.base_addr));
+        core::mem::swap(&mut self.entries, &mut self.scratch);
+        Ok(())
+    }
+}
 
-        true
+/// Appends entry to the end of entries, merging it into the last entry if they are adjacent and of the same
+/// type. Empty entries are skipped.
+fn push_merged(
+    entries: &mut StaticVec<MemoryMapEntry>,
+    entry: MemoryMapEntry,
+) -> Result<(), MemoryMapError> {
+    if entry.is_empty() {
+        return Ok(());
     }
+    if let Some(last) = entries.last_mut() {
+        if last.end_addr == entry.base_addr && last.mem_type == entry.mem_type {
+            last.end_addr = entry.end_addr;
+            return Ok(());
+        }
+    }
+
+    entries
+        .try_push(entry)
+        .map_err(|_| MemoryMapError::CapacityExceeded)
 }
 
 impl MemoryMapType {
+    /// Decides which type wins when two entries overlap. Higher wins.
+    fn precedence(&self) -> u8 {
+        match self {
+            MemoryMapType::FREE => 0,
+            MemoryMapType::RECLAIM => 1,
+            _ => 2,
+        }
+    }
+
     fn to_string(&self) -> &str {
         match self {
             MemoryMapType::FREE => "Free",
@@ -162,6 +259,7 @@ impl MemoryMapType {
             MemoryMapType::KERNEL => "Kernel",
             MemoryMapType::STACK => "Stack",
             MemoryMapType::MMIO => "MMIO",
+            MemoryMapType::DTB => "DTB",
         }
     }
 }
@@ -181,7 +279,7 @@ impl Display for MemoryMapEntry {
     }
 }
 
-impl<const CAP: usize> Display for MemoryMap<CAP> {
+impl Display for MemoryMap {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         for entry in self.entries.deref() {
             if entry.size().as_bytes() != 0 {
@@ -191,3 +289,315 @@ impl<const CAP: usize> Display for MemoryMap<CAP> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryMap, MemoryMapEntry, MemoryMapType};
+    use crate::{allocators::static_bump::StaticBumpAlloc, util::error::MemoryMapError};
+    use std::vec;
+
+    const PAGE: usize = 0x1000;
+    const TYPES: [MemoryMapType; 7] = [
+        MemoryMapType::FREE,
+        MemoryMapType::RESERVED,
+        MemoryMapType::RECLAIM,
+        MemoryMapType::KERNEL,
+        MemoryMapType::STACK,
+        MemoryMapType::MMIO,
+        MemoryMapType::DTB,
+    ];
+
+    fn new_map(capacity: usize) -> MemoryMap {
+        let buf = vec![0u8; 2 * capacity * size_of::<MemoryMapEntry>() + 64].leak();
+        let mut allocator = unsafe { StaticBumpAlloc::new(buf.as_mut_ptr() as usize, buf.len()) };
+        MemoryMap::new_in(capacity, &mut allocator).unwrap()
+    }
+
+    fn entry(base_page: usize, end_page: usize, mem_type: MemoryMapType) -> MemoryMapEntry {
+        MemoryMapEntry::new(base_page * PAGE, end_page * PAGE, mem_type)
+    }
+
+    /// Checks the invariants every MemoryMap must uphold, no matter what was inserted into it
+    fn assert_well_formed(map: &MemoryMap) {
+        for entry in map.get_entries() {
+            assert!(entry.base_addr < entry.end_addr, "Empty entry {:?}", entry);
+        }
+        for pair in map.get_entries().windows(2) {
+            assert!(
+                pair[0].end_addr <= pair[1].base_addr,
+                "Unsorted or overlapping {:?}",
+                pair
+            );
+            assert!(
+                pair[0].end_addr != pair[1].base_addr || pair[0].mem_type != pair[1].mem_type,
+                "Unmerged neighbours {:?}",
+                pair
+            );
+        }
+    }
+
+    #[test]
+    fn free_regions_are_sorted_and_merged() {
+        let mut map = new_map(8);
+        map.add_entry(entry(8, 12, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(0, 4, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(4, 8, MemoryMapType::FREE)).unwrap();
+
+        assert_eq!(map.get_entries(), &[entry(0, 12, MemoryMapType::FREE)]);
+    }
+
+    #[test]
+    fn reserved_splits_free() {
+        let mut map = new_map(8);
+        map.add_entry(entry(0, 16, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(4, 8, MemoryMapType::RESERVED)).unwrap();
+
+        assert_eq!(
+            map.get_entries(),
+            &[
+                entry(0, 4, MemoryMapType::FREE),
+                entry(4, 8, MemoryMapType::RESERVED),
+                entry(8, 16, MemoryMapType::FREE),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_remainder_keeps_original_type() {
+        let mut map = new_map(8);
+        map.add_entry(entry(0, 16, MemoryMapType::KERNEL)).unwrap();
+        map.add_entry(entry(4, 8, MemoryMapType::MMIO)).unwrap();
+
+        assert_eq!(
+            map.get_entries(),
+            &[
+                entry(0, 4, MemoryMapType::KERNEL),
+                entry(4, 8, MemoryMapType::MMIO),
+                entry(8, 16, MemoryMapType::KERNEL),
+            ]
+        );
+    }
+
+    #[test]
+    fn adjacent_entries_of_different_types_are_not_merged() {
+        let mut map = new_map(8);
+        map.add_entry(entry(0, 4, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(4, 8, MemoryMapType::STACK)).unwrap();
+
+        assert_eq!(
+            map.get_entries(),
+            &[
+                entry(0, 4, MemoryMapType::FREE),
+                entry(4, 8, MemoryMapType::STACK),
+            ]
+        );
+    }
+
+    #[test]
+    fn free_never_overrides_reserved() {
+        let mut map = new_map(8);
+        map.add_entry(entry(4, 8, MemoryMapType::KERNEL)).unwrap();
+        map.add_entry(entry(10, 12, MemoryMapType::RECLAIM))
+            .unwrap();
+        map.add_entry(entry(0, 16, MemoryMapType::FREE)).unwrap();
+
+        assert_eq!(
+            map.get_entries(),
+            &[
+                entry(0, 4, MemoryMapType::FREE),
+                entry(4, 8, MemoryMapType::KERNEL),
+                entry(8, 10, MemoryMapType::FREE),
+                entry(10, 12, MemoryMapType::RECLAIM),
+                entry(12, 16, MemoryMapType::FREE),
+            ]
+        );
+    }
+
+    #[test]
+    fn insertion_spanning_several_entries_replaces_them() {
+        let mut map = new_map(8);
+        map.add_entry(entry(0, 4, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(4, 6, MemoryMapType::STACK)).unwrap();
+        map.add_entry(entry(6, 8, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(8, 10, MemoryMapType::KERNEL)).unwrap();
+        map.add_entry(entry(2, 9, MemoryMapType::RESERVED)).unwrap();
+
+        assert_eq!(
+            map.get_entries(),
+            &[
+                entry(0, 2, MemoryMapType::FREE),
+                entry(2, 9, MemoryMapType::RESERVED),
+                entry(9, 10, MemoryMapType::KERNEL),
+            ]
+        );
+    }
+
+    #[test]
+    fn capacity_overflow_leaves_map_untouched() {
+        let mut map = new_map(2);
+        map.add_entry(entry(0, 16, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(16, 20, MemoryMapType::KERNEL)).unwrap();
+
+        assert_eq!(
+            map.add_entry(entry(4, 8, MemoryMapType::RESERVED)),
+            Err(MemoryMapError::CapacityExceeded)
+        );
+        assert_eq!(
+            map.get_entries(),
+            &[
+                entry(0, 16, MemoryMapType::FREE),
+                entry(16, 20, MemoryMapType::KERNEL),
+            ]
+        );
+    }
+
+    #[test]
+    fn inverted_entry_is_rejected() {
+        let mut map = new_map(2);
+        assert_eq!(
+            map.add_entry(entry(8, 4, MemoryMapType::FREE)),
+            Err(MemoryMapError::InvalidEntry)
+        );
+        assert!(map.get_entries().is_empty());
+    }
+
+    #[test]
+    fn find_free_region_respects_alignment_and_limit() {
+        let mut map = new_map(8);
+        map.add_entry(entry(1, 4, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(4, 6, MemoryMapType::KERNEL)).unwrap();
+        map.add_entry(entry(6, 32, MemoryMapType::FREE)).unwrap();
+
+        assert_eq!(map.find_free_region(2 * PAGE, PAGE, None), Some(PAGE));
+        // Too big for the first region, so it has to come from the second
+        assert_eq!(map.find_free_region(4 * PAGE, PAGE, None), Some(6 * PAGE));
+        assert_eq!(map.find_free_region(PAGE, 8 * PAGE, None), Some(8 * PAGE));
+        assert_eq!(
+            map.find_free_region(4 * PAGE, 8 * PAGE, Some(12 * PAGE)),
+            Some(8 * PAGE)
+        );
+        assert_eq!(
+            map.find_free_region(4 * PAGE, 8 * PAGE, Some(11 * PAGE)),
+            None
+        );
+        assert_eq!(map.find_free_region(27 * PAGE, PAGE, None), None);
+        assert_eq!(map.find_free_region(PAGE, 3 * PAGE, None), None);
+    }
+
+    #[test]
+    fn allocate_region_marks_region() {
+        let mut map = new_map(8);
+        map.add_entry(entry(0, 16, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(0, 1, MemoryMapType::RESERVED)).unwrap();
+
+        assert_eq!(
+            map.allocate_region(2 * PAGE, 4 * PAGE, None, MemoryMapType::RECLAIM),
+            Ok(4 * PAGE)
+        );
+        assert_eq!(
+            map.allocate_region(PAGE, PAGE, None, MemoryMapType::KERNEL),
+            Ok(PAGE)
+        );
+        assert_eq!(
+            map.get_entries(),
+            &[
+                entry(0, 1, MemoryMapType::RESERVED),
+                entry(1, 2, MemoryMapType::KERNEL),
+                entry(2, 4, MemoryMapType::FREE),
+                entry(4, 6, MemoryMapType::RECLAIM),
+                entry(6, 16, MemoryMapType::FREE),
+            ]
+        );
+        assert_eq!(
+            map.allocate_region(16 * PAGE, PAGE, None, MemoryMapType::KERNEL),
+            Err(MemoryMapError::OutOfMemory)
+        );
+        assert_eq!(
+            map.allocate_region(PAGE, PAGE, None, MemoryMapType::FREE),
+            Err(MemoryMapError::InvalidEntry)
+        );
+    }
+
+    #[test]
+    fn free_frames_skips_partial_and_used_pages() {
+        let mut map = new_map(8);
+        map.add_entry(MemoryMapEntry::new(0x800, 3 * PAGE, MemoryMapType::FREE))
+            .unwrap();
+        map.add_entry(MemoryMapEntry::new(
+            3 * PAGE,
+            4 * PAGE,
+            MemoryMapType::STACK,
+        ))
+        .unwrap();
+        map.add_entry(MemoryMapEntry::new(
+            4 * PAGE,
+            6 * PAGE + 0x10,
+            MemoryMapType::FREE,
+        ))
+        .unwrap();
+
+        assert!(map
+            .free_frames(PAGE)
+            .eq([PAGE, 2 * PAGE, 4 * PAGE, 5 * PAGE]));
+    }
+
+    #[test]
+    fn total_mem_leaves_out_mmio() {
+        let mut map = new_map(8);
+        map.add_entry(entry(0, 4, MemoryMapType::FREE)).unwrap();
+        map.add_entry(entry(1, 2, MemoryMapType::KERNEL)).unwrap();
+        map.add_entry(entry(16, 20, MemoryMapType::MMIO)).unwrap();
+
+        assert_eq!(map.get_total_mem(), 4 * PAGE);
+        assert_eq!(map.get_free_mem(), 3 * PAGE);
+    }
+
+    /// Inserts random entries into a map, checking after every insertion that the map is well formed and
+    /// agrees page for page with a simple model of the precedence rules
+    #[test]
+    fn random_insertions_match_model() {
+        const NUM_PAGES: usize = 64;
+        // xorshift64, so that failures are reproducible
+        let mut state: u64 = 0x2545F4914F6CDD1D;
+        let mut next = |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize % bound
+        };
+
+        for _ in 0..200 {
+            let mut map = new_map(NUM_PAGES);
+            let mut model: [Option<MemoryMapType>; NUM_PAGES] = [None; NUM_PAGES];
+
+            for _ in 0..20 {
+                let base = next(NUM_PAGES);
+                let end = base + 1 + next(NUM_PAGES - base);
+                let mem_type = TYPES[next(TYPES.len())];
+                map.add_entry(entry(base, end, mem_type)).unwrap();
+                for page in &mut model[base..end] {
+                    match page {
+                        Some(old) if old.precedence() > mem_type.precedence() => (),
+                        _ => *page = Some(mem_type),
+                    }
+                }
+
+                assert_well_formed(&map);
+                let mut expected = [None; NUM_PAGES];
+                for entry in map.get_entries() {
+                    expected[entry.base_addr / PAGE..entry.end_addr / PAGE]
+                        .fill(Some(entry.mem_type));
+                }
+                assert_eq!(expected, model);
+                assert_eq!(
+                    map.get_total_mem(),
+                    model
+                        .iter()
+                        .filter(|page| page.is_some_and(|mem_type| mem_type != MemoryMapType::MMIO))
+                        .count()
+                        * PAGE
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+/// The furthest apart two consecutive frame records may be before we assume the chain is corrupt
+#[cfg(target_arch = "aarch64")]
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Fills callers with the return addresses of the current call stack, starting with the caller of the
+/// function that called capture_callers. Unused slots are left as 0.
+///
+/// This walks the AArch64 frame record chain (x29), so it requires the code to be compiled with frame
+/// pointers. The walk stops early as soon as a frame record looks implausible, rather than risk faulting on
+/// a garbage frame pointer at the bottom of the stack.
+#[inline(never)]
+pub fn capture_callers(callers: &mut [usize]) {
+    callers.fill(0);
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mut fp: usize;
+        unsafe {
+            // Safety: Reading x29 has no side effects
+            core::arch::asm!("mov {}, x29", out(reg) fp);
+        }
+
+        // Skip our own frame, so the first recorded address belongs to whoever called our caller
+        let mut skip = 1;
+        let mut idx = 0;
+        while idx < callers.len() && fp != 0 && fp % 16 == 0 {
+            // Safety: fp is non-null, aligned, and was either read from x29 or from a previous frame record
+            // that passed the same plausibility checks. A frame record is two words: the caller's frame
+            // pointer followed by the return address.
+            let (next_fp, return_addr) = unsafe {
+                let record = fp as *const usize;
+                (record.read(), record.add(1).read())
+            };
+            if return_addr == 0 {
+                break;
+            }
+            if skip > 0 {
+                skip -= 1;
+            } else {
+                callers[idx] = return_addr;
+                idx += 1;
+            }
+
+            // The stack grows down, so every older frame must live at a higher address
+            if next_fp <= fp || next_fp - fp > MAX_FRAME_SIZE {
+                break;
+            }
+            fp = next_fp;
+        }
+    }
+}
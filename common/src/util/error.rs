@@ -3,6 +3,10 @@ pub enum DeviceError {
     BadWrite,
     Busy,
     BadOperand,
+    MissingDependency,
+    Timeout,
+    /// The device is compatible with the driver, but is not an instance the driver handles
+    Unsupported,
     Other,
 }
 
@@ -11,3 +15,35 @@ pub struct AddressSpaceError;
 
 #[derive(Debug)]
 pub struct AllocError;
+
+#[derive(Debug)]
+pub struct CapacityError;
+
+#[derive(Debug, PartialEq)]
+pub enum MemoryMapError {
+    CapacityExceeded,
+    InvalidEntry,
+    OutOfMemory,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FdtError {
+    BadMagic,
+    Truncated,
+    UnsupportedVersion,
+    BadLayout,
+    NoSpace,
+    InvalidName,
+    NotFound,
+    BadOverlay,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BootInfoError {
+    Null,
+    Misaligned,
+    BadMagic,
+    UnsupportedVersion,
+    InvalidCommandLine,
+    InvalidStdoutPath,
+}
@@ -1,6 +1,8 @@
 extern "C" {
     pub static __PG_SIZE: u8;
     pub static __KERNEL_VIRT_START: u8;
+    pub static __KERNEL_HEAP_VIRT_START: u8;
+    pub static __KERNEL_HEAP_VIRT_SIZE: u8;
 }
 
 #[macro_export]
@@ -1,3 +1,4 @@
+pub mod backtrace;
 pub mod error;
 pub mod linker_variables;
 pub mod register_ref;
@@ -0,0 +1,161 @@
+//! Provides the kernel's global allocator, so that the alloc crate can be used throughout the kernel
+//!
+//! KERNEL_HEAP starts out uninitialized, and every allocation made through it will fail until a Heap
+//! has been handed to it with init. Like GLOBAL_WRITER, it is built on a SingleThreadedCell so that the
+//! heap and the mutex protecting it can be supplied at runtime, before we enter a multi-threaded environment.
+//!
+//! Building with the heap-debug feature wraps the heap in a DebugHeap, which guards every allocation with
+//! redzones, poisons freed memory and remembers who allocated what. Outstanding allocations can then be
+//! listed at any time with print_outstanding_allocations, which the panic handler does too (unless the panic
+//! came from inside the heap, which is then in no state to be looked at).
+
+#[cfg(feature = "heap-debug")]
+use crate::kprintln;
+#[cfg(feature = "heap-debug")]
+use common::{allocators::heap::debug::DebugHeap, memory::memory_size::MemorySize};
+use common::{
+    allocators::{heap::Heap, static_box::StaticBox},
+    concurrency::RawWriterMutex,
+    util::single_threaded_cell::SingleThreadedCell,
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[global_allocator]
+pub static KERNEL_HEAP: KernelHeap = KernelHeap::new();
+
+#[cfg(not(feature = "heap-debug"))]
+type HeapImpl = Heap;
+#[cfg(feature = "heap-debug")]
+type HeapImpl = DebugHeap;
+
+struct LockedHeap {
+    mutex: StaticBox<dyn RawWriterMutex>,
+    heap: UnsafeCell<HeapImpl>,
+    /// Set for as long as the heap is in use. A single-threaded mutex can't tell that it is already held, so
+    /// this is how a panic in the middle of a heap operation (eg DebugHeap catching a double free) knows
+    /// not to touch the heap again.
+    in_use: AtomicBool,
+}
+
+impl LockedHeap {
+    /// Runs the closure on the heap, then unlocks it
+    ///
+    /// # Safety
+    /// The mutex must be held by the caller
+    unsafe fn run_locked<R, F: FnOnce(&mut HeapImpl) -> R>(&self, closure: F) -> R {
+        self.in_use.store(true, Ordering::Release);
+        // Safety: The mutex ensures there is only ever one mutable reference to the heap at a time, and the
+        // heap is not reachable from anywhere but this method.
+        let result = closure(&mut *self.heap.get());
+        self.in_use.store(false, Ordering::Release);
+
+        // Safety: The caller holds the lock, and the closure has no way to reach the mutex
+        self.mutex.unlock();
+        result
+    }
+}
+
+pub struct KernelHeap(SingleThreadedCell<LockedHeap>);
+
+impl KernelHeap {
+    pub const fn new() -> Self {
+        Self(SingleThreadedCell::new())
+    }
+
+    /// Hands the kernel its heap, after which the alloc crate becomes usable
+    ///
+    /// # Safety
+    ///
+    /// Must only be called in a single-threaded environment, and never after the first allocation has been
+    /// made, since any memory handed out by a previous heap would be leaked or freed to the wrong heap.
+    pub unsafe fn init(&self, heap: Heap, mutex: StaticBox<dyn RawWriterMutex>) {
+        self.0.set(LockedHeap {
+            mutex,
+            #[cfg(not(feature = "heap-debug"))]
+            heap: UnsafeCell::new(heap),
+            #[cfg(feature = "heap-debug")]
+            heap: UnsafeCell::new(DebugHeap::new(heap)),
+            in_use: AtomicBool::new(false),
+        });
+    }
+
+    /// Prints every allocation that has not been freed yet, along with the return addresses of the call
+    /// stack that allocated it
+    ///
+    /// The heap stays locked while printing, so the writer behind kprintln must never allocate. Nothing is
+    /// listed if the heap is already in use, such as when a heap operation panics.
+    #[cfg(feature = "heap-debug")]
+    pub fn print_outstanding_allocations(&self) {
+        if self.0.get().is_none() {
+            kprintln!("Kernel heap is not initialized");
+            return;
+        }
+        let printed = self.try_with_heap(|heap| {
+            kprintln!(
+                "Printing outstanding heap allocations:\n\n\
+                Allocations:    {}",
+                heap.outstanding_count()
+            );
+            heap.for_each_outstanding(|allocation| {
+                kprintln!(
+                    "{:#018x} | {:12} | Callers: {:#x?}",
+                    allocation.addr,
+                    MemorySize::new(allocation.size),
+                    allocation.callers
+                );
+            });
+        });
+        if printed.is_none() {
+            kprintln!("Kernel heap is in use, so its outstanding allocations can't be listed");
+        }
+    }
+
+    /// Locks the heap and runs the closure on it, or returns None if the heap has not been initialized yet
+    fn with_heap<R, F: FnOnce(&mut HeapImpl) -> R>(&self, closure: F) -> Option<R> {
+        let locked = self.0.get()?;
+        locked.mutex.lock();
+
+        // Safety: We just took the lock
+        Some(unsafe { locked.run_locked(closure) })
+    }
+
+    /// Like with_heap, but also returns None rather than waiting if the heap is already in use, whether by
+    /// someone else or by whatever we interrupted
+    #[cfg(feature = "heap-debug")]
+    fn try_with_heap<R, F: FnOnce(&mut HeapImpl) -> R>(&self, closure: F) -> Option<R> {
+        let locked = self.0.get()?;
+        if locked.in_use.load(Ordering::Acquire) || !locked.mutex.try_lock() {
+            return None;
+        }
+
+        // Safety: try_lock succeeded
+        Some(unsafe { locked.run_locked(closure) })
+    }
+}
+
+impl Default for KernelHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Safety: Every block handed out is served by a Heap, which upholds the GlobalAlloc contract, and the heap
+// is only ever accessed with its mutex held.
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| heap.allocate(layout).ok())
+            .flatten()
+            .map_or(null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            self.with_heap(|heap| heap.deallocate(ptr, layout));
+        }
+    }
+}
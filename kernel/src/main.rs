@@ -1,14 +1,43 @@
 #![no_main]
 #![no_std]
 
+extern crate alloc;
+
+use common::{
+    boot_info::BootInfo,
+    command_line::{BootParams, CommandLine},
+};
 use core::panic::PanicInfo;
 
+pub mod heap;
+pub mod memory;
 pub mod print;
 
+/// Entry point the bootloader jumps to once the MMU is enabled
+///
+/// # Safety
+/// boot_info must either be null, or point to memory that stays mapped for as long as the kernel runs.
 // no_mangle is necessary to stop this fn from being optimized out
 #[link_section = ".text.boot"]
 #[no_mangle]
-pub extern "C" fn kmain() -> ! {
+pub unsafe extern "C" fn kmain(boot_info: *const BootInfo) -> ! {
+    // TODO: Bring up the rest of the kernel with what the bootloader told us
+    let boot_info = BootInfo::from_ptr(boot_info)
+        .unwrap_or_else(|err| panic!("Bootloader passed invalid boot information: {:?}", err));
+    // Nothing can allocate until the heap is up
+    memory::init(boot_info);
+    // The bootloader has already warned about anything wrong with the command line
+    let mut params = BootParams::default();
+    CommandLine::new(boot_info.command_line()).parse_quietly(&mut [&mut params]);
+    // Without a console on the command line, use the one the firmware suggests
+    let stdout_path = Some(boot_info.stdout_path()).filter(|path| !path.is_empty());
+    let _params = BootParams {
+        console: params.console.or(stdout_path),
+        ..params
+    };
+    // Secondary CPUs stay parked until the kernel can schedule on them
+    let _cpus = boot_info.cpus();
+
     loop {}
 }
 
@@ -17,5 +46,8 @@ fn panic(info: &PanicInfo) -> ! {
     kprintln!("\n");
     kprintln!("KERNEL PANIC!");
     kprintln!("{}", info);
+    // Whatever is still allocated is often the best lead on what went wrong
+    #[cfg(feature = "heap-debug")]
+    heap::KERNEL_HEAP.print_outstanding_allocations();
     loop {}
 }
@@ -0,0 +1,187 @@
+//! Takes over memory management from the bootloader
+//!
+//! The kernel keeps the page table the bootloader built for it, and reaches its tables (along with every
+//! other frame it uses) through the linear map. Frames are handed out from the largest free region of the
+//! memory map the bootloader passed on, and frames that are given back are reused before the rest of it.
+
+use crate::heap::KERNEL_HEAP;
+use common::{
+    allocators::{
+        heap::Heap,
+        page_frame_allocator::{
+            bump::BumpPFA,
+            freelist::FreelistPFA,
+            stats::{FrameOwner, FrameStats},
+            FrameAllocator,
+        },
+        static_box::StaticBox,
+        static_bump::StaticBumpAlloc,
+    },
+    arch::aarch64::page_table::PageTable,
+    boot_info::BootInfo,
+    concurrency::{
+        single_threaded_lock::SingleThreadedLock,
+        single_threaded_writer_mutex::SingleThreadedRawWriterMutex,
+    },
+    memory::{memory_map::MemoryMapType, PhysAddr},
+    read_linker_var,
+    util::{
+        error::AllocError,
+        linker_variables::{__KERNEL_HEAP_VIRT_SIZE, __KERNEL_HEAP_VIRT_START, __PG_SIZE},
+        single_threaded_cell::SingleThreadedCell,
+    },
+};
+use core::{ptr::addr_of_mut, slice::from_raw_parts_mut};
+
+/// Size of the memory the objects behind the kernel heap live in
+const STATIC_STORAGE_SIZE: usize = 0x1000;
+
+static mut STATIC_STORAGE: [u8; STATIC_STORAGE_SIZE] = [0; STATIC_STORAGE_SIZE];
+static LINEAR_MAP_BASE: SingleThreadedCell<usize> = SingleThreadedCell::new();
+static FRAME_ALLOCATOR: SingleThreadedCell<KernelFrameAllocator> = SingleThreadedCell::new();
+
+/// Translates a physical address to where it can be reached through the linear map
+pub fn phys_to_virt(phys: usize) -> usize {
+    linear_map_base() + phys
+}
+
+/// Translates an address in the linear map back to the physical address it maps
+pub fn virt_to_phys(virt: usize) -> usize {
+    virt - linear_map_base()
+}
+
+fn linear_map_base() -> usize {
+    *LINEAR_MAP_BASE
+        .get()
+        .expect("Linear map used before memory management was initialized")
+}
+
+struct KernelFrames {
+    bump: BumpPFA,
+    /// Frames that were given back, linked through the linear map
+    freelist: FreelistPFA,
+    page_size: usize,
+    stats: FrameStats,
+}
+
+/// The frame allocator behind the kernel heap and page table
+///
+/// Single frames are taken off the freelist while it has any, anything else comes out of the part of the
+/// region that has never been used.
+pub struct KernelFrameAllocator(SingleThreadedLock<KernelFrames>);
+
+// Safety: Frames are only ever handed out once until they are given back, and are zeroed through the linear
+// map, which covers all of RAM
+unsafe impl FrameAllocator for &KernelFrameAllocator {
+    fn allocate_pages(
+        &self,
+        num_contiguous_pages: usize,
+        owner: FrameOwner,
+    ) -> Result<PhysAddr, AllocError> {
+        let mut frames = self.0.lock();
+        let reused = match num_contiguous_pages {
+            1 => frames.freelist.allocate_page(),
+            _ => None,
+        };
+        let result = match reused {
+            Some(frame) => Ok(virt_to_phys(frame as usize)),
+            None => frames
+                .bump
+                .allocate_contiguous_pages(num_contiguous_pages, owner),
+        };
+        match result {
+            Ok(_) => frames.stats.record_allocation(owner, num_contiguous_pages),
+            Err(_) => frames.stats.record_failure(),
+        }
+
+        result
+    }
+
+    fn allocate_zeroed_pages(
+        &self,
+        num_contiguous_pages: usize,
+        owner: FrameOwner,
+        translation: fn(usize) -> usize,
+    ) -> Result<PhysAddr, AllocError> {
+        let start_phys = self.allocate_pages(num_contiguous_pages, owner)?;
+        let page_size = self.0.lock().page_size;
+        // Safety: The frames were just allocated, so nothing else can be using them
+        unsafe {
+            from_raw_parts_mut(
+                translation(start_phys) as *mut u8,
+                num_contiguous_pages * page_size,
+            )
+            .fill(0);
+        }
+
+        Ok(start_phys)
+    }
+
+    unsafe fn deallocate_pages(
+        &self,
+        addr: PhysAddr,
+        num_contiguous_pages: usize,
+        owner: FrameOwner,
+    ) {
+        let mut frames = self.0.lock();
+        for page in 0..num_contiguous_pages {
+            let frame = phys_to_virt(addr + page * frames.page_size);
+            frames.freelist.free_page(frame as *mut u8);
+        }
+        frames
+            .stats
+            .record_deallocation(owner, num_contiguous_pages);
+    }
+
+    fn stats(&self) -> FrameStats {
+        self.0.lock().stats
+    }
+}
+
+/// Sets up the kernel's frame allocator and page table, and hands the kernel heap the virtual range
+/// reserved for it, after which the alloc crate becomes usable
+///
+/// # Safety
+/// Must be called exactly once, in a single-threaded environment, with the BootInfo the bootloader
+/// passed to kmain.
+pub unsafe fn init(boot_info: &BootInfo) {
+    let page_size = read_linker_var!(__PG_SIZE);
+    LINEAR_MAP_BASE.set(boot_info.linear_map_base);
+
+    // All of RAM is covered by the linear map, so any free region will do
+    // TODO: Use every free region, not just the largest
+    let region = boot_info
+        .memory_map()
+        .iter()
+        .filter(|entry| entry.mem_type == MemoryMapType::FREE)
+        .max_by_key(|entry| entry.end_addr - entry.base_addr)
+        .expect("No free memory for the kernel's frame allocator");
+    FRAME_ALLOCATOR.set(KernelFrameAllocator(SingleThreadedLock::new(
+        KernelFrames {
+            bump: BumpPFA::new(region.base_addr, region.end_addr, page_size)
+                .expect("Free memory in the memory map is not page aligned"),
+            freelist: FreelistPFA::new(),
+            page_size,
+            stats: FrameStats::new(page_size, (region.end_addr - region.base_addr) / page_size),
+        },
+    )));
+    let frame_allocator = FRAME_ALLOCATOR.get().unwrap();
+
+    let mut static_allocator =
+        StaticBumpAlloc::new(addr_of_mut!(STATIC_STORAGE) as usize, STATIC_STORAGE_SIZE);
+    let address_space = StaticBox::new(
+        PageTable::from_raw(boot_info.page_table_phys, phys_to_virt, frame_allocator),
+        &mut static_allocator,
+    )
+    .unwrap();
+    let heap = Heap::new(
+        StaticBox::new(frame_allocator, &mut static_allocator).unwrap(),
+        address_space,
+        page_size,
+        read_linker_var!(__KERNEL_HEAP_VIRT_START),
+        read_linker_var!(__KERNEL_HEAP_VIRT_SIZE),
+    )
+    .expect("Kernel heap region is not page aligned");
+    let mutex = StaticBox::new(SingleThreadedRawWriterMutex::new(), &mut static_allocator).unwrap();
+    KERNEL_HEAP.init(heap, mutex);
+}